@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX index_queue_on_priority_scheduled_for;
+ALTER TABLE schedule DROP COLUMN priority;
+ALTER TABLE queue DROP COLUMN priority;
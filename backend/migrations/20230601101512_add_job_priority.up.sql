@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE queue ADD COLUMN priority SMALLINT;
+ALTER TABLE schedule ADD COLUMN priority SMALLINT;
+CREATE INDEX index_queue_on_priority_scheduled_for ON queue ((COALESCE(priority, 0)) DESC, scheduled_for);
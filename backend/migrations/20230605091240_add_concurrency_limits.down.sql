@@ -0,0 +1,8 @@
+-- Add down migration script here
+DROP INDEX index_completed_job_on_concurrency_key_started_at;
+DROP INDEX index_queue_on_concurrency_key;
+ALTER TABLE completed_job DROP COLUMN concurrency_key;
+ALTER TABLE queue DROP COLUMN concurrency_time_window_s;
+ALTER TABLE queue DROP COLUMN concurrent_limit;
+ALTER TABLE queue DROP COLUMN concurrency_key;
+ALTER TABLE script DROP COLUMN concurrency_limit;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+ALTER TABLE script ADD COLUMN concurrency_limit JSONB;
+ALTER TABLE queue ADD COLUMN concurrency_key VARCHAR(255);
+ALTER TABLE queue ADD COLUMN concurrent_limit INTEGER;
+ALTER TABLE queue ADD COLUMN concurrency_time_window_s INTEGER;
+ALTER TABLE completed_job ADD COLUMN concurrency_key VARCHAR(255);
+CREATE INDEX index_queue_on_concurrency_key ON queue (concurrency_key) WHERE concurrency_key IS NOT NULL;
+CREATE INDEX index_completed_job_on_concurrency_key_started_at ON completed_job (concurrency_key, started_at) WHERE concurrency_key IS NOT NULL;
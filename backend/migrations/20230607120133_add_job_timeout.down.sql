@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE queue DROP COLUMN timeout;
+ALTER TABLE script DROP COLUMN timeout;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE script ADD COLUMN timeout INTEGER;
+ALTER TABLE queue ADD COLUMN timeout INTEGER;
@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP INDEX index_completed_job_on_cache_key_started_at;
+ALTER TABLE completed_job DROP COLUMN cache_key;
+ALTER TABLE completed_job DROP COLUMN cache_hit;
+ALTER TABLE queue DROP COLUMN cache_ttl;
+ALTER TABLE script DROP COLUMN cache_ttl;
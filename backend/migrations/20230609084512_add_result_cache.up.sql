@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE script ADD COLUMN cache_ttl INTEGER;
+ALTER TABLE queue ADD COLUMN cache_ttl INTEGER;
+ALTER TABLE completed_job ADD COLUMN cache_hit BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE completed_job ADD COLUMN cache_key VARCHAR(64);
+CREATE INDEX index_completed_job_on_cache_key_started_at ON completed_job (workspace_id, cache_key, started_at DESC) WHERE cache_key IS NOT NULL;
@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE queue DROP COLUMN cpu_limit_millis;
+ALTER TABLE queue DROP COLUMN memory_limit_mb;
+ALTER TABLE script DROP COLUMN cpu_limit_millis;
+ALTER TABLE script DROP COLUMN memory_limit_mb;
@@ -0,0 +1,5 @@
+-- Add up migration script here
+ALTER TABLE script ADD COLUMN memory_limit_mb INTEGER;
+ALTER TABLE script ADD COLUMN cpu_limit_millis INTEGER;
+ALTER TABLE queue ADD COLUMN memory_limit_mb INTEGER;
+ALTER TABLE queue ADD COLUMN cpu_limit_millis INTEGER;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE workspace_key DROP COLUMN previous_key;
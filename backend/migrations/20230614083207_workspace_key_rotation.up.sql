@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE workspace_key ADD COLUMN previous_key VARCHAR(255);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE resource_version;
+DROP TABLE variable_version;
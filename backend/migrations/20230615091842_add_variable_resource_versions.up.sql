@@ -0,0 +1,34 @@
+-- Add up migration script here
+CREATE TABLE variable_version (
+    id BIGSERIAL PRIMARY KEY,
+    workspace_id VARCHAR(50) NOT NULL REFERENCES workspace(id),
+    path VARCHAR(255) NOT NULL,
+    value VARCHAR(15000) NOT NULL,
+    is_secret BOOLEAN NOT NULL,
+    description TEXT NOT NULL,
+    replaced_by VARCHAR(255) NOT NULL,
+    replaced_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX variable_version_path_idx ON variable_version (workspace_id, path, id DESC);
+
+CREATE TABLE resource_version (
+    id BIGSERIAL PRIMARY KEY,
+    workspace_id VARCHAR(50) NOT NULL REFERENCES workspace(id),
+    path VARCHAR(255) NOT NULL,
+    value JSONB,
+    description TEXT,
+    replaced_by VARCHAR(255) NOT NULL,
+    replaced_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX resource_version_path_idx ON resource_version (workspace_id, path, id DESC);
+
+GRANT ALL ON variable_version TO windmill_admin;
+GRANT ALL ON variable_version TO windmill_user;
+GRANT ALL ON resource_version TO windmill_admin;
+GRANT ALL ON resource_version TO windmill_user;
+GRANT ALL ON SEQUENCE variable_version_id_seq TO windmill_admin;
+GRANT ALL ON SEQUENCE variable_version_id_seq TO windmill_user;
+GRANT ALL ON SEQUENCE resource_version_id_seq TO windmill_admin;
+GRANT ALL ON SEQUENCE resource_version_id_seq TO windmill_user;
@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE completed_job DROP COLUMN flow_version;
+ALTER TABLE queue DROP COLUMN flow_version;
+ALTER TABLE flow DROP COLUMN versions;
+DROP TABLE flow_version;
@@ -0,0 +1,27 @@
+-- Add up migration script here
+CREATE TABLE flow_version (
+    id BIGSERIAL PRIMARY KEY,
+    workspace_id VARCHAR(50) NOT NULL,
+    path VARCHAR(255) NOT NULL,
+    value JSONB NOT NULL,
+    schema JSON,
+    created_by VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    FOREIGN KEY (workspace_id, path) REFERENCES flow(workspace_id, path) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE INDEX flow_version_path_idx ON flow_version (workspace_id, path, id DESC);
+
+GRANT ALL ON flow_version TO windmill_admin;
+GRANT ALL ON flow_version TO windmill_user;
+
+ALTER TABLE flow ADD COLUMN versions BIGINT[] NOT NULL DEFAULT '{}';
+
+INSERT INTO flow_version (workspace_id, path, value, schema, created_by, created_at)
+SELECT workspace_id, path, value, schema, edited_by, edited_at FROM flow;
+
+UPDATE flow SET versions = ARRAY[flow_version.id] FROM flow_version
+WHERE flow_version.workspace_id = flow.workspace_id AND flow_version.path = flow.path;
+
+ALTER TABLE queue ADD COLUMN flow_version BIGINT;
+ALTER TABLE completed_job ADD COLUMN flow_version BIGINT;
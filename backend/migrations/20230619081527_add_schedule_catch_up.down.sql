@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE schedule DROP COLUMN last_tick;
+ALTER TABLE schedule DROP COLUMN catch_up_limit;
+ALTER TABLE schedule DROP COLUMN catch_up;
+DROP TYPE SCHEDULE_CATCH_UP;
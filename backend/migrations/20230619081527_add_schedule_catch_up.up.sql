@@ -0,0 +1,5 @@
+-- Add up migration script here
+CREATE TYPE SCHEDULE_CATCH_UP AS ENUM ('skip', 'once', 'all');
+ALTER TABLE schedule ADD COLUMN catch_up SCHEDULE_CATCH_UP NOT NULL DEFAULT 'skip';
+ALTER TABLE schedule ADD COLUMN catch_up_limit INTEGER;
+ALTER TABLE schedule ADD COLUMN last_tick TIMESTAMP WITH TIME ZONE;
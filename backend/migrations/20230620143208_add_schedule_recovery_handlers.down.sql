@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE schedule DROP COLUMN last_failure;
+ALTER TABLE schedule DROP COLUMN failure_count;
+ALTER TABLE schedule DROP COLUMN failure_threshold;
+ALTER TABLE schedule DROP COLUMN on_success;
+ALTER TABLE schedule DROP COLUMN on_recovery;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE schedule ADD COLUMN on_recovery VARCHAR(1000) DEFAULT NULL;
+ALTER TABLE schedule ADD COLUMN on_success VARCHAR(1000) DEFAULT NULL;
+ALTER TABLE schedule ADD COLUMN failure_threshold INTEGER DEFAULT NULL;
+ALTER TABLE schedule ADD COLUMN failure_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE schedule ADD COLUMN last_failure JSONB DEFAULT NULL;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE schedule DROP COLUMN run_at;
+ALTER TABLE schedule DROP COLUMN paused_until;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE schedule ADD COLUMN paused_until TIMESTAMP WITH TIME ZONE DEFAULT NULL;
+ALTER TABLE schedule ADD COLUMN run_at TIMESTAMP WITH TIME ZONE DEFAULT NULL;
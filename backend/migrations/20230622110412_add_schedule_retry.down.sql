@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE schedule DROP COLUMN retry_attempts;
+ALTER TABLE schedule DROP COLUMN retry;
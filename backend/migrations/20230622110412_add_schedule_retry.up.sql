@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE schedule ADD COLUMN retry JSONB DEFAULT NULL;
+ALTER TABLE schedule ADD COLUMN retry_attempts INTEGER NOT NULL DEFAULT 0;
@@ -55,6 +55,128 @@ fn parse_file(code: &str) -> anyhow::Result<Option<Vec<Arg>>> {
     Ok(Some(args))
 }
 
+/* Prefix of the tag of bash jobs that declare requirements, followed by the required binaries */
+pub const BASH_REQUIREMENTS_TAG_PREFIX: &str = "bash:";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VersionOp {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl VersionOp {
+    fn as_str(&self) -> &'static str {
+        match self {
+            VersionOp::Eq => "=",
+            VersionOp::Gt => ">",
+            VersionOp::Ge => ">=",
+            VersionOp::Lt => "<",
+            VersionOp::Le => "<=",
+        }
+    }
+}
+
+/* A binary a bash script needs on the worker, declared as `# requires: jq>=1.6, curl` */
+#[derive(Debug, Clone, PartialEq)]
+pub struct BashRequirement {
+    pub binary: String,
+    pub version: Option<(VersionOp, String)>,
+}
+
+impl std::fmt::Display for BashRequirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.version {
+            Some((op, version)) => write!(f, "{}{}{}", self.binary, op.as_str(), version),
+            None => write!(f, "{}", self.binary),
+        }
+    }
+}
+
+impl BashRequirement {
+    /* versions are compared numerically component by component, `1.6` == `1.6.0` */
+    pub fn is_satisfied_by(&self, version: &str) -> bool {
+        let Some((op, required)) = &self.version else {
+            return true;
+        };
+        let ordering = version_components(version).cmp(&version_components(required));
+        match op {
+            VersionOp::Eq => ordering.is_eq(),
+            VersionOp::Gt => ordering.is_gt(),
+            VersionOp::Ge => ordering.is_ge(),
+            VersionOp::Lt => ordering.is_lt(),
+            VersionOp::Le => ordering.is_le(),
+        }
+    }
+}
+
+fn version_components(version: &str) -> Vec<u64> {
+    let mut components = version
+        .split('.')
+        .map(|c| {
+            c.chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect::<String>()
+                .parse::<u64>()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+    while components.last() == Some(&0) {
+        components.pop();
+    }
+    components
+}
+
+lazy_static::lazy_static! {
+    static ref RE_REQUIRES: Regex = Regex::new(r#"(?m)^#\s*requires:(.*)$"#).unwrap();
+    static ref RE_REQUIREMENT: Regex =
+        Regex::new(r#"^([A-Za-z0-9_.+-]+)\s*(?:(>=|<=|==|=|>|<)\s*(\d[A-Za-z0-9_.+-]*))?$"#).unwrap();
+}
+
+pub fn parse_bash_requirements(code: &str) -> windmill_common::error::Result<Vec<BashRequirement>> {
+    let mut requirements: Vec<BashRequirement> = vec![];
+    for line in RE_REQUIRES.captures_iter(code) {
+        for requirement in line[1].split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let cap = RE_REQUIREMENT.captures(requirement).ok_or_else(|| {
+                windmill_common::error::Error::BadRequest(format!(
+                    "Invalid bash requirement `{requirement}`, expected e.g. `jq>=1.6`"
+                ))
+            })?;
+            let version = cap.get(2).zip(cap.get(3)).map(|(op, version)| {
+                let op = match op.as_str() {
+                    ">" => VersionOp::Gt,
+                    ">=" => VersionOp::Ge,
+                    "<" => VersionOp::Lt,
+                    "<=" => VersionOp::Le,
+                    _ => VersionOp::Eq,
+                };
+                (op, version.as_str().to_string())
+            });
+            requirements.push(BashRequirement { binary: cap[1].to_string(), version });
+        }
+    }
+    Ok(requirements)
+}
+
+/* `bash:curl,jq` for a script requiring jq and curl, None if it has no requirements */
+pub fn bash_requirements_tag(requirements: &[BashRequirement]) -> Option<String> {
+    if requirements.is_empty() {
+        return None;
+    }
+    let mut binaries = requirements
+        .iter()
+        .map(|r| r.binary.as_str())
+        .collect::<Vec<_>>();
+    binaries.sort();
+    binaries.dedup();
+    Some(format!(
+        "{BASH_REQUIREMENTS_TAG_PREFIX}{}",
+        binaries.join(",")
+    ))
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -104,4 +226,33 @@ digest="${3:-latest with spaces}"
 
         Ok(())
     }
+
+    #[test]
+    fn test_parse_bash_requirements() -> anyhow::Result<()> {
+        let code = r#"
+# requires: jq>=1.6, curl
+# requires: yq = 4
+echo "hello"
+"#;
+        let requirements = parse_bash_requirements(code)?;
+        assert_eq!(
+            requirements
+                .iter()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>(),
+            vec!["jq>=1.6", "curl", "yq=4"]
+        );
+        assert!(requirements[0].is_satisfied_by("1.6"));
+        assert!(requirements[0].is_satisfied_by("1.10.1"));
+        assert!(!requirements[0].is_satisfied_by("1.5"));
+        assert!(requirements[1].is_satisfied_by("7.88.1"));
+        assert!(requirements[2].is_satisfied_by("4.0.0"));
+        assert_eq!(
+            bash_requirements_tag(&requirements),
+            Some("bash:curl,jq,yq".to_string())
+        );
+        assert!(parse_bash_requirements("# requires: jq >= one").is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,187 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2023
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+use anyhow::anyhow;
+use regex::Regex;
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use windmill_parser::{Arg, MainArgSignature, Typ};
+
+/* the resource arg every postgresql script is run against */
+pub const PG_DATABASE_ARG: &str = "database";
+
+pub fn parse_pgsql_sig(code: &str) -> windmill_common::error::Result<MainArgSignature> {
+    let parsed = parse_pg_file(code)
+        .map_err(|e| windmill_common::error::Error::BadRequest(e.to_string()))?;
+    let mut args = vec![Arg {
+        name: PG_DATABASE_ARG.to_string(),
+        typ: Typ::Resource("postgresql".to_string()),
+        default: None,
+        otyp: None,
+        has_default: false,
+    }];
+    args.extend(parsed);
+    Ok(MainArgSignature { star_args: false, star_kwargs: false, args })
+}
+
+lazy_static::lazy_static! {
+    /* -- $1 name (type) = default */
+    static ref RE_ARG: Regex = Regex::new(
+        r#"(?m)^-- \$(\d+) (\w+)(?: \(([A-Za-z0-9_ \[\]]+)\))?(?: ?= ?(.+?))?\s*$"#
+    ).unwrap();
+}
+
+/*
+ * Declared arguments in the order they are bound, `otyp` holding the postgresql type
+ * they are bound as. Untyped arguments are bound as text.
+ */
+pub fn parse_pg_file(code: &str) -> anyhow::Result<Vec<Arg>> {
+    let mut hm: HashMap<usize, Arg> = HashMap::new();
+    for cap in RE_ARG.captures_iter(code) {
+        let i = cap[1]
+            .parse::<usize>()
+            .map_err(|_| anyhow!("Impossible to parse arg digit ${}", &cap[1]))?;
+        let name = cap[2].to_string();
+        if name == PG_DATABASE_ARG {
+            return Err(anyhow!(
+                "`{PG_DATABASE_ARG}` is reserved for the postgresql resource the query runs against"
+            ));
+        }
+        let otyp = cap
+            .get(3)
+            .map(|x| x.as_str().trim().to_lowercase())
+            .unwrap_or_else(|| "text".to_string());
+        let default = cap.get(4).map(|x| parse_pg_default(x.as_str()));
+        let arg = Arg {
+            name,
+            typ: parse_pg_typ(&otyp),
+            has_default: default.is_some(),
+            default,
+            otyp: Some(otyp),
+        };
+        if hm.insert(i, arg).is_some() {
+            return Err(anyhow!("Argument ${i} is declared more than once"));
+        }
+    }
+    let mut args = vec![];
+    for i in 1..=hm.len() {
+        match hm.remove(&i) {
+            Some(arg) => args.push(arg),
+            None => return Err(anyhow!("Argument ${i} is not declared")),
+        }
+    }
+    Ok(args)
+}
+
+pub fn parse_pg_typ(typ: &str) -> Typ {
+    if let Some(inner) = typ.strip_suffix("[]") {
+        return Typ::List(Box::new(parse_pg_typ(inner.trim())));
+    }
+    match typ {
+        "smallint" | "int2" | "integer" | "int" | "int4" | "bigint" | "int8" | "serial"
+        | "bigserial" => Typ::Int,
+        "real" | "float4" | "double precision" | "float8" | "float" | "numeric" | "decimal" => {
+            Typ::Float
+        }
+        "bool" | "boolean" => Typ::Bool,
+        "json" | "jsonb" => Typ::Object(vec![]),
+        "date"
+        | "timestamp"
+        | "timestamptz"
+        | "timestamp with time zone"
+        | "timestamp without time zone" => Typ::Datetime,
+        "bytea" => Typ::Bytes,
+        _ => Typ::Str(None),
+    }
+}
+
+/* defaults are json literals, or sql/bare strings */
+fn parse_pg_default(default: &str) -> Value {
+    let default = default.trim();
+    if default.len() >= 2 && default.starts_with('\'') && default.ends_with('\'') {
+        json!(default[1..default.len() - 1].replace("''", "'"))
+    } else {
+        serde_json::from_str(default).unwrap_or_else(|_| json!(default))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_pgsql_sig() -> anyhow::Result<()> {
+        let code = r#"
+-- $1 name
+-- $2 age (int) = 42
+-- $3 tags (text[])
+-- $4 nickname (varchar) = 'John''s'
+SELECT * FROM users WHERE name = $1 AND age > $2 AND tags && $3 OR nickname = $4;
+"#;
+        assert_eq!(
+            parse_pgsql_sig(code)?,
+            MainArgSignature {
+                star_args: false,
+                star_kwargs: false,
+                args: vec![
+                    Arg {
+                        otyp: None,
+                        name: "database".to_string(),
+                        typ: Typ::Resource("postgresql".to_string()),
+                        default: None,
+                        has_default: false
+                    },
+                    Arg {
+                        otyp: Some("text".to_string()),
+                        name: "name".to_string(),
+                        typ: Typ::Str(None),
+                        default: None,
+                        has_default: false
+                    },
+                    Arg {
+                        otyp: Some("int".to_string()),
+                        name: "age".to_string(),
+                        typ: Typ::Int,
+                        default: Some(json!(42)),
+                        has_default: true
+                    },
+                    Arg {
+                        otyp: Some("text[]".to_string()),
+                        name: "tags".to_string(),
+                        typ: Typ::List(Box::new(Typ::Str(None))),
+                        default: None,
+                        has_default: false
+                    },
+                    Arg {
+                        otyp: Some("varchar".to_string()),
+                        name: "nickname".to_string(),
+                        typ: Typ::Str(None),
+                        default: Some(json!("John's")),
+                        has_default: true
+                    }
+                ]
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_pgsql_sig_missing_arg() {
+        let code = r#"
+-- $1 name
+-- $3 age (int)
+SELECT $1, $3;
+"#;
+        assert!(parse_pgsql_sig(code).is_err());
+    }
+}
@@ -91,10 +91,8 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("Database connected");
 
     let rsmq = if let Some(config) = rsmq_config {
-        let mut rsmq = rsmq_async::MultiplexedRsmq::new(config).await.unwrap();
-
-        let _ = rsmq_async::RsmqConnection::create_queue(&mut rsmq, "main_queue", None, None, None)
-            .await;
+        // per-workspace queues are created lazily by the first push to a workspace
+        let rsmq = rsmq_async::MultiplexedRsmq::new(config).await.unwrap();
         Some(rsmq)
     } else {
         None
@@ -133,6 +131,7 @@ Windmill Community Edition {GIT_VERSION}
         "BASE_URL",
         "BASE_INTERNAL_URL",
         "TIMEOUT",
+        "MAX_TIMEOUT",
         "ZOMBIE_JOB_TIMEOUT",
         "RESTART_ZOMBIE_JOBS",
         "SLEEP_QUEUE",
@@ -176,6 +175,8 @@ Windmill Community Edition {GIT_VERSION}
         "WAIT_RESULT_FAST_POLL_INTERVAL_MS",
         "EXIT_AFTER_NO_JOB_FOR_SECS",
         "REQUEST_SIZE_LIMIT",
+        "SMTP_TRIGGER_PORT",
+        "SMTP_TRIGGER_DOMAIN",
     ]);
 
     if server_mode || num_workers > 0 {
@@ -43,6 +43,11 @@ pub async fn handle_zombie_jobs_periodically<
 ) {
     loop {
         handle_zombie_jobs(db, base_internal_url, rsmq.clone()).await;
+        if let Some(mut rsmq) = rsmq.clone() {
+            if let Err(e) = windmill_queue::drain_rsmq_main_queue(db, &mut rsmq).await {
+                tracing::error!("Error draining the rsmq main_queue: {e}");
+            }
+        }
 
         tokio::select! {
             _ = tokio::time::sleep(Duration::from_secs(30))    => (),
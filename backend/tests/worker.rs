@@ -10,8 +10,8 @@ use windmill_api_client::types::{CreateFlowBody, RawScript};
 use windmill_common::{
     flow_status::{FlowStatus, FlowStatusModule},
     flows::{FlowModule, FlowModuleValue, FlowValue, InputTransform},
-    jobs::{JobPayload, RawCode},
-    scripts::ScriptLang,
+    jobs::{JobPayload, JobUpdateNotification, RawCode, JOB_UPDATES_CHANNEL},
+    scripts::{ConcurrencyLimit, ScriptHash, ScriptLang},
 };
 use windmill_queue::get_queued_job;
 
@@ -814,11 +814,22 @@ async fn test_iteration_parallel(db: Pool<Postgres>) {
 struct RunJob {
     payload: JobPayload,
     args: serde_json::Map<String, serde_json::Value>,
+    priority: Option<i16>,
+    timeout: Option<i32>,
+    cache_ttl: Option<i32>,
+    permissioned_as: String,
 }
 
 impl From<JobPayload> for RunJob {
     fn from(payload: JobPayload) -> Self {
-        Self { payload, args: Default::default() }
+        Self {
+            payload,
+            args: Default::default(),
+            priority: None,
+            timeout: None,
+            cache_ttl: None,
+            permissioned_as: "u/admin".to_string(),
+        }
     }
 }
 
@@ -828,8 +839,28 @@ impl RunJob {
         self
     }
 
+    fn priority(mut self, priority: i16) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    fn timeout(mut self, timeout: i32) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn cache_ttl(mut self, cache_ttl: i32) -> Self {
+        self.cache_ttl = Some(cache_ttl);
+        self
+    }
+
+    fn permissioned_as<S: Into<String>>(mut self, permissioned_as: S) -> Self {
+        self.permissioned_as = permissioned_as.into();
+        self
+    }
+
     async fn push(self, db: &Pool<Postgres>) -> Uuid {
-        let RunJob { payload, args } = self;
+        let RunJob { payload, args, priority, timeout, cache_ttl, permissioned_as } = self;
         let (uuid, tx) = windmill_queue::push::<rsmq_async::MultiplexedRsmq>(
             (None, db.begin().await.unwrap()).into(),
             "test-workspace",
@@ -837,7 +868,7 @@ impl RunJob {
             args,
             /* user */ "test-user",
             /* email  */ "test@windmill.dev",
-            /* permissioned_as */ "u/admin".to_string(),
+            permissioned_as,
             /* scheduled_for_o */ None,
             /* schedule_path */ None,
             /* parent_job */ None,
@@ -848,6 +879,9 @@ impl RunJob {
             None,
             true,
             None,
+            priority,
+            timeout,
+            cache_ttl,
         )
         .await
         .expect("push has to succeed");
@@ -1015,6 +1049,10 @@ async fn test_deno_flow(db: Pool<Postgres>) {
                     suspend: Default::default(),
                     retry: None,
                     sleep: None,
+                    priority: None,
+                    timeout: None,
+                    cache_ttl: None,
+                    on_error: None,
                 },
                 FlowModule {
                     id: "b".to_string(),
@@ -1043,6 +1081,10 @@ async fn test_deno_flow(db: Pool<Postgres>) {
                             suspend: Default::default(),
                             retry: None,
                             sleep: None,
+                            priority: None,
+                            timeout: None,
+                            cache_ttl: None,
+                            on_error: None,
                         }],
                     },
                     stop_after_if: Default::default(),
@@ -1050,6 +1092,10 @@ async fn test_deno_flow(db: Pool<Postgres>) {
                     suspend: Default::default(),
                     retry: None,
                     sleep: None,
+                    priority: None,
+                    timeout: None,
+                    cache_ttl: None,
+                    on_error: None,
                 },
             ],
             same_worker: false,
@@ -1105,6 +1151,212 @@ async fn test_identity(db: Pool<Postgres>) {
     assert_eq!(result, serde_json::json!(42));
 }
 
+#[sqlx::test(fixtures("base"))]
+async fn test_whileloop_flow(db: Pool<Postgres>) {
+    initialize_tracing().await;
+
+    let server = ApiServer::start(db.clone()).await;
+
+    let whileloop = |max_iterations: u32| -> FlowValue {
+        serde_json::from_value(serde_json::json!({
+            "modules": [{
+                "id": "a",
+                "value": {
+                    "type": "whileloopflow",
+                    "max_iterations": max_iterations,
+                    "modules": [{
+                        "value": {
+                            "type": "rawscript",
+                            "language": "deno",
+                            "content": "export function main(prev: any) { return { page: (prev?.page ?? 0) + 1 } }",
+                            "input_transforms": {
+                                "prev": { "type": "javascript", "expr": "flow_input.iter.value" },
+                            },
+                        },
+                    }],
+                },
+                "stop_after_if": { "expr": "result.page >= 3", "skip_if_stopped": false },
+            }],
+        }))
+        .unwrap()
+    };
+
+    let job = RunJob::from(JobPayload::RawFlow { value: whileloop(10), path: None })
+        .run_until_complete(&db, server.addr.port())
+        .await;
+    assert!(job.success);
+    assert_eq!(
+        job.result.unwrap(),
+        json!([{ "page": 1 }, { "page": 2 }, { "page": 3 }])
+    );
+
+    let job = RunJob::from(JobPayload::RawFlow { value: whileloop(2), path: None })
+        .run_until_complete(&db, server.addr.port())
+        .await;
+    assert!(!job.success);
+    assert_eq!(
+        job.result.as_ref().and_then(|r| r["error"].get("name")),
+        Some(&json!("MaxIterationsReached"))
+    );
+
+    /* a while loop always runs at least once, also when nested */
+    let nested: FlowValue = serde_json::from_value(json!({
+        "modules": [{
+            "id": "b",
+            "value": {
+                "type": "forloopflow",
+                "iterator": { "type": "javascript", "expr": "[1]" },
+                "modules": serde_json::to_value(whileloop(0).modules).unwrap(),
+            },
+        }],
+    }))
+    .unwrap();
+    for value in [whileloop(0), nested] {
+        let result = windmill_queue::push::<rsmq_async::MultiplexedRsmq>(
+            (None, db.begin().await.unwrap()).into(),
+            "test-workspace",
+            JobPayload::RawFlow { value, path: None },
+            Default::default(),
+            "test-user",
+            "test@windmill.dev",
+            "u/admin".to_string(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            false,
+            false,
+            None,
+            true,
+            None,
+            None,
+            None,
+            None,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(windmill_common::error::Error::BadRequest(_))
+        ));
+    }
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_module_on_error(db: Pool<Postgres>) {
+    initialize_tracing().await;
+
+    let server = ApiServer::start(db.clone()).await;
+
+    let flow: FlowValue = serde_json::from_value(serde_json::json!({
+        "modules": [{
+            "id": "a",
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main() { throw new Error('boom') }",
+            },
+            "on_error": [{
+                "value": {
+                    "type": "rawscript",
+                    "language": "deno",
+                    "content": "export function main(e: any) { return { recovered: !!e.error } }",
+                    "input_transforms": {
+                        "e": { "type": "javascript", "expr": "flow_input.error" },
+                    },
+                },
+            }],
+        }, {
+            "id": "b",
+            "value": {
+                "type": "rawscript",
+                "language": "deno",
+                "content": "export function main(x: any) { return x.recovered }",
+                "input_transforms": {
+                    "x": { "type": "javascript", "expr": "previous_result" },
+                },
+            },
+        }],
+    }))
+    .unwrap();
+
+    let job = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, server.addr.port())
+        .await;
+
+    assert!(job.success);
+    assert_eq!(job.result, Some(json!(true)));
+    assert!(matches!(
+        get_module(&job, "a"),
+        Some(FlowStatusModule::Success { on_error: Some(_), .. })
+    ));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_pull_priority(db: Pool<Postgres>) {
+    initialize_tracing().await;
+
+    let low = RunJob::from(JobPayload::Identity).push(&db).await;
+    let high = RunJob::from(JobPayload::Identity)
+        .priority(10)
+        .push(&db)
+        .await;
+    let negative = RunJob::from(JobPayload::Identity)
+        .priority(-1)
+        .push(&db)
+        .await;
+
+    let mut pulled = vec![];
+    while let Some(job) = windmill_queue::pull::<rsmq_async::MultiplexedRsmq>(&db, None, None, None)
+        .await
+        .unwrap()
+    {
+        pulled.push(job.id);
+    }
+    assert_eq!(pulled, vec![high, low, negative]);
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_pull_concurrency_limit(db: Pool<Postgres>) {
+    initialize_tracing().await;
+
+    let limited = |customer: &str| {
+        RunJob::from(JobPayload::RawFlow {
+            value: FlowValue {
+                concurrency_limit: Some(ConcurrencyLimit {
+                    max_concurrent: 1,
+                    time_window_s: None,
+                    key: Some("customer-$args[customer]".to_string()),
+                }),
+                ..Default::default()
+            },
+            path: None,
+        })
+        .arg("customer", json!(customer))
+    };
+    let first_a = limited("a").push(&db).await;
+    let second_a = limited("a").push(&db).await;
+    let first_b = limited("b").push(&db).await;
+
+    let mut pulled = vec![];
+    while let Some(job) = windmill_queue::pull::<rsmq_async::MultiplexedRsmq>(&db, None, None, None)
+        .await
+        .unwrap()
+    {
+        pulled.push(job.id);
+    }
+    assert_eq!(pulled, vec![first_a, first_b]);
+
+    let (running, delayed) = sqlx::query_as::<_, (bool, bool)>(
+        "SELECT running, scheduled_for > now() FROM queue WHERE id = $1",
+    )
+    .bind(second_a)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert!(!running && delayed);
+}
+
 #[sqlx::test(fixtures("base"))]
 async fn test_deno_flow_same_worker(db: Pool<Postgres>) {
     initialize_tracing().await;
@@ -1144,6 +1396,10 @@ async fn test_deno_flow_same_worker(db: Pool<Postgres>) {
                     suspend: Default::default(),
                     retry: None,
                     sleep: None,
+                    priority: None,
+                    timeout: None,
+                    cache_ttl: None,
+                    on_error: None,
                 },
                 FlowModule {
                     id: "b".to_string(),
@@ -1183,6 +1439,10 @@ async fn test_deno_flow_same_worker(db: Pool<Postgres>) {
                                 suspend: Default::default(),
                                 retry: None,
                                 sleep: None,
+                                priority: None,
+                                timeout: None,
+                                cache_ttl: None,
+                                on_error: None,
                             },
                             FlowModule {
                                 id: "e".to_string(),
@@ -1209,6 +1469,10 @@ async fn test_deno_flow_same_worker(db: Pool<Postgres>) {
                                 suspend: Default::default(),
                                 retry: None,
                                 sleep: None,
+                                priority: None,
+                                timeout: None,
+                                cache_ttl: None,
+                                on_error: None,
                             },
                         ],
                     },
@@ -1217,6 +1481,10 @@ async fn test_deno_flow_same_worker(db: Pool<Postgres>) {
                     suspend: Default::default(),
                     retry: None,
                     sleep: None,
+                    priority: None,
+                    timeout: None,
+                    cache_ttl: None,
+                    on_error: None,
 
                 },
                 FlowModule {
@@ -1252,6 +1520,10 @@ async fn test_deno_flow_same_worker(db: Pool<Postgres>) {
                     suspend: Default::default(),
                     retry: None,
                     sleep: None,
+                    priority: None,
+                    timeout: None,
+                    cache_ttl: None,
+                    on_error: None,
                 },
             ],
             same_worker: true,
@@ -1322,6 +1594,83 @@ async fn test_flow_result_by_id(db: Pool<Postgres>) {
     assert_eq!(result, serde_json::json!([[42]]));
 }
 
+#[sqlx::test(fixtures("base"))]
+async fn test_result_store(db: Pool<Postgres>) {
+    /* the result store is configured once per process from the environment, the test runs
+     * again alone in a child process with a store and a threshold below the size of its results */
+    if std::env::var("RESULT_STORE_DIR").is_err() {
+        let dir = std::env::temp_dir().join(format!("windmill-results-{}", Uuid::new_v4()));
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "test_result_store", "--nocapture"])
+            .env("RESULT_STORE_DIR", &dir)
+            .env("RESULT_STORE_THRESHOLD", "100")
+            .status()
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(status.success());
+        return;
+    }
+
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let flow: FlowValue = serde_json::from_value(json!({
+        "modules": [
+            {
+                "id": "a",
+                "value": {
+                    "type": "rawscript",
+                    "language": "bash",
+                    "content": "printf 'x%.0s' $(seq 500)",
+                }
+            },
+            {
+                "id": "b",
+                "value": {
+                    "input_transforms": {"big": {"type": "javascript", "expr": "results.a"}},
+                    "type": "rawscript",
+                    "language": "bash",
+                    "content": "big=\"$1\"\necho \"${#big}\"",
+                }
+            },
+        ],
+    }))
+    .unwrap();
+    let job = RunJob::from(JobPayload::RawFlow { value: flow, path: None })
+        .run_until_complete(&db, port)
+        .await;
+    /* the input transform of the second step was given the full result of the first one */
+    assert_eq!(job.result, Some(json!("500")));
+
+    let big = json!("x".repeat(500));
+    let step = get_module(&job, "a").and_then(|m| m.job()).unwrap();
+    let stored = sqlx::query_scalar::<_, serde_json::Value>(
+        "SELECT result FROM completed_job WHERE id = $1",
+    )
+    .bind(step)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert!(stored.get("windmill_result_ref").is_some());
+
+    let get = |path: String| async move {
+        reqwest::Client::new()
+            .get(format!(
+                "http://localhost:{port}/api/w/test-workspace/jobs/{path}"
+            ))
+            .bearer_auth("SECRET_TOKEN")
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap()
+    };
+    assert_eq!(get(format!("completed/get_result/{step}")).await, big);
+    assert_eq!(get(format!("result_by_id/{}/a", job.id)).await, big);
+}
+
 #[sqlx::test(fixtures("base"))]
 async fn test_stop_after_if(db: Pool<Postgres>) {
     initialize_tracing().await;
@@ -1584,96 +1933,365 @@ echo "hello $msg"
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_python_job(db: Pool<Postgres>) {
+async fn test_postgresql_job(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
 
+    sqlx::query("CREATE TABLE pg_job_test (id INT PRIMARY KEY, name TEXT, tags TEXT[])")
+        .execute(&db)
+        .await
+        .unwrap();
+    sqlx::query(
+        "INSERT INTO pg_job_test VALUES (1, 'foo', '{a,b}'), (2, 'bar', '{b}'), (3, 'baz', NULL)",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    /* the test database itself is used as the postgresql resource */
+    let url = reqwest::Url::parse(&std::env::var("DATABASE_URL").unwrap()).unwrap();
+    let dbname = sqlx::query_scalar::<_, String>("SELECT current_database()")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    let database = json!({
+        "host": url.host_str().unwrap_or("localhost"),
+        "port": url.port().unwrap_or(5432),
+        "user": url.username(),
+        "password": url.password().unwrap_or(""),
+        "dbname": dbname,
+        "sslmode": "prefer",
+    });
+
     let content = r#"
-def main():
-    return "hello world"
-        "#
+-- $1 min_id (int) = 1
+-- $2 tag
+SELECT id, name, tags FROM pg_job_test WHERE id >= $1 AND $2 = ANY(tags) ORDER BY id;
+"#
     .to_owned();
 
-    let job = JobPayload::Code(RawCode {
+    let job = RunJob::from(JobPayload::Code(RawCode {
         content,
         path: None,
-        language: ScriptLang::Python3,
         lock: None,
-    });
-
-    let result = run_job_in_new_worker_until_complete(&db, job, port)
-        .await
-        .result
-        .unwrap();
+        language: ScriptLang::Postgresql,
+    }))
+    .arg("database", database)
+    .arg("tag", json!("b"))
+    .run_until_complete(&db, port)
+    .await;
 
-    assert_eq!(result, serde_json::json!("hello world"));
+    assert_eq!(
+        job.result,
+        Some(json!([
+            { "id": 1, "name": "foo", "tags": ["a", "b"] },
+            { "id": 2, "name": "bar", "tags": ["b"] }
+        ]))
+    );
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_python_job_heavy_dep(db: Pool<Postgres>) {
+async fn test_bash_requirements(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
 
-    let content = r#"
-import numpy as np
+    let job = RunJob::from(JobPayload::Code(RawCode {
+        content: "# requires: bash>=3\necho \"satisfied\"".to_owned(),
+        path: None,
+        lock: None,
+        language: ScriptLang::Bash,
+    }))
+    .run_until_complete(&db, port)
+    .await;
 
-def main():
-    a = np.arange(15).reshape(3, 5)
-    return len(a)
-        "#
-    .to_owned();
+    assert_eq!(job.result, Some(json!("satisfied")));
 
-    let job = JobPayload::Code(RawCode {
-        content,
+    let job = RunJob::from(JobPayload::Code(RawCode {
+        content: "# requires: bash, not-a-windmill-binary\necho \"unreachable\"".to_owned(),
         path: None,
-        language: ScriptLang::Python3,
         lock: None,
-    });
-
-    let result = run_job_in_new_worker_until_complete(&db, job, port)
-        .await
-        .result
-        .unwrap();
+        language: ScriptLang::Bash,
+    }))
+    .run_until_complete(&db, port)
+    .await;
 
-    assert_eq!(result, serde_json::json!(3));
+    assert!(!job.success);
+    assert!(job.result.as_ref().unwrap()["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("not-a-windmill-binary (not found)"));
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_python_job_with_imports(db: Pool<Postgres>) {
+async fn test_job_timeout(db: Pool<Postgres>) {
     initialize_tracing().await;
     let server = ApiServer::start(db.clone()).await;
     let port = server.addr.port();
 
     let content = r#"
-import wmill
-
-def main():
-    return wmill.get_workspace()
-        "#
+sleep 30
+echo "done"
+"#
     .to_owned();
 
-    let job = JobPayload::Code(RawCode {
+    let job = RunJob::from(JobPayload::Code(RawCode {
         content,
         path: None,
-        language: ScriptLang::Python3,
         lock: None,
-    });
-
-    let result = run_job_in_new_worker_until_complete(&db, job, port)
-        .await
-        .result
-        .unwrap();
+        language: ScriptLang::Bash,
+    }))
+    .timeout(1)
+    .run_until_complete(&db, port)
+    .await;
 
-    assert_eq!(result, serde_json::json!("test-workspace"));
+    assert!(!job.success);
+    assert_eq!(
+        job.result.as_ref().and_then(|r| r["error"].get("name")),
+        Some(&json!("TimeoutErr"))
+    );
 }
 
 #[sqlx::test(fixtures("base"))]
-async fn test_empty_loop(db: Pool<Postgres>) {
+async fn test_job_timeout_above_max_is_rejected(db: Pool<Postgres>) {
     initialize_tracing().await;
-    let server = ApiServer::start(db.clone()).await;
-    let port = server.addr.port();
+
+    let result = windmill_queue::push::<rsmq_async::MultiplexedRsmq>(
+        (None, db.begin().await.unwrap()).into(),
+        "test-workspace",
+        JobPayload::Code(RawCode {
+            content: "echo hello".to_string(),
+            path: None,
+            lock: None,
+            language: ScriptLang::Bash,
+        }),
+        Default::default(),
+        "test-user",
+        "test@windmill.dev",
+        "u/admin".to_string(),
+        None,
+        None,
+        None,
+        None,
+        None,
+        false,
+        false,
+        None,
+        true,
+        None,
+        None,
+        Some(*windmill_queue::MAX_TIMEOUT as i32 + 1),
+        None,
+    )
+    .await;
+
+    assert!(matches!(
+        result,
+        Err(windmill_common::error::Error::BadRequest(_))
+    ));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_job_memory_limit(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let content = r#"
+x=$(head -c 100000000 /dev/zero | tr '\0' a)
+sleep 5
+echo "done"
+"#;
+
+    sqlx::query(
+        "INSERT INTO script (workspace_id, hash, path, summary, description, content, \
+         created_by, language, memory_limit_mb) \
+         VALUES ('test-workspace', 42, 'u/test-user/memory_hungry', '', '', $1, 'test-user', \
+         'bash', 20)",
+    )
+    .bind(content)
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let job = RunJob::from(JobPayload::ScriptHash {
+        hash: ScriptHash(42),
+        path: "u/test-user/memory_hungry".to_string(),
+    })
+    .run_until_complete(&db, port)
+    .await;
+
+    assert!(!job.success);
+    assert_eq!(
+        job.result.as_ref().and_then(|r| r["error"].get("name")),
+        Some(&json!("MemoryLimitErr"))
+    );
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_job_updates_notifications(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let mut listener = PgListener::connect_with(&db).await.unwrap();
+    listener.listen(JOB_UPDATES_CHANNEL).await.unwrap();
+
+    let job = RunJob::from(JobPayload::Code(RawCode {
+        content: "echo \"hello from the stream\"".to_owned(),
+        path: None,
+        lock: None,
+        language: ScriptLang::Bash,
+    }))
+    .run_until_complete(&db, port)
+    .await;
+
+    let mut logs = String::new();
+    let success = loop {
+        let notification =
+            tokio::time::timeout(std::time::Duration::from_secs(10), listener.recv())
+                .await
+                .expect("timed out waiting for job updates")
+                .unwrap();
+        match serde_json::from_str::<JobUpdateNotification>(notification.payload()).unwrap() {
+            JobUpdateNotification::Logs { job_id, logs: new_logs, .. } if job_id == job.id => {
+                logs.push_str(&new_logs)
+            }
+            JobUpdateNotification::Completed { job_id, success } if job_id == job.id => {
+                break success
+            }
+            _ => (),
+        }
+    };
+
+    assert!(success);
+    assert!(logs.contains("hello from the stream"));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_job_cache_ttl(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let job = || {
+        RunJob::from(JobPayload::Code(RawCode {
+            content: "echo \"$RANDOM-$RANDOM\"".to_owned(),
+            path: None,
+            lock: None,
+            language: ScriptLang::Bash,
+        }))
+        .arg("n", json!(1))
+        .cache_ttl(60)
+    };
+
+    let first = job().run_until_complete(&db, port).await;
+    let second = job().run_until_complete(&db, port).await;
+    let other_args = job().arg("n", json!(2)).run_until_complete(&db, port).await;
+    let other_user = job()
+        .permissioned_as("u/test-user")
+        .run_until_complete(&db, port)
+        .await;
+
+    assert!(!first.cache_hit);
+    assert!(second.cache_hit);
+    assert_eq!(first.result, second.result);
+    assert!(!other_args.cache_hit);
+    assert!(!other_user.cache_hit);
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_python_job(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let content = r#"
+def main():
+    return "hello world"
+        "#
+    .to_owned();
+
+    let job = JobPayload::Code(RawCode {
+        content,
+        path: None,
+        language: ScriptLang::Python3,
+        lock: None,
+    });
+
+    let result = run_job_in_new_worker_until_complete(&db, job, port)
+        .await
+        .result
+        .unwrap();
+
+    assert_eq!(result, serde_json::json!("hello world"));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_python_job_heavy_dep(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let content = r#"
+import numpy as np
+
+def main():
+    a = np.arange(15).reshape(3, 5)
+    return len(a)
+        "#
+    .to_owned();
+
+    let job = JobPayload::Code(RawCode {
+        content,
+        path: None,
+        language: ScriptLang::Python3,
+        lock: None,
+    });
+
+    let result = run_job_in_new_worker_until_complete(&db, job, port)
+        .await
+        .result
+        .unwrap();
+
+    assert_eq!(result, serde_json::json!(3));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_python_job_with_imports(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let content = r#"
+import wmill
+
+def main():
+    return wmill.get_workspace()
+        "#
+    .to_owned();
+
+    let job = JobPayload::Code(RawCode {
+        content,
+        path: None,
+        language: ScriptLang::Python3,
+        lock: None,
+    });
+
+    let result = run_job_in_new_worker_until_complete(&db, job, port)
+        .await
+        .result
+        .unwrap();
+
+    assert_eq!(result, serde_json::json!("test-workspace"));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_empty_loop(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
 
     let flow: FlowValue = serde_json::from_value(serde_json::json!({
         "modules": [
@@ -2487,6 +3105,1189 @@ async fn test_flow_lock_all(db: Pool<Postgres>) {
         });
 }
 
+#[sqlx::test(fixtures("base"))]
+async fn test_flow_early_return(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let step = |id: &str, content: &str| {
+        json!({
+            "id": id,
+            "value": {
+                "type": "rawscript",
+                "language": "bash",
+                "content": content,
+                "input_transforms": {}
+            }
+        })
+    };
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://localhost:{port}/api/w/test-workspace/flows/create"
+        ))
+        .bearer_auth("SECRET_TOKEN")
+        .json(&json!({
+            "path": "u/test-user/early",
+            "summary": "",
+            "value": {
+                "modules": [step("a", "echo early"), step("b", "sleep 15\necho late")],
+                "early_return": "a"
+            },
+            "schema": {}
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    let (result, elapsed, flow_running) = in_test_worker(
+        &db,
+        async {
+            let start = std::time::Instant::now();
+            let result = reqwest::Client::new()
+                .post(format!(
+                    "http://localhost:{port}/api/w/test-workspace/jobs/run_wait_result/f/u/test-user/early"
+                ))
+                .bearer_auth("SECRET_TOKEN")
+                .json(&json!({}))
+                .send()
+                .await
+                .unwrap()
+                .json::<serde_json::Value>()
+                .await
+                .unwrap();
+            let flow_running = sqlx::query_scalar::<_, bool>(
+                "SELECT EXISTS(SELECT 1 FROM queue WHERE script_path = 'u/test-user/early')",
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            (result, start.elapsed(), flow_running)
+        },
+        port,
+    )
+    .await;
+
+    /* the result of the first step is returned while the second one is still sleeping */
+    assert_eq!(result, json!("early"));
+    assert!(elapsed < std::time::Duration::from_secs(15));
+    assert!(flow_running);
+
+    /* the result of a loop is only known once all of its iterations are done */
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://localhost:{port}/api/w/test-workspace/jobs/run/preview_flow"
+        ))
+        .bearer_auth("SECRET_TOKEN")
+        .json(&json!({
+            "value": {
+                "modules": [{
+                    "id": "loop",
+                    "value": {
+                        "type": "forloopflow",
+                        "iterator": { "type": "javascript", "expr": "[1, 2]" },
+                        "modules": [step("a", "echo early")]
+                    }
+                }],
+                "early_return": "loop"
+            },
+            "args": {}
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_flow_versions(db: Pool<Postgres>) {
+    use windmill_api_client::types::{OpenFlow, OpenFlowWPath};
+
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let client = windmill_api_client::create_client(
+        &format!("http://localhost:{port}"),
+        "SECRET_TOKEN".to_string(),
+    );
+    let flow = |same_worker: bool| -> OpenFlow {
+        serde_json::from_value(json!({
+            "summary": "",
+            "value": { "modules": [], "same_worker": same_worker },
+            "schema": {}
+        }))
+        .unwrap()
+    };
+    let path = "u/test-user/versioned_flow";
+    client
+        .create_flow(
+            "test-workspace",
+            &CreateFlowBody {
+                open_flow_w_path: OpenFlowWPath { open_flow: flow(false), path: path.to_string() },
+                draft_only: None,
+            },
+        )
+        .await
+        .unwrap();
+    client
+        .update_flow(
+            "test-workspace",
+            path,
+            &OpenFlowWPath { open_flow: flow(true), path: path.to_string() },
+        )
+        .await
+        .unwrap();
+
+    let versions = client
+        .list_flow_versions("test-workspace", path, None, None)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(versions.len(), 2);
+    let (latest, first) = (versions[0].id, versions[1].id);
+
+    /* jobs keep the version they were pushed with */
+    let job = RunJob::from(JobPayload::Flow(path.to_string()))
+        .push(&db)
+        .await;
+    let pinned =
+        sqlx::query_scalar::<_, Option<i64>>("SELECT flow_version FROM queue WHERE id = $1")
+            .bind(job)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    assert_eq!(pinned, Some(latest));
+
+    client
+        .restore_flow_version("test-workspace", first)
+        .await
+        .unwrap();
+    let versions = client
+        .list_flow_versions("test-workspace", path, None, None)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(versions.len(), 3);
+    let restored = client
+        .get_flow_version("test-workspace", versions[0].id)
+        .await
+        .unwrap()
+        .into_inner();
+    let first = client
+        .get_flow_version("test-workspace", first)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(restored.value.same_worker, first.value.same_worker);
+    assert_eq!(restored.value.same_worker, Some(false));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_workspace_key_rotation(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let client = windmill_api_client::create_client(
+        &format!("http://localhost:{port}"),
+        "SECRET_TOKEN".to_string(),
+    );
+    client
+        .create_variable(
+            "test-workspace",
+            None,
+            &windmill_api_client::types::CreateVariable {
+                path: "u/test-user/rotated".to_string(),
+                value: "hunter2".to_string(),
+                is_secret: true,
+                description: "".to_string(),
+                account: None,
+                is_oauth: None,
+            },
+        )
+        .await
+        .unwrap();
+    let encrypted_with_old_key = sqlx::query_scalar::<_, String>(
+        "SELECT value FROM variable WHERE path = 'u/test-user/rotated'",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    client.rotate_workspace_key("test-workspace").await.unwrap();
+
+    let reencrypted = sqlx::query_scalar::<_, String>(
+        "SELECT value FROM variable WHERE path = 'u/test-user/rotated'",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_ne!(reencrypted, encrypted_with_old_key);
+
+    /* a secret submitted encrypted with the previous key still decrypts */
+    sqlx::query(
+        "INSERT INTO variable (workspace_id, path, value, is_secret) \
+         VALUES ('test-workspace', 'u/test-user/imported', $1, true)",
+    )
+    .bind(&encrypted_with_old_key)
+    .execute(&db)
+    .await
+    .unwrap();
+
+    /* as is one that the next rotation re-encrypts before it is read */
+    client.rotate_workspace_key("test-workspace").await.unwrap();
+
+    for path in ["u/test-user/rotated", "u/test-user/imported"] {
+        let variable = client
+            .get_variable("test-workspace", path, None)
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(variable.value, Some("hunter2".to_string()));
+    }
+
+    /* a secret that no key decrypts aborts the rotation instead of being lost */
+    let key = || {
+        sqlx::query_scalar::<_, String>(
+            "SELECT key FROM workspace_key WHERE workspace_id = 'test-workspace'",
+        )
+        .fetch_one(&db)
+    };
+    let key_before = key().await.unwrap();
+    sqlx::query(
+        "INSERT INTO variable (workspace_id, path, value, is_secret) \
+         VALUES ('test-workspace', 'u/test-user/undecryptable', 'not encrypted', true)",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    assert!(client.rotate_workspace_key("test-workspace").await.is_err());
+    assert_eq!(key().await.unwrap(), key_before);
+    let variable = client
+        .get_variable("test-workspace", "u/test-user/rotated", None)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(variable.value, Some("hunter2".to_string()));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_variable_versions(db: Pool<Postgres>) {
+    use windmill_api_client::types::{CreateVariable, EditVariable, RestoreVariableVersionBody};
+
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let client = windmill_api_client::create_client(
+        &format!("http://localhost:{port}"),
+        "SECRET_TOKEN".to_string(),
+    );
+    let path = "u/test-user/versioned";
+    client
+        .create_variable(
+            "test-workspace",
+            None,
+            &CreateVariable {
+                path: path.to_string(),
+                value: "first".to_string(),
+                is_secret: false,
+                description: "".to_string(),
+                account: None,
+                is_oauth: None,
+            },
+        )
+        .await
+        .unwrap();
+    client
+        .update_variable(
+            "test-workspace",
+            path,
+            None,
+            &EditVariable {
+                path: None,
+                value: Some("second".to_string()),
+                is_secret: None,
+                description: None,
+            },
+        )
+        .await
+        .unwrap();
+
+    let versions = client
+        .list_variable_versions("test-workspace", path, None, None)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(versions.len(), 1);
+    assert_eq!(versions[0].value, Some("first".to_string()));
+
+    let diff = client
+        .diff_variable_version("test-workspace", path, versions[0].id)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(diff.previous, "first");
+    assert_eq!(diff.current, "second");
+
+    client
+        .restore_variable_version(
+            "test-workspace",
+            path,
+            &RestoreVariableVersionBody { version: versions[0].id },
+        )
+        .await
+        .unwrap();
+    let variable = client
+        .get_variable("test-workspace", path, None)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(variable.value, Some("first".to_string()));
+
+    /* the value replaced by the restore is kept too */
+    let versions = client
+        .list_variable_versions("test-workspace", path, None, None)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[0].value, Some("second".to_string()));
+
+    let restores = sqlx::query_scalar::<_, i64>(
+        "SELECT count(*) FROM audit WHERE operation = 'variables.restore' AND resource = $1",
+    )
+    .bind(path)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(restores, 1);
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_script_restore(db: Pool<Postgres>) {
+    use windmill_common::scripts::to_i64;
+
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let post = |route: String, body: serde_json::Value| async move {
+        let response = reqwest::Client::new()
+            .post(format!(
+                "http://localhost:{port}/api/w/test-workspace/scripts/{route}"
+            ))
+            .bearer_auth("SECRET_TOKEN")
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        to_i64(&response.text().await.unwrap()).unwrap()
+    };
+    let script = |content: &str, parent_hash: Option<i64>| {
+        json!({
+            "path": "u/test-user/restored",
+            "parent_hash": parent_hash.map(|h| ScriptHash(h).to_string()),
+            "summary": "",
+            "description": "",
+            "content": content,
+            "language": "bash",
+        })
+    };
+    let first = post("create".to_string(), script("echo first", None)).await;
+    let second = post("create".to_string(), script("echo second", Some(first))).await;
+
+    let restored = post(
+        format!("restore/h/{}", ScriptHash(first)),
+        serde_json::Value::Null,
+    )
+    .await;
+
+    let (parent_hashes, content, archived) = sqlx::query_as::<_, (Vec<i64>, String, bool)>(
+        "SELECT parent_hashes, content, archived FROM script WHERE hash = $1",
+    )
+    .bind(restored)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    /* the restored version is a new head on top of the current one */
+    assert_eq!(parent_hashes, vec![second, first]);
+    assert_eq!(content, "echo first");
+    assert!(!archived);
+
+    let second_archived =
+        sqlx::query_scalar::<_, bool>("SELECT archived FROM script WHERE hash = $1")
+            .bind(second)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    assert!(second_archived);
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_schedule_catch_up(db: Pool<Postgres>) {
+    use windmill_api_client::types::{BackfillScheduleBody, OpenFlowWPath, SetScheduleEnabledBody};
+
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let client = windmill_api_client::create_client(
+        &format!("http://localhost:{port}"),
+        "SECRET_TOKEN".to_string(),
+    );
+    let flow: OpenFlowWPath = serde_json::from_value(json!({
+        "path": "u/test-user/etl",
+        "summary": "",
+        "value": { "modules": [] },
+        "schema": {}
+    }))
+    .unwrap();
+    client
+        .create_flow(
+            "test-workspace",
+            &CreateFlowBody { open_flow_w_path: flow, draft_only: None },
+        )
+        .await
+        .unwrap();
+    let path = "u/test-user/daily_etl";
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://localhost:{port}/api/w/test-workspace/schedules/create"
+        ))
+        .bearer_auth("SECRET_TOKEN")
+        .json(&json!({
+            "path": path,
+            "schedule": "0 0 * * * *",
+            "timezone": "UTC",
+            "script_path": "u/test-user/etl",
+            "is_flow": true,
+            "args": {},
+            "enabled": false,
+            "catch_up": "once"
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    let range = BackfillScheduleBody {
+        from: "2023-01-01T00:00:00Z".parse().unwrap(),
+        to: "2023-01-01T02:00:00Z".parse().unwrap(),
+        tick_arg: None,
+    };
+    let ticks = client
+        .backfill_schedule("test-workspace", path, &range)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(ticks.len(), 3);
+    let args = sqlx::query_scalar::<_, String>(
+        "SELECT args->>'scheduled_for' FROM queue WHERE schedule_path = $1 ORDER BY scheduled_for",
+    )
+    .bind(path)
+    .fetch_all(&db)
+    .await
+    .unwrap();
+    assert_eq!(
+        args,
+        vec![
+            "2023-01-01T00:00:00+00:00",
+            "2023-01-01T01:00:00+00:00",
+            "2023-01-01T02:00:00+00:00"
+        ]
+    );
+
+    /* ticks which already have a queued job are not backfilled again */
+    let ticks = client
+        .backfill_schedule("test-workspace", path, &range)
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(ticks.is_empty());
+
+    /* only the latest of the ticks missed while disabled is run when enabled again */
+    sqlx::query("UPDATE schedule SET last_tick = now() - interval '5 hours' WHERE path = $1")
+        .bind(path)
+        .execute(&db)
+        .await
+        .unwrap();
+    client
+        .set_schedule_enabled(
+            "test-workspace",
+            path,
+            &SetScheduleEnabledBody { enabled: true, paused_until: None },
+        )
+        .await
+        .unwrap();
+    let (missed, next) = sqlx::query_as::<_, (i64, i64)>(
+        "SELECT count(*) FILTER (WHERE scheduled_for <= now()), count(*) FILTER (WHERE \
+         scheduled_for > now()) FROM queue WHERE schedule_path = $1 AND args = '{}'::jsonb",
+    )
+    .bind(path)
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!((missed, next), (1, 1));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_schedule_pause_and_one_shot(db: Pool<Postgres>) {
+    use windmill_api_client::types::{OpenFlowWPath, PreviewScheduleBody, SetScheduleEnabledBody};
+
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let client = windmill_api_client::create_client(
+        &format!("http://localhost:{port}"),
+        "SECRET_TOKEN".to_string(),
+    );
+    let flow: OpenFlowWPath = serde_json::from_value(json!({
+        "path": "u/test-user/report",
+        "summary": "",
+        "value": { "modules": [] },
+        "schema": {}
+    }))
+    .unwrap();
+    client
+        .create_flow(
+            "test-workspace",
+            &CreateFlowBody { open_flow_w_path: flow, draft_only: None },
+        )
+        .await
+        .unwrap();
+    let create_schedule = |schedule: serde_json::Value| {
+        let request = reqwest::Client::new()
+            .post(format!(
+                "http://localhost:{port}/api/w/test-workspace/schedules/create"
+            ))
+            .bearer_auth("SECRET_TOKEN")
+            .json(&schedule)
+            .send();
+        async move { assert!(request.await.unwrap().status().is_success()) }
+    };
+    let queued = |path: &'static str| {
+        sqlx::query_scalar::<_, chrono::DateTime<chrono::Utc>>(
+            "SELECT scheduled_for FROM queue WHERE schedule_path = $1",
+        )
+        .bind(path)
+        .fetch_all(&db)
+    };
+
+    /* a one-shot schedule queues its single run */
+    let run_at: chrono::DateTime<chrono::Utc> = "2099-01-01T12:00:00Z".parse().unwrap();
+    create_schedule(json!({
+        "path": "u/test-user/one_shot",
+        "schedule": "",
+        "timezone": "UTC",
+        "script_path": "u/test-user/report",
+        "is_flow": true,
+        "args": {},
+        "enabled": true,
+        "run_at": run_at
+    }))
+    .await;
+    assert_eq!(queued("u/test-user/one_shot").await.unwrap(), vec![run_at]);
+
+    /* a paused schedule resumes with the first tick after the pause */
+    create_schedule(json!({
+        "path": "u/test-user/hourly",
+        "schedule": "0 0 * * * *",
+        "timezone": "UTC",
+        "script_path": "u/test-user/report",
+        "is_flow": true,
+        "args": {},
+        "enabled": true
+    }))
+    .await;
+    let paused_until: chrono::DateTime<chrono::Utc> = "2099-01-01T12:30:00Z".parse().unwrap();
+    client
+        .set_schedule_enabled(
+            "test-workspace",
+            "u/test-user/hourly",
+            &SetScheduleEnabledBody { enabled: true, paused_until: Some(paused_until) },
+        )
+        .await
+        .unwrap();
+    let resume: chrono::DateTime<chrono::Utc> = "2099-01-01T13:00:00Z".parse().unwrap();
+    assert_eq!(queued("u/test-user/hourly").await.unwrap(), vec![resume]);
+
+    let preview = client
+        .preview_schedule(&PreviewScheduleBody {
+            schedule: "0 0 * * * *".to_string(),
+            timezone: "UTC".to_string(),
+            paused_until: Some(paused_until),
+            run_at: None,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(preview[0], resume);
+}
+
+async fn create_bash_script(port: u16, path: &str, content: &str) {
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://localhost:{port}/api/w/test-workspace/scripts/create"
+        ))
+        .bearer_auth("SECRET_TOKEN")
+        .json(&json!({
+            "path": path,
+            "summary": "",
+            "description": "",
+            "content": content,
+            "language": "bash",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+}
+
+/* runs a queued job now, keeping when it was scheduled for if it is past, and waits for it */
+async fn run_queued_job(db: &Pool<Postgres>, port: u16, uuid: Uuid) -> CompletedJob {
+    let listener = listen_for_completed_jobs(db).await;
+    sqlx::query("UPDATE queue SET scheduled_for = LEAST(scheduled_for, now()) WHERE id = $1")
+        .bind(uuid)
+        .execute(db)
+        .await
+        .unwrap();
+    in_test_worker(db, listener.find(&uuid), port).await;
+    completed_job(uuid, db).await
+}
+
+/* runs the next tick of the schedule, queued with its empty args, now with the given args */
+async fn run_schedule_tick(
+    db: &Pool<Postgres>,
+    port: u16,
+    schedule_path: &str,
+    args: serde_json::Value,
+) -> CompletedJob {
+    let uuid = sqlx::query_scalar::<_, Uuid>(
+        "UPDATE queue SET args = $2 WHERE schedule_path = $1 AND running = false AND \
+         args = '{}'::jsonb RETURNING id",
+    )
+    .bind(schedule_path)
+    .bind(args)
+    .fetch_one(db)
+    .await
+    .unwrap();
+    run_queued_job(db, port, uuid).await
+}
+
+/* the args of the jobs pushed for a script, in the order they were pushed */
+async fn pushed_args(db: &Pool<Postgres>, script_path: &str) -> Vec<serde_json::Value> {
+    sqlx::query_scalar::<_, serde_json::Value>(
+        "SELECT args FROM (SELECT script_path, args, created_at FROM queue UNION ALL \
+         SELECT script_path, args, created_at FROM completed_job) j WHERE script_path = $1 \
+         ORDER BY created_at",
+    )
+    .bind(script_path)
+    .fetch_all(db)
+    .await
+    .unwrap()
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_schedule_handlers(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    create_bash_script(
+        port,
+        "u/test-user/flaky",
+        "fail=\"$1\"\nif [ \"$fail\" = \"true\" ]; then\n  exit 1\nfi\necho ok",
+    )
+    .await;
+    for handler in ["on_failure", "on_recovery", "on_success"] {
+        create_bash_script(port, &format!("u/test-user/{handler}"), "echo handled").await;
+    }
+    let path = "u/test-user/flaky_schedule";
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://localhost:{port}/api/w/test-workspace/schedules/create"
+        ))
+        .bearer_auth("SECRET_TOKEN")
+        .json(&json!({
+            "path": path,
+            "schedule": "0 0 * * * *",
+            "timezone": "UTC",
+            "script_path": "u/test-user/flaky",
+            "is_flow": false,
+            "args": {},
+            "enabled": true,
+            "on_failure": "script/u/test-user/on_failure",
+            "on_recovery": "script/u/test-user/on_recovery",
+            "on_success": "script/u/test-user/on_success",
+            "failure_threshold": 2
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    /* the failure handler runs once, when the streak reaches the threshold */
+    let mut failures = vec![];
+    for _ in 0..3 {
+        let job = run_schedule_tick(&db, port, path, json!({ "fail": "true" })).await;
+        assert!(!job.success);
+        failures.push(job.result.unwrap());
+    }
+    let on_failure = pushed_args(&db, "u/test-user/on_failure").await;
+    assert_eq!(on_failure.len(), 1);
+    assert_eq!(on_failure[0]["failure_count"], json!(2));
+    assert_eq!(on_failure[0]["previous_error"], failures[0]);
+    assert_eq!(on_failure[0]["schedule_path"], json!(path));
+
+    /* the first success after it reports the whole streak and its last error */
+    for _ in 0..2 {
+        let job = run_schedule_tick(&db, port, path, json!({ "fail": "false" })).await;
+        assert!(job.success);
+    }
+    let on_recovery = pushed_args(&db, "u/test-user/on_recovery").await;
+    assert_eq!(on_recovery.len(), 1);
+    assert_eq!(on_recovery[0]["failure_count"], json!(3));
+    assert_eq!(on_recovery[0]["previous_error"], failures[2]);
+    assert_eq!(on_recovery[0]["result"], json!("ok"));
+    let on_success = pushed_args(&db, "u/test-user/on_success").await;
+    assert_eq!(on_success.len(), 2);
+    assert_eq!(on_success[1]["result"], json!("ok"));
+    assert_eq!(pushed_args(&db, "u/test-user/on_failure").await.len(), 1);
+
+    let failure_count =
+        sqlx::query_scalar::<_, i32>("SELECT failure_count FROM schedule WHERE path = $1")
+            .bind(path)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    assert_eq!(failure_count, 0);
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_schedule_retry(db: Pool<Postgres>) {
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    create_bash_script(
+        port,
+        "u/test-user/flaky",
+        "fail=\"$1\"\nif [ \"$fail\" = \"true\" ]; then\n  exit 1\nfi\necho ok",
+    )
+    .await;
+    create_bash_script(port, "u/test-user/on_failure", "echo handled").await;
+    let path = "u/test-user/retried_schedule";
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://localhost:{port}/api/w/test-workspace/schedules/create"
+        ))
+        .bearer_auth("SECRET_TOKEN")
+        .json(&json!({
+            "path": path,
+            "schedule": "0 0 * * * *",
+            "timezone": "UTC",
+            "script_path": "u/test-user/flaky",
+            "is_flow": false,
+            "args": {},
+            "enabled": true,
+            "on_failure": "script/u/test-user/on_failure",
+            "retry": { "constant": { "attempts": 2, "seconds": 600 } }
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    let now =
+        || sqlx::query_scalar::<_, chrono::DateTime<chrono::Utc>>("SELECT now()").fetch_one(&db);
+    let retry = || {
+        sqlx::query_as::<_, (Uuid, chrono::DateTime<chrono::Utc>)>(
+            "SELECT id, scheduled_for FROM queue WHERE schedule_path = $1 AND args = $2",
+        )
+        .bind(path)
+        .bind(json!({ "fail": "true" }))
+        .fetch_optional(&db)
+    };
+    let attempts = || {
+        sqlx::query_as::<_, (i32, i32)>(
+            "SELECT retry_attempts, failure_count FROM schedule WHERE path = $1",
+        )
+        .bind(path)
+        .fetch_one(&db)
+    };
+    let last_tick = || {
+        sqlx::query_scalar::<_, Option<chrono::DateTime<chrono::Utc>>>(
+            "SELECT last_tick FROM schedule WHERE path = $1",
+        )
+        .bind(path)
+        .fetch_one(&db)
+    };
+    let tick: chrono::DateTime<chrono::Utc> = "2023-01-01T05:00:00Z".parse().unwrap();
+    sqlx::query("UPDATE queue SET scheduled_for = $2 WHERE schedule_path = $1")
+        .bind(path)
+        .bind(tick)
+        .execute(&db)
+        .await
+        .unwrap();
+
+    /* the failed job is pushed again, with the same args, after the retry delay */
+    let before = now().await.unwrap();
+    let job = run_schedule_tick(&db, port, path, json!({ "fail": "true" })).await;
+    assert!(!job.success);
+    let after = now().await.unwrap();
+    let (retry_id, retry_at) = retry().await.unwrap().unwrap();
+    let delay = chrono::Duration::seconds(600);
+    assert!(retry_at >= before + delay && retry_at <= after + delay);
+    assert_eq!(attempts().await.unwrap(), (1, 0));
+    assert!(pushed_args(&db, "u/test-user/on_failure").await.is_empty());
+    assert_eq!(last_tick().await.unwrap(), Some(tick));
+
+    /* a retry is not a tick, the ticks up to when it runs are still to be caught up */
+    sqlx::query("UPDATE queue SET scheduled_for = $2 WHERE id = $1")
+        .bind(retry_id)
+        .bind(tick + chrono::Duration::minutes(10))
+        .execute(&db)
+        .await
+        .unwrap();
+    let job = run_queued_job(&db, port, retry_id).await;
+    assert!(!job.success);
+    let (retry_id, _) = retry().await.unwrap().unwrap();
+    assert_eq!(attempts().await.unwrap(), (2, 0));
+    assert!(pushed_args(&db, "u/test-user/on_failure").await.is_empty());
+    assert_eq!(last_tick().await.unwrap(), Some(tick));
+
+    /* the failure is only counted and handled once the retries are exhausted */
+    let job = run_queued_job(&db, port, retry_id).await;
+    assert!(!job.success);
+    assert!(retry().await.unwrap().is_none());
+    assert_eq!(attempts().await.unwrap(), (0, 1));
+    let on_failure = pushed_args(&db, "u/test-user/on_failure").await;
+    assert_eq!(on_failure.len(), 1);
+    assert_eq!(on_failure[0]["failure_count"], json!(1));
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_workspace_import(db: Pool<Postgres>) {
+    use windmill_api_client::types::CreateVariable;
+
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let client = windmill_api_client::create_client(
+        &format!("http://localhost:{port}"),
+        "SECRET_TOKEN".to_string(),
+    );
+    for (path, is_secret) in [("u/test-user/plain", false), ("u/test-user/secret", true)] {
+        client
+            .create_variable(
+                "test-workspace",
+                None,
+                &CreateVariable {
+                    path: path.to_string(),
+                    value: format!("{path} value"),
+                    is_secret,
+                    description: "".to_string(),
+                    account: None,
+                    is_oauth: None,
+                },
+            )
+            .await
+            .unwrap();
+    }
+
+    let http = reqwest::Client::new();
+    let status = http
+        .post(format!(
+            "http://localhost:{port}/api/w/test-workspace/scripts/create"
+        ))
+        .bearer_auth("SECRET_TOKEN")
+        .json(&json!({
+            "path": "u/test-user/limited",
+            "summary": "",
+            "description": "",
+            "content": "echo limited",
+            "language": "bash",
+            "tag": "limited",
+            "concurrency_limit": { "max_concurrent": 2 },
+            "timeout": 60,
+            "cache_ttl": 30,
+            "memory_limit_mb": 256,
+            "cpu_limit_millis": 500,
+        }))
+        .send()
+        .await
+        .unwrap()
+        .status();
+    assert!(status.is_success());
+
+    let base = format!("http://localhost:{port}/api/w/test-workspace/workspaces");
+    let tarball = http
+        .get(format!("{base}/tarball?archive_type=tar"))
+        .bearer_auth("SECRET_TOKEN")
+        .send()
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    let import = |query: &'static str| {
+        let request = http
+            .post(format!("{base}/import_tarball?{query}"))
+            .bearer_auth("SECRET_TOKEN")
+            .body(tarball.clone())
+            .send();
+        async move {
+            let response = request.await.unwrap();
+            assert_eq!(response.status(), 200);
+            response.json::<serde_json::Value>().await.unwrap()
+        }
+    };
+
+    /* a dry run reports the conflicts without writing anything */
+    client
+        .delete_variable("test-workspace", "u/test-user/plain")
+        .await
+        .unwrap();
+    let report = import("dry_run=true&on_conflict=overwrite").await;
+    assert_eq!(report["created"], json!(["variable u/test-user/plain"]));
+    assert_eq!(
+        report["overwritten"],
+        json!(["variable u/test-user/secret", "script u/test-user/limited"])
+    );
+    assert_eq!(report["errors"], json!([]));
+    assert!(client
+        .get_variable("test-workspace", "u/test-user/plain", None)
+        .await
+        .is_err());
+
+    let report = import("on_conflict=skip").await;
+    assert_eq!(report["created"], json!(["variable u/test-user/plain"]));
+    assert_eq!(
+        report["skipped"],
+        json!(["variable u/test-user/secret", "script u/test-user/limited"])
+    );
+    let variable = client
+        .get_variable("test-workspace", "u/test-user/plain", None)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(variable.value, Some("u/test-user/plain value".to_string()));
+
+    /* the secret is decrypted from the archive and encrypted again when overwritten */
+    let report = import("on_conflict=overwrite").await;
+    assert_eq!(report["errors"], json!([]));
+    let variable = client
+        .get_variable("test-workspace", "u/test-user/secret", Some(true))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(variable.value, Some("u/test-user/secret value".to_string()));
+
+    /* the settings of the script are exported and restored with it */
+    let settings = sqlx::query_as::<
+        _,
+        (
+            Option<String>,
+            Option<serde_json::Value>,
+            Option<i32>,
+            Option<i32>,
+            Option<i32>,
+            Option<i32>,
+            Vec<i64>,
+        ),
+    >(
+        "SELECT tag, concurrency_limit, timeout, cache_ttl, memory_limit_mb, cpu_limit_millis, \
+         parent_hashes FROM script WHERE path = 'u/test-user/limited' AND archived = false",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(settings.0.as_deref(), Some("limited"));
+    assert_eq!(settings.1, Some(json!({ "max_concurrent": 2 })));
+    assert_eq!(
+        (settings.2, settings.3, settings.4, settings.5),
+        (Some(60), Some(30), Some(256), Some(500))
+    );
+    assert_eq!(settings.6.len(), 1);
+}
+
+#[sqlx::test(fixtures("base"))]
+async fn test_email_triggers(db: Pool<Postgres>) {
+    use windmill_api_client::types::{EditEmailTrigger, NewEmailTrigger};
+
+    initialize_tracing().await;
+    let server = ApiServer::start(db.clone()).await;
+    let port = server.addr.port();
+
+    let client = windmill_api_client::create_client(
+        &format!("http://localhost:{port}"),
+        "SECRET_TOKEN".to_string(),
+    );
+    let created = client
+        .create_email_trigger(
+            "test-workspace",
+            &NewEmailTrigger {
+                path: "u/test-user/on_email".to_string(),
+                is_flow: false,
+                allowed_senders: vec!["@windmill.dev".to_string()],
+                token: Some("abc123".to_string()),
+                enabled: None,
+            },
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(created.token, "abc123");
+    let stored_token = || {
+        sqlx::query_scalar::<_, String>("SELECT token FROM email_trigger WHERE path = $1")
+            .bind("u/test-user/on_email")
+            .fetch_one(&db)
+    };
+
+    let trigger = client
+        .get_email_trigger("test-workspace", "u/test-user/on_email")
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(trigger.enabled);
+    assert_eq!(trigger.edited_by, "test-user");
+
+    /* the token is only returned by the creation */
+    let trigger = reqwest::Client::new()
+        .get(format!(
+            "http://localhost:{port}/api/w/test-workspace/email_triggers/get/u/test-user/on_email"
+        ))
+        .bearer_auth("SECRET_TOKEN")
+        .send()
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+    assert!(trigger.get("token").is_none());
+
+    /* the path and the token are part of the recipient address */
+    let status = reqwest::Client::new()
+        .post(format!(
+            "http://localhost:{port}/api/w/test-workspace/email_triggers/create"
+        ))
+        .bearer_auth("SECRET_TOKEN")
+        .json(&json!({
+            "path": "u/test-user/a+b",
+            "is_flow": false,
+            "allowed_senders": ["*"]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .status();
+    assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+
+    /* a trigger always has a token, generated when none is given */
+    let generated = client
+        .create_email_trigger(
+            "test-workspace",
+            &NewEmailTrigger {
+                path: "u/test-user/generated".to_string(),
+                is_flow: false,
+                allowed_senders: vec!["*".to_string()],
+                token: None,
+                enabled: None,
+            },
+        )
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(generated.token.len(), 32);
+    assert!(generated.token.chars().all(|c| c.is_ascii_alphanumeric()));
+    client
+        .delete_email_trigger("test-workspace", "u/test-user/generated")
+        .await
+        .unwrap();
+
+    /* the triggers of a user are neither visible nor writable by the other members */
+    sqlx::query(
+        "INSERT INTO usr(workspace_id, email, username, is_admin, role) VALUES \
+         ('test-workspace', 'other@windmill.dev', 'other-user', false, 'Developer')",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO token(token, email, label, super_admin) VALUES ('OTHER_TOKEN', \
+         'other@windmill.dev', 'other token', false)",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    let other = windmill_api_client::create_client(
+        &format!("http://localhost:{port}"),
+        "OTHER_TOKEN".to_string(),
+    );
+    assert!(other
+        .list_email_triggers("test-workspace", None, None)
+        .await
+        .unwrap()
+        .into_inner()
+        .is_empty());
+    let edit = EditEmailTrigger {
+        is_flow: false,
+        allowed_senders: vec!["*".to_string()],
+        token: Some("stolen".to_string()),
+        enabled: None,
+    };
+    assert!(other
+        .update_email_trigger("test-workspace", "u/test-user/on_email", &edit)
+        .await
+        .is_err());
+    assert!(other
+        .delete_email_trigger("test-workspace", "u/test-user/on_email")
+        .await
+        .is_err());
+    assert_eq!(stored_token().await.unwrap(), "abc123");
+
+    client
+        .update_email_trigger(
+            "test-workspace",
+            "u/test-user/on_email",
+            &EditEmailTrigger {
+                is_flow: false,
+                allowed_senders: vec!["alice@windmill.dev".to_string()],
+                token: None,
+                enabled: Some(false),
+            },
+        )
+        .await
+        .unwrap();
+    let triggers = client
+        .list_email_triggers("test-workspace", None, None)
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(triggers.len(), 1);
+    assert!(!triggers[0].enabled);
+    assert_eq!(triggers[0].edited_by, "test-user");
+    assert_eq!(triggers[0].allowed_senders, vec!["alice@windmill.dev"]);
+    assert_eq!(stored_token().await.unwrap(), "abc123");
+
+    client
+        .delete_email_trigger("test-workspace", "u/test-user/on_email")
+        .await
+        .unwrap();
+    assert!(client
+        .list_email_triggers("test-workspace", None, None)
+        .await
+        .unwrap()
+        .into_inner()
+        .is_empty());
+}
+
 #[sqlx::test(fixtures("base"))]
 async fn test_rust_client(db: Pool<Postgres>) {
     initialize_tracing().await;
@@ -368,7 +368,7 @@ async fn get_secret_id(
     Ok(hx)
 }
 
-async fn create_app(
+pub(crate) async fn create_app(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Extension(webhook): Extension<WebhookShared>,
@@ -531,7 +531,7 @@ async fn delete_app(
     Ok(format!("app {} deleted", path))
 }
 
-async fn update_app(
+pub(crate) async fn update_app(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Extension(webhook): Extension<WebhookShared>,
@@ -671,6 +671,7 @@ pub struct ExecuteApp {
     pub raw_code: Option<RawCode>,
     // if set, the app is executed as viewer with the given static fields
     pub force_viewer_static_fields: Option<StaticFields>,
+    pub priority: Option<i16>,
 }
 
 fn digest(code: &str) -> String {
@@ -801,6 +802,9 @@ async fn execute_component(
         None,
         true,
         tag,
+        payload.priority,
+        None,
+        None,
     )
     .await?;
     tx.commit().await?;
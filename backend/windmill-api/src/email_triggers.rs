@@ -0,0 +1,266 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2023
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+use axum::{
+    extract::{Extension, Path, Query},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use windmill_audit::{audit_log, ActionKind};
+use windmill_common::{
+    error::{Error, JsonResult, Result},
+    utils::{not_found_if_none, paginate, rd_string, Pagination, StripPath},
+};
+
+use crate::{
+    db::{UserDB, DB},
+    users::{maybe_refresh_folders, Authed},
+};
+
+pub fn workspaced_service() -> Router {
+    Router::new()
+        .route("/list", get(list_email_triggers))
+        .route("/get/*path", get(get_email_trigger))
+        .route("/create", post(create_email_trigger))
+        .route("/update/*path", post(update_email_trigger))
+        .route("/delete/*path", delete(delete_email_trigger))
+}
+
+/* the token is only ever returned once, when the trigger is created */
+#[derive(sqlx::FromRow, Serialize, Clone)]
+pub struct EmailTrigger {
+    pub workspace_id: String,
+    pub path: String,
+    pub is_flow: bool,
+    pub allowed_senders: Vec<String>,
+    #[serde(skip_serializing)]
+    pub token: String,
+    pub enabled: bool,
+    pub edited_by: String,
+    pub email: String,
+    pub edited_at: DateTime<Utc>,
+    pub extra_perms: serde_json::Value,
+}
+
+#[derive(Serialize)]
+pub struct CreatedEmailTrigger {
+    pub path: String,
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewEmailTrigger {
+    pub path: String,
+    pub is_flow: bool,
+    pub allowed_senders: Vec<String>,
+    pub token: Option<String>,
+    pub enabled: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct EditEmailTrigger {
+    pub is_flow: bool,
+    pub allowed_senders: Vec<String>,
+    pub token: Option<String>,
+    pub enabled: Option<bool>,
+}
+
+/* length of the token generated when a trigger is created without one */
+const TOKEN_LENGTH: usize = 32;
+
+/* the path and the token end up in the local part of the recipient address
+ * <workspace>+<path>+<token>@domain, which is split on '+'. The token is what authenticates an
+ * email, the allowed senders can be forged */
+fn check_email_trigger(path: &str, token: Option<&str>, allowed_senders: &[String]) -> Result<()> {
+    if path.contains('+') {
+        return Err(Error::BadRequest(format!(
+            "path {path} of an email trigger cannot contain '+'"
+        )));
+    }
+    if let Some(token) = token {
+        if token.is_empty() || !token.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(Error::BadRequest(
+                "email trigger token must be non-empty and alphanumeric".to_string(),
+            ));
+        }
+    }
+    if let Some(sender) = allowed_senders
+        .iter()
+        .find(|s| s.as_str() != "*" && !s.contains('@'))
+    {
+        return Err(Error::BadRequest(format!(
+            "allowed sender {sender} must be an address, a domain starting with '@' or '*'"
+        )));
+    }
+    Ok(())
+}
+
+async fn list_email_triggers(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+    Query(pagination): Query<Pagination>,
+) -> JsonResult<Vec<EmailTrigger>> {
+    let mut tx = user_db.begin(&authed).await?;
+    let (per_page, offset) = paginate(pagination);
+    let rows = sqlx::query_as!(
+        EmailTrigger,
+        "SELECT * FROM email_trigger WHERE workspace_id = $1 ORDER BY edited_at desc LIMIT $2 \
+         OFFSET $3",
+        w_id,
+        per_page as i64,
+        offset as i64
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Json(rows))
+}
+
+async fn get_email_trigger(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+) -> JsonResult<EmailTrigger> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+    let trigger_o = sqlx::query_as!(
+        EmailTrigger,
+        "SELECT * FROM email_trigger WHERE workspace_id = $1 AND path = $2",
+        w_id,
+        path
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let trigger = not_found_if_none(trigger_o, "Email trigger", path)?;
+    tx.commit().await?;
+    Ok(Json(trigger))
+}
+
+async fn create_email_trigger(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Extension(user_db): Extension<UserDB>,
+    Path(w_id): Path<String>,
+    Json(nt): Json<NewEmailTrigger>,
+) -> JsonResult<CreatedEmailTrigger> {
+    check_email_trigger(&nt.path, nt.token.as_deref(), &nt.allowed_senders)?;
+    let token = nt.token.unwrap_or_else(|| rd_string(TOKEN_LENGTH));
+    let authed = maybe_refresh_folders(&nt.path, &w_id, authed, &db).await;
+    let mut tx = user_db.begin(&authed).await?;
+
+    sqlx::query!(
+        "INSERT INTO email_trigger (workspace_id, path, is_flow, allowed_senders, token, enabled, \
+         edited_by, email) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        w_id,
+        nt.path,
+        nt.is_flow,
+        &nt.allowed_senders,
+        &token,
+        nt.enabled.unwrap_or(true),
+        &authed.username,
+        &authed.email,
+    )
+    .execute(&mut tx)
+    .await
+    .map_err(|e| Error::BadRequest(format!("creating email trigger {}: {e}", nt.path)))?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "email_triggers.create",
+        ActionKind::Create,
+        &w_id,
+        Some(&nt.path),
+        None,
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(Json(CreatedEmailTrigger { path: nt.path, token }))
+}
+
+/* the trigger runs with the permissions of its last editor, the policies of the table only
+ * letting those it is shared with for writing update or delete it */
+async fn update_email_trigger(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+    Json(et): Json<EditEmailTrigger>,
+) -> Result<String> {
+    let path = path.to_path();
+    check_email_trigger(path, et.token.as_deref(), &et.allowed_senders)?;
+    let authed = maybe_refresh_folders(path, &w_id, authed, &db).await;
+    let mut tx = user_db.begin(&authed).await?;
+
+    let updated = sqlx::query_scalar!(
+        "UPDATE email_trigger SET is_flow = $1, allowed_senders = $2, token = COALESCE($3, \
+         token), enabled = COALESCE($4, enabled), edited_by = $5, email = $6, edited_at = now() \
+         WHERE workspace_id = $7 AND path = $8 RETURNING path",
+        et.is_flow,
+        &et.allowed_senders,
+        et.token,
+        et.enabled,
+        &authed.username,
+        &authed.email,
+        w_id,
+        path
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    not_found_if_none(updated, "Email trigger", path)?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "email_triggers.update",
+        ActionKind::Update,
+        &w_id,
+        Some(path),
+        None,
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(path.to_string())
+}
+
+async fn delete_email_trigger(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+) -> Result<String> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+
+    let deleted = sqlx::query_scalar!(
+        "DELETE FROM email_trigger WHERE workspace_id = $1 AND path = $2 RETURNING path",
+        w_id,
+        path
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    not_found_if_none(deleted, "Email trigger", path)?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "email_triggers.delete",
+        ActionKind::Delete,
+        &w_id,
+        Some(path),
+        None,
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(format!("email trigger {path} deleted"))
+}
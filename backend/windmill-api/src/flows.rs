@@ -47,6 +47,9 @@ pub fn workspaced_service() -> Router {
         .route("/get/draft/*path", get(get_flow_by_path_w_draft))
         .route("/exists/*path", get(exists_flow_by_path))
         .route("/list_paths", get(list_paths))
+        .route("/history/p/*path", get(list_flow_versions))
+        .route("/get/v/:version", get(get_flow_version))
+        .route("/restore/v/:version", post(restore_flow_version))
 }
 
 pub fn global_service() -> Router {
@@ -184,7 +187,7 @@ async fn check_path_conflict<'c>(
     return Ok(());
 }
 
-async fn create_flow(
+pub(crate) async fn create_flow(
     authed: Authed,
     Extension(db): Extension<DB>,
     Extension(user_db): Extension<UserDB>,
@@ -201,6 +204,7 @@ async fn create_flow(
     check_path_conflict(tx.transaction_mut(), &w_id, &nf.path).await?;
     check_schedule_conflict(tx.transaction_mut(), &w_id, &nf.path).await?;
 
+    let schema = nf.schema.and_then(|x| serde_json::to_string(&x.0).ok());
     sqlx::query!(
         "INSERT INTO flow (workspace_id, path, summary, description, value, edited_by, edited_at, \
          schema, dependency_job, draft_only) VALUES ($1, $2, $3, $4, $5, $6, now(), $7::text::json, NULL, $8)",
@@ -210,11 +214,20 @@ async fn create_flow(
         nf.description,
         nf.value,
         &authed.username,
-        nf.schema.and_then(|x| serde_json::to_string(&x.0).ok()),
+        schema.as_deref(),
         nf.draft_only
     )
     .execute(&mut tx)
     .await?;
+    create_flow_version(
+        tx.transaction_mut(),
+        &w_id,
+        &nf.path,
+        &nf.value,
+        schema.as_deref(),
+        &authed.username,
+    )
+    .await?;
 
     sqlx::query!(
         "DELETE FROM draft WHERE path = $1 AND workspace_id = $2 AND typ = 'flow'",
@@ -263,6 +276,9 @@ async fn create_flow(
         None,
         true,
         None,
+        None,
+        None,
+        None,
     )
     .await?;
 
@@ -302,7 +318,7 @@ async fn check_schedule_conflict<'c>(
     Ok(())
 }
 
-async fn update_flow(
+pub(crate) async fn update_flow(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Extension(rsmq): Extension<Option<rsmq_async::MultiplexedRsmq>>,
@@ -318,7 +334,7 @@ async fn update_flow(
 
     check_schedule_conflict(tx.transaction_mut(), &w_id, flow_path).await?;
 
-    let schema = nf.schema.map(|x| x.0);
+    let schema = nf.schema.and_then(|x| serde_json::to_string(&x.0).ok());
     let old_dep_job = sqlx::query_scalar!(
         "SELECT dependency_job FROM flow WHERE path = $1 AND workspace_id = $2",
         flow_path,
@@ -335,12 +351,21 @@ async fn update_flow(
         nf.description,
         nf.value,
         &authed.username,
-        schema.and_then(|x| serde_json::to_string(&x).ok()),
+        schema.as_deref(),
         flow_path,
         w_id,
     )
     .execute(&mut tx)
     .await?;
+    create_flow_version(
+        tx.transaction_mut(),
+        &w_id,
+        &nf.path,
+        &nf.value,
+        schema.as_deref(),
+        &authed.username,
+    )
+    .await?;
 
     if nf.path != flow_path {
         check_schedule_conflict(tx.transaction_mut(), &w_id, &nf.path).await?;
@@ -352,7 +377,10 @@ async fn update_flow(
 
     let mut schedulables: Vec<Schedule> = sqlx::query_as!(
         Schedule,
-            "UPDATE schedule SET script_path = $1 WHERE script_path = $2 AND path != $2 AND workspace_id = $3 AND is_flow IS true RETURNING *",
+            r#"UPDATE schedule SET script_path = $1 WHERE script_path = $2 AND path != $2 AND workspace_id = $3 AND is_flow IS true
+            RETURNING workspace_id, path, edited_by, edited_at, schedule, timezone, enabled, script_path, is_flow, args,
+            extra_perms, email, error, on_failure, on_recovery, on_success, failure_threshold, failure_count, last_failure,
+            retry, retry_attempts, priority, catch_up as "catch_up: _", catch_up_limit, last_tick, paused_until, run_at"#,
             nf.path,
             flow_path,
             w_id,
@@ -361,7 +389,10 @@ async fn update_flow(
         .await?;
 
     let schedule = sqlx::query_as!(Schedule,
-        "UPDATE schedule SET path = $1, script_path = $1 WHERE path = $2 AND workspace_id = $3 AND is_flow IS true RETURNING *",
+        r#"UPDATE schedule SET path = $1, script_path = $1 WHERE path = $2 AND workspace_id = $3 AND is_flow IS true
+        RETURNING workspace_id, path, edited_by, edited_at, schedule, timezone, enabled, script_path, is_flow, args,
+        extra_perms, email, error, on_failure, on_recovery, on_success, failure_threshold, failure_count, last_failure,
+        retry, retry_attempts, priority, catch_up as "catch_up: _", catch_up_limit, last_tick, paused_until, run_at"#,
         nf.path,
         flow_path,
         w_id,
@@ -432,6 +463,9 @@ async fn update_flow(
         None,
         true,
         None,
+        None,
+        None,
+        None,
     )
     .await?;
     sqlx::query!(
@@ -454,6 +488,187 @@ async fn update_flow(
     Ok(nf.path.to_string())
 }
 
+/*
+ * Every deployment of a flow is kept as an immutable version, the latest one being the one in
+ * `flow`. Jobs of a flow keep the version they were pushed with, stored as their flow_version.
+ */
+async fn create_flow_version<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+    value: &serde_json::Value,
+    schema: Option<&str>,
+    username: &str,
+) -> Result<i64> {
+    let version = sqlx::query_scalar!(
+        "INSERT INTO flow_version (workspace_id, path, value, schema, created_by)
+            VALUES ($1, $2, $3, $4::text::json, $5) RETURNING id",
+        w_id,
+        path,
+        value,
+        schema,
+        username,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE flow SET versions = array_append(versions, $1) WHERE path = $2 AND workspace_id = $3",
+        version,
+        path,
+        w_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(version)
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct ListableFlowVersion {
+    pub id: i64,
+    pub created_by: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct FlowVersion {
+    pub id: i64,
+    pub path: String,
+    pub value: serde_json::Value,
+    pub schema: Option<Schema>,
+    pub created_by: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+async fn list_flow_versions(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+    Query(pagination): Query<Pagination>,
+) -> JsonResult<Vec<ListableFlowVersion>> {
+    let path = path.to_path();
+    let (per_page, offset) = paginate(pagination);
+    let mut tx = user_db.begin(&authed).await?;
+
+    /* the join on flow restricts the versions to the flows visible to the user */
+    let versions = sqlx::query_as!(
+        ListableFlowVersion,
+        "SELECT flow_version.id, flow_version.created_by, flow_version.created_at
+            FROM flow_version JOIN flow
+            ON flow.path = flow_version.path AND flow.workspace_id = flow_version.workspace_id
+            WHERE flow_version.path = $1 AND flow_version.workspace_id = $2
+            ORDER BY flow_version.id DESC LIMIT $3 OFFSET $4",
+        path,
+        w_id,
+        per_page as i64,
+        offset as i64,
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(versions))
+}
+
+async fn get_flow_version_internal<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    version: i64,
+) -> Result<FlowVersion> {
+    let version_o = sqlx::query_as!(
+        FlowVersion,
+        r#"SELECT flow_version.id, flow_version.path, flow_version.value, flow_version.schema as "schema: _",
+            flow_version.created_by, flow_version.created_at
+            FROM flow_version JOIN flow
+            ON flow.path = flow_version.path AND flow.workspace_id = flow_version.workspace_id
+            WHERE flow_version.id = $1 AND flow_version.workspace_id = $2"#,
+        version,
+        w_id,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    not_found_if_none(version_o, "Flow version", version.to_string())
+}
+
+async fn get_flow_version(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, version)): Path<(String, i64)>,
+) -> JsonResult<FlowVersion> {
+    let mut tx = user_db.begin(&authed).await?;
+    let version = get_flow_version_internal(&mut tx, &w_id, version).await?;
+    tx.commit().await?;
+
+    Ok(Json(version))
+}
+
+/* deploys a copy of an older version as the latest one, the locks of the older version included */
+async fn restore_flow_version(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Extension(webhook): Extension<WebhookShared>,
+    Path((w_id, version)): Path<(String, i64)>,
+) -> Result<String> {
+    let mut tx = user_db.begin(&authed).await?;
+    let restored = get_flow_version_internal(&mut tx, &w_id, version).await?;
+    let schema = restored
+        .schema
+        .and_then(|x| serde_json::to_string(&x.0).ok());
+
+    let path_o = sqlx::query_scalar!(
+        "UPDATE flow SET value = $1, schema = $2::text::json, edited_by = $3, edited_at = now()
+            WHERE path = $4 AND workspace_id = $5 RETURNING path",
+        restored.value,
+        schema.as_deref(),
+        &authed.username,
+        &restored.path,
+        &w_id,
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    not_found_if_none(path_o, "Flow", &restored.path)?;
+    let new_version = create_flow_version(
+        &mut tx,
+        &w_id,
+        &restored.path,
+        &restored.value,
+        schema.as_deref(),
+        &authed.username,
+    )
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "flows.restore",
+        ActionKind::Update,
+        &w_id,
+        Some(&restored.path),
+        Some(
+            [
+                ("restored_version", version.to_string().as_str()),
+                ("version", new_version.to_string().as_str()),
+            ]
+            .into(),
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+
+    webhook.send_message(
+        w_id.clone(),
+        WebhookMessage::UpdateFlow {
+            workspace: w_id.clone(),
+            old_path: restored.path.clone(),
+            new_path: restored.path.clone(),
+        },
+    );
+
+    Ok(format!(
+        "restored version {version} of flow {} as version {new_version}",
+        restored.path
+    ))
+}
+
 async fn get_flow_by_path(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
@@ -652,6 +867,10 @@ mod tests {
                     suspend: Default::default(),
                     retry: None,
                     sleep: None,
+                    priority: None,
+                    timeout: None,
+                    cache_ttl: None,
+                    on_error: None,
                 },
                 FlowModule {
                     id: "b".to_string(),
@@ -671,6 +890,10 @@ mod tests {
                     suspend: Default::default(),
                     retry: None,
                     sleep: None,
+                    priority: None,
+                    timeout: None,
+                    cache_ttl: None,
+                    on_error: None,
                 },
                 FlowModule {
                     id: "c".to_string(),
@@ -688,6 +911,10 @@ mod tests {
                     suspend: Default::default(),
                     retry: None,
                     sleep: None,
+                    priority: None,
+                    timeout: None,
+                    cache_ttl: None,
+                    on_error: None,
                 },
             ],
             failure_module: Some(FlowModule {
@@ -705,8 +932,14 @@ mod tests {
                 suspend: Default::default(),
                 retry: None,
                 sleep: None,
+                priority: None,
+                timeout: None,
+                cache_ttl: None,
+                on_error: None,
             }),
             same_worker: false,
+            concurrency_limit: None,
+            early_return: None,
         };
         let expect = serde_json::json!({
           "modules": [
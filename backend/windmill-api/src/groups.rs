@@ -294,7 +294,8 @@ async fn delete_group(
 
     if name == "all" {
         return Err(Error::BadRequest(
-            "The group 'all' is a special group that contains all users and cannot be deleted".to_string(),
+            "The group 'all' is a special group that contains all users and cannot be deleted"
+                .to_string(),
         ));
     }
 
@@ -0,0 +1,74 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2023
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+use std::time::Duration;
+
+use sqlx::postgres::PgListener;
+use tokio::{select, sync::broadcast};
+use windmill_common::jobs::{JobUpdateNotification, JOB_UPDATES_CHANNEL};
+
+use crate::db::DB;
+
+const JOB_UPDATES_CAPACITY: usize = 1024;
+
+/* A single LISTEN connection per server, fanned out to every job stream */
+#[derive(Clone)]
+pub struct JobUpdatesShared {
+    pub channel: broadcast::Sender<JobUpdateNotification>,
+}
+
+impl JobUpdatesShared {
+    pub fn new(mut shutdown_rx: broadcast::Receiver<()>, db: DB) -> Self {
+        let (tx, _) = broadcast::channel::<JobUpdateNotification>(JOB_UPDATES_CAPACITY);
+        let sender = tx.clone();
+        let _process = tokio::spawn(async move {
+            let mut listener = loop {
+                match listen_for_job_updates(&db).await {
+                    Ok(listener) => break listener,
+                    Err(e) => {
+                        tracing::error!("Could not listen for job updates, retrying: {e}");
+                        select! {
+                            _ = shutdown_rx.recv() => return,
+                            _ = tokio::time::sleep(Duration::from_secs(5)) => (),
+                        }
+                    }
+                }
+            };
+            loop {
+                select! {
+                    _ = shutdown_rx.recv() => break,
+                    notification = listener.recv() => match notification {
+                        Ok(notification) => {
+                            match serde_json::from_str::<JobUpdateNotification>(notification.payload()) {
+                                // no receivers just means that no job is being streamed
+                                Ok(update) => { let _ = sender.send(update); },
+                                Err(e) => tracing::error!("Invalid job update notification: {e}"),
+                            }
+                        },
+                        Err(e) => {
+                            // the listener reconnects on the next recv
+                            tracing::error!("Error receiving job updates: {e}");
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                        }
+                    }
+                }
+            }
+        });
+        Self { channel: tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<JobUpdateNotification> {
+        self.channel.subscribe()
+    }
+}
+
+async fn listen_for_job_updates(db: &DB) -> Result<PgListener, sqlx::Error> {
+    let mut listener = PgListener::connect_with(db).await?;
+    listener.listen(JOB_UPDATES_CHANNEL).await?;
+    Ok(listener)
+}
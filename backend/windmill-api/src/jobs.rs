@@ -8,6 +8,7 @@
 
 use crate::{
     db::{UserDB, DB},
+    job_updates::JobUpdatesShared,
     users::{check_scopes, require_owner_of_path, Authed, OptAuthed},
     variables::get_workspace_key,
     BASE_URL,
@@ -15,25 +16,34 @@ use crate::{
 use anyhow::Context;
 use axum::{
     extract::{FromRequest, Json, Path, Query},
-    response::{IntoResponse, Response},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Extension, Form, RequestExt, Router,
 };
 use base64::Engine;
 use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt};
 use hmac::Mac;
 use hyper::{header::CONTENT_TYPE, http, HeaderMap, Request, StatusCode};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sql_builder::{prelude::*, quote, SqlBuilder};
 use sqlx::{query_scalar, types::Uuid, FromRow, Postgres, Transaction};
+use std::convert::Infallible;
+use tokio::sync::broadcast;
 use urlencoding::encode;
 use windmill_audit::{audit_log, ActionKind};
 use windmill_common::{
     error::{self, to_anyhow, Error},
     flow_status::{Approval, FlowStatus, FlowStatusModule},
     flows::FlowValue,
-    jobs::{script_path_to_payload, JobKind, JobPayload, QueuedJob, RawCode},
+    jobs::{
+        script_path_to_payload, JobKind, JobPayload, JobUpdateNotification, QueuedJob, RawCode,
+    },
     oauth2::HmacSha256,
+    result_store::{delete_stored_result, resolve_optional_result, resolve_result, result_ref},
     scripts::{ScriptHash, ScriptLang},
     users::username_to_permissioned_as,
     utils::{not_found_if_none, now_from_db, paginate, require_admin, Pagination, StripPath},
@@ -119,6 +129,7 @@ pub fn global_service() -> Router {
             get(get_completed_job_result_maybe),
         )
         .route("/getupdate/:id", get(get_job_update))
+        .route("/getupdate_sse/:id", get(get_job_update_sse))
         .route("/queue/cancel/:id", post(cancel_job_api))
         .route("/queue/force_cancel/:id", post(force_cancel))
 }
@@ -344,6 +355,7 @@ pub struct CompletedJob {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mem_peak: Option<i32>,
     pub tag: String,
+    pub cache_hit: bool,
 }
 
 #[derive(Deserialize, Clone)]
@@ -356,6 +368,8 @@ pub struct RunJobQuery {
     queue_limit: Option<i64>,
     payload: Option<String>,
     job_id: Option<Uuid>,
+    priority: Option<i16>,
+    timeout: Option<i32>,
 }
 
 lazy_static::lazy_static! {
@@ -430,6 +444,7 @@ pub struct ListQueueQuery {
     // filter by matching a subset of the args using base64 encoded json subset
     pub args: Option<String>,
     pub tag: Option<String>,
+    pub priority: Option<i16>,
 }
 
 fn list_queue_jobs_query(w_id: &str, lq: &ListQueueQuery, fields: &[&str]) -> SqlBuilder {
@@ -455,6 +470,9 @@ fn list_queue_jobs_query(w_id: &str, lq: &ListQueueQuery, fields: &[&str]) -> Sq
     if let Some(t) = &lq.tag {
         sqlb.and_where_eq("tag", "?".bind(t));
     }
+    if let Some(p) = &lq.priority {
+        sqlb.and_where_eq("priority", p);
+    }
     if let Some(r) = &lq.running {
         sqlb.and_where_eq("running", &r);
     }
@@ -506,8 +524,23 @@ struct ListableQueuedJob {
     pub language: Option<ScriptLang>,
     pub email: String,
     pub suspend: Option<i32>,
+    pub priority: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency_key: Option<String>,
+    pub waiting_for_concurrency: bool,
 }
 
+/* A queued job is waiting for a concurrency slot when the jobs that hold the slots of its
+ * concurrency key, as counted by the workers when pulling, already reach its limit */
+const WAITING_FOR_CONCURRENCY_FIELD: &str = "(running = false AND concurrency_key IS NOT NULL \
+     AND (SELECT COUNT(*) FROM queue q WHERE q.concurrency_key = queue.concurrency_key \
+            AND q.running = true AND (queue.concurrency_time_window_s IS NULL \
+            OR q.started_at > now() - make_interval(secs => queue.concurrency_time_window_s))) \
+        + (SELECT COUNT(*) FROM completed_job c WHERE queue.concurrency_time_window_s IS NOT NULL \
+            AND c.concurrency_key = queue.concurrency_key \
+            AND c.started_at > now() - make_interval(secs => queue.concurrency_time_window_s)) \
+        >= queue.concurrent_limit) AS waiting_for_concurrency";
+
 async fn list_queue_jobs(
     Extension(db): Extension<DB>,
     Path(w_id): Path<String>,
@@ -533,6 +566,9 @@ async fn list_queue_jobs(
             "same_worker",
             "email",
             "suspend",
+            "priority",
+            "concurrency_key",
+            WAITING_FOR_CONCURRENCY_FIELD,
         ],
     )
     .sql()?;
@@ -589,6 +625,7 @@ async fn list_jobs(
             suspended: lq.suspended,
             args: lq.args,
             tag: lq.tag,
+            priority: None,
         },
         &[
             "'QueuedJob' as typ",
@@ -619,6 +656,7 @@ async fn list_jobs(
             "suspend",
             "mem_peak",
             "tag",
+            "false as cache_hit",
         ],
     );
     let sqlc = list_completed_jobs_query(
@@ -655,6 +693,7 @@ async fn list_jobs(
             "null as suspend",
             "mem_peak",
             "tag",
+            "cache_hit",
         ],
     );
     let sql = format!(
@@ -1121,6 +1160,7 @@ struct UnifiedJob {
     suspend: Option<i32>,
     mem_peak: Option<i32>,
     tag: String,
+    cache_hit: bool,
 }
 
 impl From<UnifiedJob> for Job {
@@ -1157,6 +1197,7 @@ impl From<UnifiedJob> for Job {
                 visible_to_owner: uj.visible_to_owner,
                 mem_peak: uj.mem_peak,
                 tag: uj.tag,
+                cache_hit: uj.cache_hit,
             }),
             "QueuedJob" => Job::QueuedJob(QueuedJob {
                 workspace_id: uj.workspace_id,
@@ -1193,6 +1234,15 @@ impl From<UnifiedJob> for Job {
                 root_job: None,
                 leaf_jobs: None,
                 tag: uj.tag,
+                priority: None,
+                concurrency_key: None,
+                concurrent_limit: None,
+                concurrency_time_window_s: None,
+                timeout: None,
+                cache_ttl: None,
+                memory_limit_mb: None,
+                cpu_limit_millis: None,
+                flow_version: None,
             }),
             t => panic!("job type {} not valid", t),
         }
@@ -1382,6 +1432,9 @@ pub async fn run_flow_by_path(
         None,
         !run_query.invisible_to_owner.unwrap_or(false),
         None,
+        run_query.priority,
+        run_query.timeout,
+        None,
     )
     .await?;
     tx.commit().await?;
@@ -1425,6 +1478,9 @@ pub async fn run_job_by_path(
         None,
         !run_query.invisible_to_owner.unwrap_or(false),
         tag,
+        run_query.priority,
+        run_query.timeout,
+        None,
     )
     .await?;
     tx.commit().await?;
@@ -1472,6 +1528,7 @@ async fn run_wait_result<T>(
     Extension(user_db): Extension<UserDB>,
     timeout: i32,
     uuid: Uuid,
+    early_return: Option<String>,
     Path((w_id, _)): Path<(String, T)>,
 ) -> error::JsonResult<serde_json::Value> {
     let mut result;
@@ -1493,14 +1550,21 @@ async fn run_wait_result<T>(
     let mut accumulated_delay = 0 as u64;
     loop {
         let mut tx = user_db.clone().begin(&authed).await?;
-        result = sqlx::query_scalar!(
-            "SELECT result FROM completed_job WHERE id = $1 AND workspace_id = $2",
-            uuid,
-            &w_id
-        )
-        .fetch_optional(&mut tx)
-        .await?
-        .flatten();
+        result = if let Some(early_return) = early_return.as_ref() {
+            get_early_return_result(&mut tx, &w_id, uuid, early_return).await?
+        } else {
+            None
+        };
+        if result.is_none() {
+            result = sqlx::query_scalar!(
+                "SELECT result FROM completed_job WHERE id = $1 AND workspace_id = $2",
+                uuid,
+                &w_id
+            )
+            .fetch_optional(&mut tx)
+            .await?
+            .flatten();
+        }
         drop(tx);
 
         if result.is_some() {
@@ -1520,6 +1584,7 @@ async fn run_wait_result<T>(
     }
     if let Some(result) = result {
         g.done = true;
+        let result = resolve_result(result).await?;
         let status_code = result
             .get("windmill_status_code")
             .and_then(|x| x.as_i64())
@@ -1533,6 +1598,33 @@ async fn run_wait_result<T>(
     }
 }
 
+/* the result of the early return module of a flow, available as soon as the module succeeded,
+ * whether the flow is still running or not */
+async fn get_early_return_result(
+    tx: &mut Transaction<'_, Postgres>,
+    w_id: &str,
+    flow_id: Uuid,
+    early_return: &str,
+) -> error::Result<Option<serde_json::Value>> {
+    let result = sqlx::query_scalar!(
+        "SELECT cj.result
+         FROM (SELECT flow_status FROM queue WHERE id = $1 AND workspace_id = $2
+               UNION ALL
+               SELECT flow_status FROM completed_job WHERE id = $1 AND workspace_id = $2) f
+            , jsonb_array_elements(f.flow_status->'modules') m
+         JOIN completed_job cj ON cj.id = (m->>'job')::uuid
+         WHERE m->>'id' = $3 AND m->>'type' = 'Success'
+         LIMIT 1",
+        flow_id,
+        w_id,
+        early_return
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .flatten();
+    Ok(result)
+}
+
 pub async fn check_queue_too_long(db: DB, queue_limit: Option<i64>) -> error::Result<()> {
     if let Some(limit) = queue_limit {
         let count = sqlx::query_scalar!(
@@ -1625,6 +1717,9 @@ pub async fn run_wait_result_job_by_path_get(
         None,
         !run_query.invisible_to_owner.unwrap_or(false),
         tag,
+        run_query.priority,
+        run_query.timeout,
+        None,
     )
     .await?;
     tx.commit().await?;
@@ -1634,6 +1729,7 @@ pub async fn run_wait_result_job_by_path_get(
         Extension(user_db),
         *TIMEOUT_WAIT_RESULT,
         uuid,
+        None,
         Path((w_id, script_path)),
     )
     .await
@@ -1678,6 +1774,9 @@ pub async fn run_wait_result_job_by_path(
         None,
         !run_query.invisible_to_owner.unwrap_or(false),
         tag,
+        run_query.priority,
+        run_query.timeout,
+        None,
     )
     .await?;
     tx.commit().await?;
@@ -1687,6 +1786,7 @@ pub async fn run_wait_result_job_by_path(
         Extension(user_db),
         *TIMEOUT_WAIT_RESULT,
         uuid,
+        None,
         Path((w_id, script_path)),
     )
     .await
@@ -1730,6 +1830,9 @@ pub async fn run_wait_result_job_by_hash(
         None,
         !run_query.invisible_to_owner.unwrap_or(false),
         tag,
+        run_query.priority,
+        run_query.timeout,
+        None,
     )
     .await?;
     tx.commit().await?;
@@ -1739,6 +1842,7 @@ pub async fn run_wait_result_job_by_hash(
         Extension(user_db),
         *TIMEOUT_WAIT_RESULT,
         uuid,
+        None,
         Path((w_id, script_hash)),
     )
     .await
@@ -1764,6 +1868,15 @@ pub async fn run_wait_result_flow_by_path(
     let args = run_query.add_include_headers(headers, args.unwrap_or_default());
     let args = add_raw_string(raw_string, args);
 
+    let early_return = sqlx::query_scalar!(
+        "SELECT value->>'early_return' FROM flow WHERE path = $1 AND workspace_id = $2",
+        flow_path,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .flatten();
+
     let (uuid, tx) = push(
         tx,
         &w_id,
@@ -1782,6 +1895,9 @@ pub async fn run_wait_result_flow_by_path(
         None,
         !run_query.invisible_to_owner.unwrap_or(false),
         None,
+        run_query.priority,
+        run_query.timeout,
+        None,
     )
     .await?;
     tx.commit().await?;
@@ -1791,6 +1907,7 @@ pub async fn run_wait_result_flow_by_path(
         Extension(user_db),
         *TIMEOUT_WAIT_RESULT,
         uuid,
+        early_return,
         Path((w_id, flow_path)),
     )
     .await
@@ -1833,6 +1950,9 @@ async fn run_preview_job(
         None,
         true,
         preview.tag,
+        run_query.priority,
+        run_query.timeout,
+        None,
     )
     .await?;
     tx.commit().await?;
@@ -1872,6 +1992,9 @@ async fn run_preview_flow_job(
         None,
         true,
         None,
+        run_query.priority,
+        run_query.timeout,
+        None,
     )
     .await?;
     tx.commit().await?;
@@ -1915,6 +2038,9 @@ pub async fn run_job_by_hash(
         None,
         !run_query.invisible_to_owner.unwrap_or(false),
         tag,
+        run_query.priority,
+        run_query.timeout,
+        None,
     )
     .await?;
     tx.commit().await?;
@@ -1981,6 +2107,183 @@ async fn get_job_update(
     }
 }
 
+fn sse_event(event: &str, data: serde_json::Value) -> Event {
+    Event::default().event(event).data(data.to_string())
+}
+
+fn logs_event(new_logs: String, log_offset: i32) -> Event {
+    sse_event(
+        "logs",
+        serde_json::json!({ "new_logs": new_logs, "log_offset": log_offset }),
+    )
+}
+
+struct JobUpdateStream {
+    db: DB,
+    rx: broadcast::Receiver<JobUpdateNotification>,
+    w_id: String,
+    id: Uuid,
+    log_offset: i32,
+    completed: bool,
+}
+
+impl JobUpdateStream {
+    /* re-reads the logs past the offset already sent, used when notifications were missed */
+    async fn resync_logs(&mut self) -> error::Result<Option<Event>> {
+        let logs = sqlx::query!(
+            "SELECT substr(logs, $1) as new_logs, char_length(logs) as log_offset FROM queue WHERE workspace_id = $2 AND id = $3",
+            self.log_offset + 1,
+            self.w_id,
+            self.id
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .map(|r| (r.new_logs, r.log_offset));
+        match logs {
+            Some((Some(new_logs), Some(log_offset))) if log_offset > self.log_offset => {
+                self.log_offset = log_offset;
+                Ok(Some(logs_event(new_logs, log_offset)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn next_event(&mut self) -> error::Result<Option<Event>> {
+        loop {
+            let update = match self.rx.recv().await {
+                Ok(update) if update.job_id() == &self.id => update,
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(_)) => match self.resync_logs().await? {
+                    Some(event) => return Ok(Some(event)),
+                    None => continue,
+                },
+                Err(broadcast::error::RecvError::Closed) => return Ok(None),
+            };
+            match update {
+                JobUpdateNotification::Logs { logs, log_offset, .. } => {
+                    if log_offset <= self.log_offset {
+                        continue;
+                    }
+                    let new_chars = (log_offset - self.log_offset) as usize;
+                    let chunk_chars = logs.chars().count();
+                    if new_chars > chunk_chars {
+                        match self.resync_logs().await? {
+                            Some(event) => return Ok(Some(event)),
+                            None => continue,
+                        }
+                    }
+                    let new_logs = logs.chars().skip(chunk_chars - new_chars).collect();
+                    self.log_offset = log_offset;
+                    return Ok(Some(logs_event(new_logs, log_offset)));
+                }
+                JobUpdateNotification::FlowStatus { .. } => {
+                    let flow_status = sqlx::query_scalar!(
+                        "SELECT flow_status FROM queue WHERE workspace_id = $1 AND id = $2",
+                        self.w_id,
+                        self.id
+                    )
+                    .fetch_optional(&self.db)
+                    .await?
+                    .flatten();
+                    if let Some(flow_status) = flow_status {
+                        return Ok(Some(sse_event("flow_status", flow_status)));
+                    }
+                }
+                JobUpdateNotification::Completed { .. } => {
+                    let completed =
+                        get_completed_job_update(&self.db, &self.w_id, self.id, self.log_offset)
+                            .await?;
+                    self.completed = true;
+                    return Ok(completed);
+                }
+            }
+        }
+    }
+}
+
+async fn get_completed_job_update(
+    db: &DB,
+    w_id: &str,
+    id: Uuid,
+    log_offset: i32,
+) -> error::Result<Option<Event>> {
+    let completed = sqlx::query!(
+        "SELECT substr(logs, $1) as new_logs, success, result FROM completed_job WHERE workspace_id = $2 AND id = $3",
+        log_offset + 1,
+        w_id,
+        id
+    )
+    .fetch_optional(db)
+    .await?
+    .map(|r| (r.new_logs, r.success, r.result));
+    match completed {
+        Some((new_logs, success, result)) => {
+            let result = resolve_optional_result(result).await?;
+            Ok(Some(sse_event(
+                "completed",
+                serde_json::json!({ "new_logs": new_logs, "success": success, "result": result }),
+            )))
+        }
+        None => Ok(None),
+    }
+}
+
+/* streams the logs, flow status transitions and final result of a job as server-sent events.
+ * Updates are pushed by the workers through NOTIFY, so an open stream does not poll the db */
+async fn get_job_update_sse(
+    Extension(db): Extension<DB>,
+    Extension(job_updates): Extension<JobUpdatesShared>,
+    Path((w_id, id)): Path<(String, Uuid)>,
+) -> error::Result<Sse<BoxStream<'static, Result<Event, Infallible>>>> {
+    // subscribe before reading the current state so that no update is lost in between
+    let rx = job_updates.subscribe();
+
+    let queued = sqlx::query!(
+        "SELECT logs, flow_status FROM queue WHERE workspace_id = $1 AND id = $2",
+        w_id,
+        id
+    )
+    .fetch_optional(&db)
+    .await?
+    .map(|r| (r.logs, r.flow_status));
+
+    let (initial_events, log_offset, completed) = if let Some((logs, flow_status)) = queued {
+        let logs = logs.unwrap_or_default();
+        let log_offset = logs.chars().count() as i32;
+        let mut events = vec![logs_event(logs, log_offset)];
+        if let Some(flow_status) = flow_status {
+            events.push(sse_event("flow_status", flow_status));
+        }
+        (events, log_offset, false)
+    } else {
+        let completed = get_completed_job_update(&db, &w_id, id, 0).await?;
+        let completed = not_found_if_none(completed, "Job", id.to_string())?;
+        (vec![completed], 0, true)
+    };
+
+    let state = JobUpdateStream { db, rx, w_id, id, log_offset, completed };
+    let updates = stream::unfold(state, |mut state| async move {
+        if state.completed {
+            return None;
+        }
+        match state.next_event().await {
+            Ok(Some(event)) => Some((Ok(event), state)),
+            Ok(None) => None,
+            Err(e) => {
+                tracing::error!(job_id = %state.id, "Error streaming job updates: {e}");
+                None
+            }
+        }
+    });
+
+    Ok(Sse::new(
+        stream::iter(initial_events.into_iter().map(Ok))
+            .chain(updates)
+            .boxed(),
+    )
+    .keep_alive(KeepAlive::default()))
+}
+
 fn list_completed_jobs_query(
     w_id: &str,
     per_page: usize,
@@ -2026,6 +2329,9 @@ fn list_completed_jobs_query(
     if let Some(sk) = &lq.is_skipped {
         sqlb.and_where_eq("is_skipped", sk);
     }
+    if let Some(ch) = &lq.cache_hit {
+        sqlb.and_where_eq("cache_hit", ch);
+    }
     if let Some(fs) = &lq.is_flow_step {
         sqlb.and_where_eq("is_flow_step", fs);
     }
@@ -2066,6 +2372,7 @@ pub struct ListCompletedQuery {
     // filter by matching a subset of the result using base64 encoded json subset
     pub result: Option<String>,
     pub tag: Option<String>,
+    pub cache_hit: Option<bool>,
 }
 
 async fn list_completed_jobs(
@@ -2115,6 +2422,7 @@ async fn list_completed_jobs(
             "visible_to_owner",
             "mem_peak",
             "tag",
+            "cache_hit",
         ],
     )
     .sql()?;
@@ -2154,7 +2462,7 @@ async fn get_completed_job_result(
     .await?;
 
     let result = not_found_if_none(result_o, "Completed Job", id.to_string())?;
-    Ok(Json(result))
+    Ok(Json(resolve_optional_result(result).await?))
 }
 
 #[derive(Serialize)]
@@ -2176,6 +2484,7 @@ async fn get_completed_job_result_maybe(
     .await?;
 
     if let Some(result) = result_o {
+        let result = resolve_optional_result(result).await?;
         Ok(Json(CompletedJobResult { completed: true, result }))
     } else {
         Ok(Json(CompletedJobResult { completed: false, result: None }))
@@ -2192,6 +2501,16 @@ async fn delete_completed_job(
     let mut tx = user_db.begin(&authed).await?;
 
     require_admin(authed.is_admin, &authed.username)?;
+    let stored_result = sqlx::query_scalar!(
+        "SELECT result FROM completed_job WHERE id = $1 AND workspace_id = $2",
+        id,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .flatten()
+    .and_then(|result| result_ref(&result));
+
     let job_o = sqlx::query_as::<_, CompletedJob>(
         "UPDATE completed_job SET logs = '', result = null, deleted = true WHERE id = $1 AND workspace_id = $2 \
          RETURNING *",
@@ -2215,5 +2534,8 @@ async fn delete_completed_job(
     .await?;
 
     tx.commit().await?;
+    if let Some(stored_result) = stored_result {
+        delete_stored_result(&stored_result.key).await?;
+    }
     Ok(Json(job))
 }
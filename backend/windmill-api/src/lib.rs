@@ -9,6 +9,7 @@
 use crate::oauth2::AllClients;
 use crate::{
     db::UserDB,
+    job_updates::JobUpdatesShared,
     oauth2::{build_oauth_clients, SlackVerifier},
     tracing_init::{MyMakeSpan, MyOnResponse},
     users::{Authed, OptAuthed},
@@ -35,18 +36,22 @@ mod audit;
 mod capture;
 mod db;
 mod drafts;
+mod email_triggers;
 mod favorite;
 mod flows;
 mod folders;
 mod granular_acls;
 mod groups;
 mod inputs;
+mod job_updates;
 pub mod jobs;
 mod oauth2;
 mod raw_apps;
 mod resources;
 mod schedule;
 mod scripts;
+mod secret_backend;
+mod smtp;
 mod static_assets;
 mod tracing_init;
 mod users;
@@ -112,11 +117,15 @@ pub async fn run_server(
                 .on_request(()),
         )
         .layer(Extension(db.clone()))
-        .layer(Extension(rsmq))
+        .layer(Extension(rsmq.clone()))
         .layer(Extension(user_db))
         .layer(Extension(auth_cache.clone()))
         .layer(CookieManagerLayer::new())
         .layer(Extension(WebhookShared::new(rx.resubscribe(), db.clone())))
+        .layer(Extension(JobUpdatesShared::new(
+            rx.resubscribe(),
+            db.clone(),
+        )))
         .layer(DefaultBodyLimit::max(*REQUEST_SIZE_LIMIT));
 
     let cors = CorsLayer::new()
@@ -148,6 +157,7 @@ pub async fn run_server(
                         .nest("/schedules", schedule::workspaced_service())
                         .nest("/scripts", scripts::workspaced_service())
                         .nest("/drafts", drafts::workspaced_service())
+                        .nest("/email_triggers", email_triggers::workspaced_service())
                         .nest(
                             "/users",
                             users::workspaced_service().layer(Extension(argon2.clone())),
@@ -194,6 +204,16 @@ pub async fn run_server(
         .fallback(static_assets::static_handler)
         .layer(middleware_stack);
 
+    if let Some(smtp_port) = *smtp::SMTP_TRIGGER_PORT {
+        let smtp_addr = SocketAddr::new(addr.ip(), smtp_port);
+        let smtp_f = smtp::run_smtp_server(db.clone(), rsmq, smtp_addr, rx.resubscribe());
+        tokio::spawn(async move {
+            if let Err(e) = smtp_f.await {
+                tracing::error!("Error running smtp trigger listener: {e}");
+            }
+        });
+    }
+
     let instance_name = rd_string(5);
 
     tracing::info!(addr = %addr.to_string(), instance = %instance_name, "server started listening");
@@ -39,7 +39,7 @@ use crate::webhook_util::{InstanceEvent, WebhookShared};
 use crate::workspaces::invite_user_to_all_auto_invite_worspaces;
 use crate::{
     db::{UserDB, DB},
-    variables::{build_crypt, encrypt},
+    secret_backend::store_secret,
     workspaces::WorkspaceSettings,
 };
 use crate::{BASE_URL, HTTP_CLIENT, IS_SECURE, OAUTH_CLIENTS, SLACK_SIGNING_SECRET};
@@ -482,13 +482,14 @@ async fn refresh_token(
 ) -> error::Result<String> {
     let tx = user_db.begin(&authed).await?;
 
-    _refresh_token(tx, &path, w_id, id).await?;
+    _refresh_token(tx, &authed, &path, w_id, id).await?;
 
     Ok(format!("Token at path {path} refreshed"))
 }
 
 pub async fn _refresh_token<'c>(
     mut tx: Transaction<'c, Postgres>,
+    authed: &Authed,
     path: &str,
     w_id: String,
     id: i32,
@@ -551,12 +552,11 @@ pub async fn _refresh_token<'c>(
     .await?;
 
     let token_str = token.access_token.to_string();
-    let mc = build_crypt(&mut tx, &w_id).await?;
-    let encrypted_token = encrypt(&mc, token_str.as_str());
+    let stored_token = store_secret(&mut tx, authed, &w_id, path, token_str.clone(), false).await?;
 
     sqlx::query!(
         "UPDATE variable SET value = $1 WHERE workspace_id = $2 AND path = $3",
-        encrypted_token,
+        stored_token,
         w_id,
         path
     )
@@ -660,8 +660,15 @@ async fn connect_slack_callback(
     .await?;
 
     let token_path = "f/slack_bot/bot_token";
-    let mc = build_crypt(&mut tx, &w_id).await?;
-    let value = encrypt(&mc, &token.bot.bot_access_token);
+    let value = store_secret(
+        &mut tx,
+        &authed,
+        &w_id,
+        token_path,
+        token.bot.bot_access_token.clone(),
+        false,
+    )
+    .await?;
     sqlx::query!(
         "INSERT INTO variable
             (workspace_id, path, value, is_secret, description, account, is_oauth)
@@ -802,6 +809,9 @@ async fn slack_command(
                 None,
                 true,
                 tag,
+                None,
+                None,
+                None,
             )
             .await?;
             let url = BASE_URL.to_owned();
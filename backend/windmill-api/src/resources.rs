@@ -9,6 +9,7 @@
 use crate::{
     db::{UserDB, DB},
     users::{maybe_refresh_folders, require_owner_of_path, Authed},
+    variables::{rename_versions, RestoreVersion},
     webhook_util::{WebhookMessage, WebhookShared},
 };
 use axum::{
@@ -16,6 +17,7 @@ use axum::{
     routing::{delete, get, post},
     Json, Router,
 };
+use chrono::{DateTime, Utc};
 use hyper::StatusCode;
 use serde::{Deserialize, Serialize};
 use sql_builder::{bind::Bind, SqlBuilder};
@@ -36,6 +38,9 @@ pub fn workspaced_service() -> Router {
         .route("/update_value/*path", post(update_resource_value))
         .route("/delete/*path", delete(delete_resource))
         .route("/create", post(create_resource))
+        .route("/history/*path", get(list_resource_versions))
+        .route("/history_diff/*path", get(diff_resource_version))
+        .route("/restore/*path", post(restore_resource_version))
         .route("/type/list", get(list_resource_types))
         .route("/type/listnames", get(list_resource_types_names))
         .route("/type/get/:name", get(get_resource_type))
@@ -100,10 +105,10 @@ pub struct CreateResource {
     pub resource_type: String,
 }
 #[derive(Deserialize)]
-struct EditResource {
-    path: Option<String>,
-    description: Option<String>,
-    value: Option<serde_json::Value>,
+pub struct EditResource {
+    pub path: Option<String>,
+    pub description: Option<String>,
+    pub value: Option<serde_json::Value>,
 }
 
 #[derive(Deserialize)]
@@ -260,7 +265,7 @@ async fn check_path_conflict<'c>(
     return Ok(());
 }
 
-async fn create_resource(
+pub(crate) async fn create_resource(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Extension(webhook): Extension<WebhookShared>,
@@ -331,6 +336,20 @@ async fn delete_resource(
     )
     .execute(&mut tx)
     .await?;
+    sqlx::query!(
+        "DELETE FROM resource_version WHERE path = $1 AND workspace_id = $2",
+        path,
+        w_id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM variable_version WHERE path = $1 AND workspace_id = $2",
+        path,
+        w_id
+    )
+    .execute(&mut tx)
+    .await?;
     audit_log(
         &mut tx,
         &authed.username,
@@ -351,7 +370,7 @@ async fn delete_resource(
     Ok(format!("resource {} deleted", path))
 }
 
-async fn update_resource(
+pub(crate) async fn update_resource(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Extension(webhook): Extension<WebhookShared>,
@@ -382,6 +401,8 @@ async fn update_resource(
 
     let mut tx = user_db.begin(&authed).await?;
 
+    save_resource_version(&mut tx, &w_id, path, &authed.username).await?;
+
     if let Some(npath) = ns.path {
         if npath != path {
             check_path_conflict(&mut tx, &w_id, &npath).await?;
@@ -396,6 +417,7 @@ async fn update_resource(
             )
             .execute(&mut tx)
             .await?;
+            rename_versions(&mut tx, &w_id, path, &npath).await?;
         }
     }
 
@@ -443,6 +465,7 @@ async fn update_resource_value(
     let path = path.to_path();
     let mut tx = user_db.begin(&authed).await?;
 
+    save_resource_version(&mut tx, &w_id, path, &authed.username).await?;
     sqlx::query!(
         "UPDATE resource SET value = $1 WHERE path = $2 AND workspace_id = $3",
         nv.value,
@@ -474,6 +497,208 @@ async fn update_resource_value(
     Ok(format!("value of resource {} updated", path))
 }
 
+#[derive(FromRow, Serialize)]
+pub struct ResourceVersion {
+    pub id: i64,
+    pub path: String,
+    pub value: Option<serde_json::Value>,
+    pub description: Option<String>,
+    pub replaced_by: String,
+    pub replaced_at: DateTime<Utc>,
+}
+
+/*
+ * Snapshots the resource as it is before being overwritten. Secrets of a resource are held by
+ * the variables it references with `$var:`, which have their own history.
+ */
+async fn save_resource_version<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+    username: &str,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO resource_version (workspace_id, path, value, description, replaced_by)
+            SELECT workspace_id, path, value, description, $3 FROM resource
+            WHERE path = $1 AND workspace_id = $2",
+        path,
+        w_id,
+        username,
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/* the versions are only visible to those who can see the resource */
+async fn require_resource<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+) -> Result<Option<serde_json::Value>> {
+    let resource_o = sqlx::query_scalar!(
+        "SELECT value FROM resource WHERE path = $1 AND workspace_id = $2",
+        path,
+        w_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    not_found_if_none(resource_o, "Resource", path)
+}
+
+async fn list_resource_versions(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+    Query(pagination): Query<Pagination>,
+) -> JsonResult<Vec<ResourceVersion>> {
+    let path = path.to_path();
+    let (per_page, offset) = paginate(pagination);
+    let mut tx = user_db.begin(&authed).await?;
+
+    require_resource(&mut tx, &w_id, path).await?;
+    let versions = sqlx::query_as!(
+        ResourceVersion,
+        "SELECT id, path, value, description, replaced_by, replaced_at
+            FROM resource_version WHERE path = $1 AND workspace_id = $2
+            ORDER BY id DESC LIMIT $3 OFFSET $4",
+        path,
+        w_id,
+        per_page as i64,
+        offset as i64,
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(versions))
+}
+
+#[derive(Deserialize)]
+struct VersionQuery {
+    version: i64,
+}
+
+#[derive(Serialize)]
+struct ResourceDiff {
+    version: i64,
+    previous: Option<serde_json::Value>,
+    current: Option<serde_json::Value>,
+    /* top level keys whose value differ */
+    changed_keys: Vec<String>,
+}
+
+fn changed_keys(
+    previous: &Option<serde_json::Value>,
+    current: &Option<serde_json::Value>,
+) -> Vec<String> {
+    use serde_json::Value;
+    match (previous, current) {
+        (Some(Value::Object(previous)), Some(Value::Object(current))) => {
+            let mut keys: Vec<String> = previous
+                .keys()
+                .chain(current.keys())
+                .filter(|k| previous.get(*k) != current.get(*k))
+                .cloned()
+                .collect();
+            keys.sort();
+            keys.dedup();
+            keys
+        }
+        _ => vec![],
+    }
+}
+
+async fn diff_resource_version(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+    Query(VersionQuery { version }): Query<VersionQuery>,
+) -> JsonResult<ResourceDiff> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+
+    let current = require_resource(&mut tx, &w_id, path).await?;
+    let version_o = sqlx::query_scalar!(
+        "SELECT value FROM resource_version WHERE id = $1 AND path = $2 AND workspace_id = $3",
+        version,
+        path,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let previous = not_found_if_none(version_o, "Resource version", version.to_string())?;
+    tx.commit().await?;
+
+    let changed_keys = changed_keys(&previous, &current);
+    Ok(Json(ResourceDiff {
+        version,
+        previous,
+        current,
+        changed_keys,
+    }))
+}
+
+async fn restore_resource_version(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Extension(webhook): Extension<WebhookShared>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+    Json(RestoreVersion { version }): Json<RestoreVersion>,
+) -> Result<String> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+
+    require_resource(&mut tx, &w_id, path).await?;
+    let version_o = sqlx::query!(
+        "SELECT value, description FROM resource_version
+            WHERE id = $1 AND path = $2 AND workspace_id = $3",
+        version,
+        path,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let restored = not_found_if_none(version_o, "Resource version", version.to_string())?;
+
+    /* the restore is itself undoable */
+    save_resource_version(&mut tx, &w_id, path, &authed.username).await?;
+    let npath_o = sqlx::query_scalar!(
+        "UPDATE resource SET value = $1, description = $2
+            WHERE path = $3 AND workspace_id = $4 RETURNING path",
+        restored.value,
+        restored.description,
+        path,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    not_found_if_none(npath_o, "Resource", path)?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "resources.restore",
+        ActionKind::Update,
+        &w_id,
+        Some(path),
+        Some([("version", version.to_string().as_str())].into()),
+    )
+    .await?;
+    tx.commit().await?;
+
+    webhook.send_message(
+        w_id.clone(),
+        WebhookMessage::UpdateResource {
+            workspace: w_id,
+            old_path: path.to_owned(),
+            new_path: path.to_owned(),
+        },
+    );
+
+    Ok(format!("resource {path} restored to version {version}"))
+}
+
 async fn list_resource_types(
     Extension(db): Extension<DB>,
     Path(w_id): Path<String>,
@@ -542,7 +767,7 @@ async fn exists_resource_type(
     Ok(Json(exists))
 }
 
-async fn create_resource_type(
+pub(crate) async fn create_resource_type(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Extension(webhook): Extension<WebhookShared>,
@@ -645,7 +870,7 @@ async fn delete_resource_type(
     Ok(format!("resource_type {} deleted", name))
 }
 
-async fn update_resource_type(
+pub(crate) async fn update_resource_type(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Extension(webhook): Extension<WebhookShared>,
@@ -22,11 +22,16 @@ use std::str::FromStr;
 use windmill_audit::{audit_log, ActionKind};
 use windmill_common::{
     error::{Error, JsonResult, Result},
+    flows::Retry,
     jobs::JobKind,
-    schedule::Schedule,
+    schedule::{CatchUp, Schedule, MAX_CATCH_UP_TICKS},
     utils::{not_found_if_none, paginate, Pagination, StripPath},
 };
-use windmill_queue::{self, schedule::push_scheduled_job, QueueTransaction};
+use windmill_queue::{
+    self,
+    schedule::{push_schedule_backfill, push_scheduled_job},
+    QueueTransaction,
+};
 
 pub fn workspaced_service() -> Router {
     Router::new()
@@ -37,6 +42,7 @@ pub fn workspaced_service() -> Router {
         .route("/update/*path", post(edit_schedule))
         .route("/delete/*path", delete(delete_schedule))
         .route("/setenabled/*path", post(set_enabled))
+        .route("/backfill/*path", post(backfill_schedule))
 }
 
 pub fn global_service() -> Router {
@@ -53,6 +59,40 @@ pub struct NewSchedule {
     pub args: Option<serde_json::Value>,
     pub enabled: Option<bool>,
     pub on_failure: Option<String>,
+    pub on_recovery: Option<String>,
+    pub on_success: Option<String>,
+    pub failure_threshold: Option<i32>,
+    pub retry: Option<Retry>,
+    pub priority: Option<i16>,
+    pub catch_up: Option<CatchUp>,
+    pub catch_up_limit: Option<i32>,
+    pub run_at: Option<DateTime<Utc>>,
+}
+
+/* the cron expression of one-shot schedules is ignored */
+fn check_schedule(schedule: &str, run_at: Option<DateTime<Utc>>) -> Result<()> {
+    if run_at.is_none() {
+        cron::Schedule::from_str(schedule).map_err(|e| Error::BadRequest(e.to_string()))?;
+    }
+    Ok(())
+}
+
+fn check_catch_up_limit(catch_up_limit: Option<i32>) -> Result<()> {
+    match catch_up_limit {
+        Some(limit) if limit < 1 || limit > MAX_CATCH_UP_TICKS => Err(Error::BadRequest(format!(
+            "catch_up_limit must be between 1 and {MAX_CATCH_UP_TICKS}"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn check_failure_threshold(failure_threshold: Option<i32>) -> Result<()> {
+    match failure_threshold {
+        Some(threshold) if threshold < 1 => Err(Error::BadRequest(
+            "failure_threshold must be at least 1".to_string(),
+        )),
+        _ => Ok(()),
+    }
 }
 
 async fn check_path_conflict<'c>(
@@ -88,7 +128,9 @@ async fn create_schedule(
     let authed = maybe_refresh_folders(&ns.path, &w_id, authed, &db).await;
     let mut tx: QueueTransaction<'_, _> = (rsmq, user_db.begin(&authed).await?).into();
 
-    cron::Schedule::from_str(&ns.schedule).map_err(|e| Error::BadRequest(e.to_string()))?;
+    check_schedule(&ns.schedule, ns.run_at)?;
+    check_catch_up_limit(ns.catch_up_limit)?;
+    check_failure_threshold(ns.failure_threshold)?;
     check_path_conflict(tx.transaction_mut(), &w_id, &ns.path).await?;
     check_flow_conflict(
         tx.transaction_mut(),
@@ -101,8 +143,13 @@ async fn create_schedule(
 
     let schedule = sqlx::query_as!(
         Schedule,
-        "INSERT INTO schedule (workspace_id, path, schedule, timezone, edited_by, script_path, \
-         is_flow, args, enabled, email, on_failure) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) RETURNING *",
+        r#"INSERT INTO schedule (workspace_id, path, schedule, timezone, edited_by, script_path,
+         is_flow, args, enabled, email, on_failure, on_recovery, on_success, failure_threshold,
+         retry, priority, catch_up, catch_up_limit, run_at) VALUES ($1, $2, $3, $4, $5, $6, $7,
+         $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19) RETURNING workspace_id, path, edited_by, edited_at, schedule, timezone, enabled, script_path,
+         is_flow, args, extra_perms, email, error, on_failure, on_recovery, on_success,
+         failure_threshold, failure_count, last_failure, retry, retry_attempts, priority,
+         catch_up as "catch_up: _", catch_up_limit, last_tick, paused_until, run_at"#,
         w_id,
         ns.path,
         ns.schedule,
@@ -113,7 +160,15 @@ async fn create_schedule(
         ns.args,
         ns.enabled.unwrap_or(false),
         &authed.email,
-        ns.on_failure
+        ns.on_failure,
+        ns.on_recovery,
+        ns.on_success,
+        ns.failure_threshold,
+        ns.retry.as_ref().map(|x| serde_json::json!(x)),
+        ns.priority,
+        ns.catch_up.unwrap_or_default(): CatchUp,
+        ns.catch_up_limit,
+        ns.run_at,
     )
     .fetch_one(&mut tx)
     .await
@@ -160,7 +215,9 @@ async fn edit_schedule(
     let mut tx: QueueTransaction<'_, rsmq_async::MultiplexedRsmq> =
         (rsmq, user_db.begin(&authed).await?).into();
 
-    cron::Schedule::from_str(&es.schedule).map_err(|e| Error::BadRequest(e.to_string()))?;
+    check_schedule(&es.schedule, es.run_at)?;
+    check_catch_up_limit(es.catch_up_limit)?;
+    check_failure_threshold(es.failure_threshold)?;
 
     let is_flow = sqlx::query_scalar!(
         "SELECT is_flow FROM schedule WHERE path = $1 AND workspace_id = $2",
@@ -171,14 +228,32 @@ async fn edit_schedule(
     .await?;
 
     clear_schedule(tx.transaction_mut(), path, is_flow).await?;
+    /*
+     * the ticks of a previous cron expression are not caught up, and a one-shot schedule runs
+     * again when given another run_at
+     */
     let schedule = sqlx::query_as!(
         Schedule,
-        "UPDATE schedule SET schedule = $1, timezone = $2, args = $3, on_failure = $4 WHERE path \
-         = $5 AND workspace_id = $6 RETURNING *",
+        r#"UPDATE schedule SET schedule = $1, timezone = $2, args = $3, on_failure = $4,
+         on_recovery = $5, on_success = $6, failure_threshold = $7, retry = $8, priority = $9,
+         catch_up = $10, catch_up_limit = $11, run_at = $12, last_tick = CASE WHEN schedule = $1::varchar
+         AND timezone = $2::varchar AND run_at IS NOT DISTINCT FROM $12::timestamptz THEN last_tick
+         WHEN $12::timestamptz IS NULL THEN now() ELSE NULL END WHERE path = $13 AND workspace_id = $14 RETURNING workspace_id, path, edited_by, edited_at, schedule, timezone, enabled, script_path,
+         is_flow, args, extra_perms, email, error, on_failure, on_recovery, on_success,
+         failure_threshold, failure_count, last_failure, retry, retry_attempts, priority,
+         catch_up as "catch_up: _", catch_up_limit, last_tick, paused_until, run_at"#,
         es.schedule,
         es.timezone,
         es.args,
         es.on_failure,
+        es.on_recovery,
+        es.on_success,
+        es.failure_threshold,
+        es.retry.as_ref().map(|x| serde_json::json!(x)),
+        es.priority,
+        es.catch_up.unwrap_or_default(): CatchUp,
+        es.catch_up_limit,
+        es.run_at,
         path,
         w_id,
     )
@@ -220,7 +295,11 @@ async fn list_schedule(
     let (per_page, offset) = paginate(pagination);
     let rows = sqlx::query_as!(
         Schedule,
-        "SELECT * FROM schedule WHERE workspace_id = $1 ORDER BY edited_at desc LIMIT $2 OFFSET $3",
+        r#"SELECT workspace_id, path, edited_by, edited_at, schedule, timezone, enabled, script_path,
+         is_flow, args, extra_perms, email, error, on_failure, on_recovery, on_success,
+         failure_threshold, failure_count, last_failure, retry, retry_attempts, priority,
+         catch_up as "catch_up: _", catch_up_limit, last_tick, paused_until, run_at
+         FROM schedule WHERE workspace_id = $1 ORDER BY edited_at desc LIMIT $2 OFFSET $3"#,
         w_id,
         per_page as i64,
         offset as i64
@@ -259,19 +338,28 @@ async fn exists_schedule(
 pub struct PreviewPayload {
     pub schedule: String,
     pub timezone: String,
+    pub paused_until: Option<DateTime<Utc>>,
+    pub run_at: Option<DateTime<Utc>>,
 }
 
 pub async fn preview_schedule(
     Json(payload): Json<PreviewPayload>,
 ) -> JsonResult<Vec<DateTime<Utc>>> {
+    if let Some(run_at) = payload.run_at {
+        return Ok(Json(vec![run_at]));
+    }
+
     let schedule = cron::Schedule::from_str(&payload.schedule)
         .map_err(|e| Error::BadRequest(e.to_string()))?;
 
     let tz =
         chrono_tz::Tz::from_str(&payload.timezone).map_err(|e| Error::BadRequest(e.to_string()))?;
 
+    let start = payload
+        .paused_until
+        .map_or_else(Utc::now, |paused_until| paused_until.max(Utc::now()));
     let upcoming: Vec<DateTime<Utc>> = schedule
-        .upcoming(tz)
+        .after(&start.with_timezone(&tz))
         .take(5)
         // Convert back to UTC for a standardised API response. The client will convert to the local timezone.
         .map(|x| x.with_timezone(&Utc))
@@ -290,11 +378,22 @@ pub async fn set_enabled(
     let mut tx: QueueTransaction<'_, rsmq_async::MultiplexedRsmq> =
         (rsmq, user_db.begin(&authed).await?).into();
     let path = path.to_path();
+    if !payload.enabled && payload.paused_until.is_some() {
+        return Err(Error::BadRequest(
+            "only an enabled schedule can be paused".to_string(),
+        ));
+    }
+    /* enabling a schedule with paused_until pauses it, it resumes on its own after it */
     let schedule_o = sqlx::query_as!(
         Schedule,
-        "UPDATE schedule SET enabled = $1, email = $2 WHERE path = $3 AND workspace_id = $4 RETURNING *",
-        &payload.enabled,
-        authed.email,
+        r#"UPDATE schedule SET enabled = $1, email = $2, paused_until = $3 WHERE path = $4 AND
+         workspace_id = $5 RETURNING workspace_id, path, edited_by, edited_at, schedule, timezone, enabled, script_path,
+         is_flow, args, extra_perms, email, error, on_failure, on_recovery, on_success,
+         failure_threshold, failure_count, last_failure, retry, retry_attempts, priority,
+         catch_up as "catch_up: _", catch_up_limit, last_tick, paused_until, run_at"#,
+        payload.enabled,
+        &authed.email,
+        payload.paused_until,
         path,
         w_id
     )
@@ -312,7 +411,18 @@ pub async fn set_enabled(
         ActionKind::Update,
         &w_id,
         Some(path),
-        Some([("enabled", payload.enabled.to_string().as_ref())].into()),
+        Some(
+            [
+                Some(("enabled", payload.enabled.to_string())),
+                payload
+                    .paused_until
+                    .map(|paused_until| ("paused_until", paused_until.to_rfc3339())),
+            ]
+            .iter()
+            .flatten()
+            .map(|(k, v)| (*k, v.as_str()))
+            .collect(),
+        ),
     )
     .await?;
 
@@ -391,6 +501,14 @@ pub struct EditSchedule {
     pub timezone: String,
     pub args: Option<serde_json::Value>,
     pub on_failure: Option<String>,
+    pub on_recovery: Option<String>,
+    pub on_success: Option<String>,
+    pub failure_threshold: Option<i32>,
+    pub retry: Option<Retry>,
+    pub priority: Option<i16>,
+    pub catch_up: Option<CatchUp>,
+    pub catch_up_limit: Option<i32>,
+    pub run_at: Option<DateTime<Utc>>,
 }
 
 pub async fn clear_schedule<'c>(
@@ -413,7 +531,67 @@ pub async fn clear_schedule<'c>(
     Ok(())
 }
 
+#[derive(Deserialize)]
+pub struct Backfill {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub tick_arg: Option<String>,
+}
+
+/* the logical time of each tick is passed as the tick_arg arg, `scheduled_for` by default */
+async fn backfill_schedule(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Extension(rsmq): Extension<Option<rsmq_async::MultiplexedRsmq>>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+    Json(backfill): Json<Backfill>,
+) -> JsonResult<Vec<DateTime<Utc>>> {
+    let path = path.to_path();
+    if backfill.from > backfill.to {
+        return Err(Error::BadRequest(
+            "the start of the backfill range must be before its end".to_string(),
+        ));
+    }
+    let mut tx: QueueTransaction<'_, rsmq_async::MultiplexedRsmq> =
+        (rsmq, user_db.begin(&authed).await?).into();
+
+    let schedule_o =
+        windmill_queue::schedule::get_schedule_opt(tx.transaction_mut(), &w_id, path).await?;
+    let schedule = not_found_if_none(schedule_o, "Schedule", path)?;
+
+    let (ticks, mut tx) = push_schedule_backfill(
+        tx,
+        &schedule,
+        backfill.from,
+        backfill.to,
+        backfill.tick_arg.as_deref().unwrap_or("scheduled_for"),
+    )
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "schedule.backfill",
+        ActionKind::Execute,
+        &w_id,
+        Some(path),
+        Some(
+            [
+                ("from", backfill.from.to_rfc3339().as_str()),
+                ("to", backfill.to.to_rfc3339().as_str()),
+                ("jobs", ticks.len().to_string().as_str()),
+            ]
+            .into(),
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(ticks))
+}
+
 #[derive(Deserialize)]
 pub struct SetEnabled {
     pub enabled: bool,
+    pub paused_until: Option<DateTime<Utc>>,
 }
@@ -35,8 +35,8 @@ use windmill_common::{
     jobs::JobPayload,
     schedule::Schedule,
     scripts::{
-        to_i64, HubScript, ListScriptQuery, ListableScript, NewScript, Schema, Script, ScriptHash,
-        ScriptKind, ScriptLang,
+        to_i64, ConcurrencyLimit, HubScript, ListScriptQuery, ListableScript, NewScript, Schema,
+        Script, ScriptHash, ScriptKind, ScriptLang,
     },
     users::username_to_permissioned_as,
     utils::{
@@ -70,6 +70,16 @@ pub struct ScriptWDraft {
     pub schema: Option<Schema>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub draft_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency_limit: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_ttl: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_limit_mb: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_limit_millis: Option<i32>,
 }
 
 pub fn global_service() -> Router {
@@ -81,6 +91,10 @@ pub fn global_service() -> Router {
         .route("/deno/tojsonschema", post(parse_deno_code_to_jsonschema))
         .route("/go/tojsonschema", post(parse_go_code_to_jsonschema))
         .route("/bash/tojsonschema", post(parse_bash_code_to_jsonschema))
+        .route(
+            "/postgresql/tojsonschema",
+            post(parse_postgresql_code_to_jsonschema),
+        )
         .route("/hub/list", get(list_hub_scripts))
         .route("/hub/get/*path", get(get_hub_script_by_path))
         .route("/hub/get_full/*path", get(get_full_hub_script_by_path))
@@ -108,6 +122,8 @@ pub fn workspaced_service() -> Router {
         .route("/get/h/:hash", get(get_script_by_hash))
         .route("/raw/h/:hash", get(raw_script_by_hash))
         .route("/deployment_status/h/:hash", get(get_deployment_status))
+        .route("/diff/h/:hash/:other_hash", get(diff_script_by_hashes))
+        .route("/restore/h/:hash", post(restore_script_by_hash))
         .route("/list_paths", get(list_paths))
 }
 
@@ -217,7 +233,7 @@ fn hash_script(ns: &NewScript) -> i64 {
     dh.finish() as i64
 }
 
-async fn create_script(
+pub(crate) async fn create_script(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Extension(rsmq): Extension<Option<rsmq_async::MultiplexedRsmq>>,
@@ -227,6 +243,13 @@ async fn create_script(
     Json(ns): Json<NewScript>,
 ) -> Result<(StatusCode, String)> {
     let hash = ScriptHash(hash_script(&ns));
+    if matches!(ns.memory_limit_mb, Some(limit) if limit < 1)
+        || matches!(ns.cpu_limit_millis, Some(limit) if limit < 1)
+    {
+        return Err(Error::BadRequest(
+            "memory_limit_mb and cpu_limit_millis must be at least 1".to_string(),
+        ));
+    }
     let authed = maybe_refresh_folders(&ns.path, &w_id, authed, &db).await;
     let mut tx: QueueTransaction<'_, _> = (rsmq, user_db.begin(&authed).await?).into();
 
@@ -353,7 +376,22 @@ async fn create_script(
         .map(|v| v.perms.clone())
         .unwrap_or(json!({}));
 
-    let lock = if ns.language == ScriptLang::Bash || ns.language == ScriptLang::Deno {
+    /* bash requirements are checked against a worker by a dependency job, and route the script's
+     * jobs to the workers that have them unless it has a tag */
+    let bash_requirements = if ns.language == ScriptLang::Bash {
+        windmill_parser_bash::parse_bash_requirements(&ns.content)?
+    } else {
+        vec![]
+    };
+    let tag = ns
+        .tag
+        .clone()
+        .or_else(|| windmill_parser_bash::bash_requirements_tag(&bash_requirements));
+
+    let lock = if (ns.language == ScriptLang::Bash && bash_requirements.is_empty())
+        || ns.language == ScriptLang::Deno
+        || ns.language == ScriptLang::Postgresql
+    {
         Some(String::new())
     } else {
         ns.lock
@@ -366,8 +404,10 @@ async fn create_script(
     //::text::json is to ensure we use serde_json with preserve order
     sqlx::query!(
         "INSERT INTO script (workspace_id, hash, path, parent_hashes, summary, description, \
-         content, created_by, schema, is_template, extra_perms, lock, language, kind, tag, draft_only) \
-         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9::text::json, $10, $11, $12, $13, $14, $15, $16)",
+         content, created_by, schema, is_template, extra_perms, lock, language, kind, tag, \
+         draft_only, concurrency_limit, timeout, cache_ttl, memory_limit_mb, cpu_limit_millis) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9::text::json, $10, $11, $12, $13, $14, $15, \
+         $16, $17, $18, $19, $20, $21)",
         &w_id,
         &hash.0,
         ns.path,
@@ -376,14 +416,21 @@ async fn create_script(
         ns.description,
         &ns.content,
         &authed.username,
-        ns.schema.and_then(|x| serde_json::to_string(&x.0).ok()),
+        ns.schema
+            .as_ref()
+            .and_then(|x| serde_json::to_string(&x.0).ok()),
         ns.is_template.unwrap_or(false),
         extra_perms,
         lock,
         ns.language: ScriptLang,
         ns.kind.unwrap_or(ScriptKind::Script): ScriptKind,
-        ns.tag,
-        ns.draft_only
+        tag,
+        ns.draft_only,
+        ns.concurrency_limit.as_ref().map(|x| json!(x)),
+        ns.timeout,
+        ns.cache_ttl,
+        ns.memory_limit_mb,
+        ns.cpu_limit_millis,
     )
     .execute(&mut tx)
     .await?;
@@ -399,7 +446,10 @@ async fn create_script(
 
         let schedulables = sqlx::query_as!(
         Schedule,
-            "UPDATE schedule SET script_path = $1 WHERE script_path = $2 AND workspace_id = $3 AND is_flow IS false RETURNING *",
+            r#"UPDATE schedule SET script_path = $1 WHERE script_path = $2 AND workspace_id = $3 AND is_flow IS false
+            RETURNING workspace_id, path, edited_by, edited_at, schedule, timezone, enabled, script_path, is_flow, args,
+            extra_perms, email, error, on_failure, on_recovery, on_success, failure_threshold, failure_count, last_failure,
+            retry, retry_attempts, priority, catch_up as "catch_up: _", catch_up_limit, last_tick, paused_until, run_at"#,
             ns.path,
             p_path,
             w_id,
@@ -495,6 +545,9 @@ async fn create_script(
             None,
             true,
             None,
+            None,
+            None,
+            None,
         )
         .await?;
         tx = new_tx;
@@ -550,7 +603,7 @@ async fn get_script_by_path_w_draft(
     let mut tx = user_db.begin(&authed).await?;
 
     let script_o = sqlx::query_as::<_, ScriptWDraft>(
-        "SELECT hash, script.path, summary, description, content, language, kind, tag, schema, draft_only, draft.value as draft FROM script LEFT JOIN draft ON 
+        "SELECT hash, script.path, summary, description, content, language, kind, tag, schema, draft_only, concurrency_limit, timeout, cache_ttl, memory_limit_mb, cpu_limit_millis, draft.value as draft FROM script LEFT JOIN draft ON 
          script.path = draft.path AND script.workspace_id = draft.workspace_id AND draft.typ = 'script'
          WHERE script.path = $1 AND script.workspace_id = $2 \
          AND script.created_at = (SELECT max(created_at) FROM script WHERE path = $1 AND \
@@ -695,6 +748,190 @@ async fn raw_script_by_hash(
     Ok(r.content)
 }
 
+/* line by line diff, each line prefixed with '-', '+' or ' ', None if there is no difference */
+pub fn diff_lines(before: &str, after: &str) -> Option<String> {
+    if before == after {
+        return None;
+    }
+    let mut diff = String::new();
+    for line in diff::lines(before, after) {
+        let (prefix, line) = match line {
+            diff::Result::Left(l) => ('-', l),
+            diff::Result::Right(r) => ('+', r),
+            diff::Result::Both(l, _) => (' ', l),
+        };
+        diff.push(prefix);
+        diff.push_str(line);
+        diff.push('\n');
+    }
+    Some(diff)
+}
+
+/* names of the arguments whose definition differ, `required` if the required arguments differ */
+fn diff_schemas(before: &Option<Schema>, after: &Option<Schema>) -> Vec<String> {
+    let field = |schema: &Option<Schema>, name: &str| {
+        schema
+            .as_ref()
+            .and_then(|s| s.0.get(name).cloned())
+            .unwrap_or(serde_json::Value::Null)
+    };
+    let (before_props, after_props) = (field(before, "properties"), field(after, "properties"));
+    let empty = serde_json::Map::new();
+    let before_props = before_props.as_object().unwrap_or(&empty);
+    let after_props = after_props.as_object().unwrap_or(&empty);
+
+    let mut changed: Vec<String> = before_props
+        .keys()
+        .chain(after_props.keys())
+        .filter(|k| before_props.get(*k) != after_props.get(*k))
+        .cloned()
+        .collect();
+    changed.sort();
+    changed.dedup();
+    if field(before, "required") != field(after, "required") {
+        changed.push("required".to_string());
+    }
+    changed
+}
+
+#[derive(Serialize)]
+struct ScriptDiff {
+    hash: ScriptHash,
+    other_hash: ScriptHash,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<(String, String)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    schema: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lock: Option<String>,
+}
+
+async fn diff_script_by_hashes(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, hash, other_hash)): Path<(String, ScriptHash, ScriptHash)>,
+) -> JsonResult<ScriptDiff> {
+    let mut tx = user_db.begin(&authed).await?;
+    let script = get_script_by_hash_internal(&mut tx, &w_id, &hash).await?;
+    let other = get_script_by_hash_internal(&mut tx, &w_id, &other_hash).await?;
+    tx.commit().await?;
+
+    Ok(Json(ScriptDiff {
+        hash,
+        other_hash,
+        path: (script.path != other.path).then(|| (script.path, other.path)),
+        content: diff_lines(&script.content, &other.content),
+        schema: diff_schemas(&script.schema, &other.schema),
+        lock: diff_lines(
+            script.lock.as_deref().unwrap_or(""),
+            other.lock.as_deref().unwrap_or(""),
+        ),
+    }))
+}
+
+/*
+ * Deploys the content of an older hash as a new version on top of the current head of its
+ * lineage, found through the parent hashes or else by path, so the history stays linear.
+ */
+async fn restore_script_by_hash(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Extension(rsmq): Extension<Option<rsmq_async::MultiplexedRsmq>>,
+    Extension(webhook): Extension<WebhookShared>,
+    Extension(db): Extension<DB>,
+    Path((w_id, hash)): Path<(String, ScriptHash)>,
+) -> Result<(StatusCode, String)> {
+    let mut tx = user_db.begin(&authed).await?;
+    let script = get_script_by_hash_internal(&mut tx, &w_id, &hash).await?;
+    if script.deleted {
+        return Err(Error::BadRequest(format!(
+            "script {hash} was deleted and cannot be restored"
+        )));
+    }
+    let head = sqlx::query!(
+        "SELECT hash, path FROM script WHERE workspace_id = $1 AND archived = false \
+         AND (hash = $2 OR $2 = ANY(parent_hashes) OR path = $3) \
+         ORDER BY (hash = $2 OR $2 = ANY(parent_hashes)) DESC, created_at DESC LIMIT 1",
+        w_id,
+        hash.0,
+        script.path
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .map(|r| (ScriptHash(r.hash), r.path));
+    tx.commit().await?;
+
+    let (parent_hash, path) = match head {
+        Some((head_hash, _)) if head_hash.0 == hash.0 => {
+            return Err(Error::BadRequest(format!(
+                "script {hash} is already the latest version of {}",
+                script.path
+            )))
+        }
+        Some((head_hash, head_path)) => (Some(head_hash), head_path),
+        None => (None, script.path),
+    };
+    let concurrency_limit = script
+        .concurrency_limit
+        .map(serde_json::from_value::<ConcurrencyLimit>)
+        .transpose()
+        .map_err(|e| Error::InternalErr(format!("invalid concurrency limit of {hash}: {e}")))?;
+
+    let ns = NewScript {
+        path: path.clone(),
+        parent_hash,
+        summary: script.summary,
+        description: script.description,
+        content: script.content,
+        schema: script.schema,
+        is_template: Some(script.is_template),
+        lock: script
+            .lock
+            .map(|l| l.lines().map(|x| x.to_string()).collect()),
+        language: script.language,
+        kind: Some(script.kind),
+        tag: script.tag,
+        draft_only: None,
+        concurrency_limit,
+        timeout: script.timeout,
+        cache_ttl: script.cache_ttl,
+        memory_limit_mb: script.memory_limit_mb,
+        cpu_limit_millis: script.cpu_limit_millis,
+    };
+    let (status, new_hash) = create_script(
+        authed.clone(),
+        Extension(user_db.clone()),
+        Extension(rsmq),
+        Extension(webhook),
+        Extension(db),
+        Path(w_id.clone()),
+        Json(ns),
+    )
+    .await?;
+
+    let mut tx = user_db.begin(&authed).await?;
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "scripts.restore",
+        ActionKind::Update,
+        &w_id,
+        Some(&path),
+        Some(
+            [
+                ("restored_hash", hash.to_string().as_str()),
+                ("hash", new_hash.as_str()),
+            ]
+            .into(),
+        ),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok((status, new_hash))
+}
+
 #[derive(FromRow, Serialize)]
 struct DeploymentStatus {
     lock: Option<String>,
@@ -915,3 +1152,38 @@ async fn parse_go_code_to_jsonschema(Json(code): Json<String>) -> Json<SigParsin
 async fn parse_bash_code_to_jsonschema(Json(code): Json<String>) -> Json<SigParsing> {
     result_to_sig_parsing(windmill_parser_bash::parse_bash_sig(&code))
 }
+
+async fn parse_postgresql_code_to_jsonschema(Json(code): Json<String>) -> Json<SigParsing> {
+    result_to_sig_parsing(windmill_parser_sql::parse_pgsql_sig(&code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_lines() {
+        assert_eq!(diff_lines("a\nb\n", "a\nb\n"), None);
+        assert_eq!(
+            diff_lines("a\nb\nc", "a\nB\nc"),
+            Some(" a\n-b\n+B\n c\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_diff_schemas() {
+        let before = Some(Schema(json!({
+            "properties": { "a": { "type": "string" }, "b": { "type": "number" } },
+            "required": ["a"]
+        })));
+        let after = Some(Schema(json!({
+            "properties": { "a": { "type": "string" }, "c": { "type": "number" } },
+            "required": ["a", "c"]
+        })));
+        assert_eq!(
+            diff_schemas(&before, &after),
+            vec!["b".to_string(), "c".to_string(), "required".to_string()]
+        );
+        assert!(diff_schemas(&before, &before).is_empty());
+    }
+}
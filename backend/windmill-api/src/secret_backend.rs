@@ -0,0 +1,498 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2023
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+/*
+ * Where the values of secret variables live. With the default database backend, they are
+ * encrypted with the workspace key and stored in the variable row. With SECRET_BACKEND=vault,
+ * they are written to the KV v2 secrets engine of a HashiCorp Vault compatible server
+ * (VAULT_ADDR, VAULT_TOKEN, VAULT_KV_MOUNT, VAULT_NAMESPACE) under `windmill/<workspace>/<path>`
+ * and the row only keeps a reference to them: `$vault:<key>`, `$vault:<key>#<field>` or, for the
+ * secrets written by windmill, `$vault:<key>#<field>@<version>` pinned to the KV version written
+ * so that the variable versions keep pointing at the values they replaced. Every version of such a
+ * secret is deleted with its variable, the KV engine must keep enough of them (max_versions) for
+ * the history of the variable to be restorable.
+ *
+ * A secret variable can also be created directly, by an admin, as a reference to a secret managed
+ * outside of windmill under the prefix VAULT_WORKSPACE_PREFIX gives to the workspace, e.g.
+ * `teams/{workspace}/`. A reference to a secret managed by windmill can only be to the secret of
+ * the variable itself. References are resolved when the variable is read, whatever the current
+ * backend is.
+ */
+
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Postgres, Transaction};
+use windmill_common::{
+    error::{to_anyhow, Error, Result},
+    utils::require_admin,
+};
+
+use crate::{
+    users::Authed,
+    variables::{build_crypt, decrypt_secret, encrypt},
+    HTTP_CLIENT,
+};
+
+pub const VAULT_REF_PREFIX: &str = "$vault:";
+const VAULT_MANAGED_PREFIX: &str = "windmill/";
+const DEFAULT_VAULT_FIELD: &str = "value";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SecretBackend {
+    Database,
+    Vault,
+}
+
+#[derive(Debug, Clone)]
+pub struct Vault {
+    pub addr: String,
+    pub token: String,
+    pub mount: String,
+    pub namespace: Option<String>,
+}
+
+lazy_static::lazy_static! {
+    pub static ref SECRET_BACKEND: SecretBackend = match std::env::var("SECRET_BACKEND").as_deref() {
+        Ok("vault") => SecretBackend::Vault,
+        _ => SecretBackend::Database,
+    };
+
+    pub static ref VAULT: Option<Vault> = match (std::env::var("VAULT_ADDR"), std::env::var("VAULT_TOKEN")) {
+        (Ok(addr), Ok(token)) => Some(Vault {
+            addr: addr.trim_end_matches('/').to_string(),
+            token,
+            mount: std::env::var("VAULT_KV_MOUNT").unwrap_or_else(|_| "secret".to_string()),
+            namespace: std::env::var("VAULT_NAMESPACE").ok(),
+        }),
+        _ => None,
+    };
+
+    static ref VAULT_WORKSPACE_PREFIX: Option<String> = std::env::var("VAULT_WORKSPACE_PREFIX").ok();
+}
+
+#[derive(Deserialize)]
+struct VaultKvResponse {
+    data: VaultKvData,
+}
+
+#[derive(Deserialize)]
+struct VaultKvData {
+    data: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct VaultWriteResponse {
+    data: VaultWriteData,
+}
+
+#[derive(Deserialize)]
+struct VaultWriteData {
+    version: u64,
+}
+
+impl Vault {
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let req = HTTP_CLIENT
+            .request(method, format!("{}/v1/{}/{path}", self.addr, self.mount))
+            .header("X-Vault-Token", &self.token);
+        match &self.namespace {
+            Some(namespace) => req.header("X-Vault-Namespace", namespace),
+            None => req,
+        }
+    }
+
+    async fn send(req: RequestBuilder, key: &str) -> Result<reqwest::Response> {
+        let res = req.send().await.map_err(to_anyhow)?;
+        match res.status() {
+            s if s.is_success() => Ok(res),
+            StatusCode::NOT_FOUND => Err(Error::NotFound(format!("secret {key} in vault"))),
+            s => Err(Error::InternalErr(format!(
+                "vault responded with {s} for secret {key}: {}",
+                res.text().await.unwrap_or_default()
+            ))),
+        }
+    }
+
+    /* returns the version written */
+    pub async fn write(&self, key: &str, field: &str, value: &str) -> Result<u64> {
+        let req = self
+            .request(Method::POST, &format!("data/{key}"))
+            .json(&json!({ "data": { field: value } }));
+        let res = Self::send(req, key).await?;
+        let written = res.json::<VaultWriteResponse>().await.map_err(to_anyhow)?;
+        Ok(written.data.version)
+    }
+
+    /* reads the latest version of the secret if none is given */
+    pub async fn read(&self, key: &str, field: &str, version: Option<u64>) -> Result<String> {
+        let mut req = self.request(Method::GET, &format!("data/{key}"));
+        if let Some(version) = version {
+            req = req.query(&[("version", version)]);
+        }
+        let res = Self::send(req, key).await?;
+        let kv = res.json::<VaultKvResponse>().await.map_err(to_anyhow)?;
+        match kv.data.data.get(field) {
+            Some(serde_json::Value::String(s)) => Ok(s.clone()),
+            Some(v) => Ok(v.to_string()),
+            None => Err(Error::NotFound(format!(
+                "field {field} of secret {key} in vault"
+            ))),
+        }
+    }
+
+    /* deletes every version of the secret */
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        let req = self.request(Method::DELETE, &format!("metadata/{key}"));
+        match Self::send(req, key).await {
+            Ok(_) | Err(Error::NotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /* writes a new version of the secret of the variable and returns the reference pinned to it */
+    async fn store(&self, w_id: &str, path: &str, value: &str) -> Result<String> {
+        let key = managed_vault_key(w_id, path);
+        let version = self.write(&key, DEFAULT_VAULT_FIELD, value).await?;
+        Ok(format!(
+            "{VAULT_REF_PREFIX}{key}#{DEFAULT_VAULT_FIELD}@{version}"
+        ))
+    }
+}
+
+fn vault() -> Result<&'static Vault> {
+    VAULT.as_ref().ok_or_else(|| {
+        Error::BadConfig("VAULT_ADDR and VAULT_TOKEN must be set to use vault secrets".to_string())
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VaultRef<'a> {
+    pub key: &'a str,
+    pub field: &'a str,
+    pub version: Option<u64>,
+}
+
+/* `$vault:<key>#<field>@<version>` */
+pub fn vault_ref(value: &str) -> Option<VaultRef<'_>> {
+    let reference = value.strip_prefix(VAULT_REF_PREFIX)?;
+    let (reference, version) = match reference.rsplit_once('@') {
+        Some((reference, version)) if version.parse::<u64>().is_ok() => {
+            (reference, version.parse().ok())
+        }
+        _ => (reference, None),
+    };
+    let (key, field) = reference
+        .split_once('#')
+        .unwrap_or((reference, DEFAULT_VAULT_FIELD));
+    Some(VaultRef { key, field, version })
+}
+
+fn managed_vault_key(w_id: &str, path: &str) -> String {
+    format!("{VAULT_MANAGED_PREFIX}{w_id}/{path}")
+}
+
+/* the prefix of the secrets managed outside of windmill the workspace can reference */
+fn workspace_vault_prefix(template: &str, w_id: &str) -> String {
+    format!(
+        "{}/",
+        template.replace("{workspace}", w_id).trim_end_matches('/')
+    )
+}
+
+fn check_vault_ref(
+    authed: &Authed,
+    w_id: &str,
+    path: &str,
+    key: &str,
+    external_prefix: Option<&str>,
+) -> Result<()> {
+    if key
+        .split('/')
+        .any(|segment| segment == ".." || segment == ".")
+    {
+        return Err(Error::BadRequest(format!("invalid vault secret {key}")));
+    }
+    if key.starts_with(VAULT_MANAGED_PREFIX) {
+        if key != managed_vault_key(w_id, path) {
+            return Err(Error::BadRequest(format!(
+                "vault secret {key} is managed by windmill for another variable"
+            )));
+        }
+        return Ok(());
+    }
+    require_admin(authed.is_admin, &authed.username)?;
+    match external_prefix {
+        Some(prefix) if key.starts_with(prefix) => Ok(()),
+        Some(prefix) => Err(Error::BadRequest(format!(
+            "vault secret {key} is not under {prefix}, the prefix of the workspace"
+        ))),
+        None => Err(Error::BadRequest(
+            "VAULT_WORKSPACE_PREFIX must be set to reference secrets managed outside of windmill"
+                .to_string(),
+        )),
+    }
+}
+
+/* The value to save in the variable row for a secret. References are saved as is */
+pub async fn store_secret<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    authed: &Authed,
+    w_id: &str,
+    path: &str,
+    value: String,
+    already_encrypted: bool,
+) -> Result<String> {
+    if let Some(VaultRef { key, .. }) = vault_ref(&value) {
+        let external_prefix = VAULT_WORKSPACE_PREFIX
+            .as_deref()
+            .map(|template| workspace_vault_prefix(template, w_id));
+        check_vault_ref(authed, w_id, path, key, external_prefix.as_deref())?;
+        return Ok(value);
+    }
+    if already_encrypted {
+        return Ok(value);
+    }
+    match *SECRET_BACKEND {
+        SecretBackend::Database => {
+            let mc = build_crypt(tx, w_id).await?;
+            Ok(encrypt(&mc, &value))
+        }
+        SecretBackend::Vault => vault()?.store(w_id, path, &value).await,
+    }
+}
+
+/* The plain value of a secret from what is saved in its variable row */
+pub async fn resolve_secret<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+    stored: String,
+) -> Result<String> {
+    match vault_ref(&stored) {
+        Some(VaultRef { key, field, version }) => vault()?.read(key, field, version).await,
+        None => decrypt_secret(tx, w_id, path, stored).await,
+    }
+}
+
+/* The secrets windmill wrote to vault among the values of a deleted variable and of its versions,
+ * which may have been written under previous paths of the variable */
+pub fn managed_secrets<'a>(w_id: &str, stored: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let managed_prefix = managed_vault_key(w_id, "");
+    let mut keys = stored
+        .into_iter()
+        .filter_map(vault_ref)
+        .map(|r| r.key)
+        .filter(|key| key.starts_with(&managed_prefix))
+        .map(|key| key.to_string())
+        .collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/* Deletes every version of secrets windmill wrote to vault */
+pub async fn delete_secrets(keys: &[String]) -> Result<()> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let vault = vault()?;
+    for key in keys {
+        vault.delete(key).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        extract::{Path, Query, State},
+        http::StatusCode,
+        routing::get,
+        Json, Router,
+    };
+    use serde_json::Value;
+
+    use super::*;
+
+    /* every version of each secret, the first one being version 1 */
+    type Store = Arc<Mutex<HashMap<String, Vec<Value>>>>;
+
+    #[derive(Deserialize)]
+    struct VersionQuery {
+        version: Option<usize>,
+    }
+
+    /* a stub of the kv v2 secrets engine of vault */
+    async fn start_vault_stub() -> Vault {
+        async fn read(
+            State(store): State<Store>,
+            Path(key): Path<String>,
+            Query(VersionQuery { version }): Query<VersionQuery>,
+        ) -> std::result::Result<Json<Value>, StatusCode> {
+            let store = store.lock().unwrap();
+            let versions = store.get(&key).ok_or(StatusCode::NOT_FOUND)?;
+            version
+                .map_or(versions.last(), |v| {
+                    v.checked_sub(1).and_then(|i| versions.get(i))
+                })
+                .map(|data| Json(json!({ "data": { "data": data, "metadata": {} } })))
+                .ok_or(StatusCode::NOT_FOUND)
+        }
+        async fn write(
+            State(store): State<Store>,
+            Path(key): Path<String>,
+            Json(body): Json<Value>,
+        ) -> Json<Value> {
+            let mut store = store.lock().unwrap();
+            let versions = store.entry(key).or_default();
+            versions.push(body["data"].clone());
+            Json(json!({ "data": { "version": versions.len() } }))
+        }
+        async fn delete(State(store): State<Store>, Path(key): Path<String>) -> StatusCode {
+            store.lock().unwrap().remove(&key);
+            StatusCode::NO_CONTENT
+        }
+
+        let app = Router::new()
+            .route("/v1/secret/data/*key", get(read).post(write))
+            .route("/v1/secret/metadata/*key", axum::routing::delete(delete))
+            .with_state(Store::default());
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        Vault {
+            addr: format!("http://{addr}"),
+            token: "test-token".to_string(),
+            mount: "secret".to_string(),
+            namespace: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_vault_kv() {
+        let vault = start_vault_stub().await;
+        let key = managed_vault_key("test-workspace", "u/test-user/secret");
+
+        let version = vault
+            .write(&key, DEFAULT_VAULT_FIELD, "hunter2")
+            .await
+            .unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(
+            vault.read(&key, DEFAULT_VAULT_FIELD, None).await.unwrap(),
+            "hunter2"
+        );
+        assert!(matches!(
+            vault.read(&key, "other", None).await,
+            Err(Error::NotFound(_))
+        ));
+
+        vault.delete(&key).await.unwrap();
+        assert!(matches!(
+            vault.read(&key, DEFAULT_VAULT_FIELD, None).await,
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    /* a restored variable version gets back the reference it had, which must still read the
+     * value it had then, after newer values were written to the same secret */
+    #[tokio::test]
+    async fn test_vault_restore_version() {
+        let vault = start_vault_stub().await;
+        let (w_id, path) = ("test-workspace", "u/test-user/secret");
+
+        let first = vault.store(w_id, path, "first").await.unwrap();
+        let second = vault.store(w_id, path, "second").await.unwrap();
+        assert_ne!(first, second);
+
+        let read = |stored: String| {
+            let vault = vault.clone();
+            async move {
+                let r = vault_ref(&stored).unwrap();
+                vault.read(r.key, r.field, r.version).await
+            }
+        };
+        assert_eq!(read(first.clone()).await.unwrap(), "first");
+        assert_eq!(read(second.clone()).await.unwrap(), "second");
+
+        /* the secret is only deleted with the variable and all of its versions */
+        let keys = managed_secrets(w_id, [first.as_str(), second.as_str(), "$vault:team/db"]);
+        assert_eq!(keys, vec![managed_vault_key(w_id, path)]);
+        for key in &keys {
+            vault.delete(key).await.unwrap();
+        }
+        assert!(matches!(read(first).await, Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn test_vault_ref() {
+        assert_eq!(
+            vault_ref("$vault:team/db#password"),
+            Some(VaultRef { key: "team/db", field: "password", version: None })
+        );
+        assert_eq!(
+            vault_ref("$vault:team/db"),
+            Some(VaultRef { key: "team/db", field: "value", version: None })
+        );
+        assert_eq!(
+            vault_ref("$vault:windmill/ws/u/a/b#value@3"),
+            Some(VaultRef { key: "windmill/ws/u/a/b", field: "value", version: Some(3) })
+        );
+        assert_eq!(
+            vault_ref("$vault:team/db#user@host"),
+            Some(VaultRef { key: "team/db", field: "user@host", version: None })
+        );
+        assert_eq!(vault_ref("aGVsbG8="), None);
+    }
+
+    #[test]
+    fn test_check_vault_ref() {
+        let authed = |is_admin| Authed {
+            email: "test@windmill.dev".to_string(),
+            username: "test-user".to_string(),
+            is_admin,
+            groups: vec![],
+            folders: vec![],
+            scopes: None,
+        };
+        let (admin, user) = (authed(true), authed(false));
+        let prefix = workspace_vault_prefix("teams/{workspace}", "test-workspace");
+        assert_eq!(prefix, "teams/test-workspace/");
+        let check = |authed: &Authed, key: &str| {
+            check_vault_ref(authed, "test-workspace", "u/a/b", key, Some(&prefix))
+        };
+
+        assert!(check(&admin, "teams/test-workspace/db").is_ok());
+        assert!(matches!(
+            check(&user, "teams/test-workspace/db"),
+            Err(Error::RequireAdmin(_))
+        ));
+        assert!(check(&admin, "teams/other/db").is_err());
+        assert!(check(&admin, "teams/test-workspace-2/db").is_err());
+        assert!(check(&admin, "teams/test-workspace/../other/db").is_err());
+        assert!(check_vault_ref(
+            &admin,
+            "test-workspace",
+            "u/a/b",
+            "teams/test-workspace/db",
+            None
+        )
+        .is_err());
+
+        assert!(check(&user, "windmill/test-workspace/u/a/b").is_ok());
+        assert!(check(&admin, "windmill/test-workspace/u/a/other").is_err());
+        assert!(check(&admin, "windmill/other/u/a/b").is_err());
+    }
+}
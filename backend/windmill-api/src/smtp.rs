@@ -0,0 +1,623 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2023
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+/* Minimal SMTP listener turning inbound emails into jobs. A recipient
+ * <workspace>+<path>+<token>@domain is resolved to the email trigger of that path, its token
+ * being what authenticates the email. The envelope sender must also match the allowlist of the
+ * trigger, which only filters emails as both it and the From header can be forged. */
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use base64::Engine;
+use mailparse::{DispositionType, MailHeaderMap, ParsedMail};
+use serde_json::{json, Map, Value};
+use sqlx::types::Uuid;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+use windmill_common::{error, jobs::JobPayload, users::username_to_permissioned_as};
+use windmill_queue::QueueTransaction;
+
+use crate::{db::DB, email_triggers::EmailTrigger, REQUEST_SIZE_LIMIT};
+
+lazy_static::lazy_static! {
+    pub static ref SMTP_TRIGGER_PORT: Option<u16> = std::env::var("SMTP_TRIGGER_PORT")
+        .ok()
+        .and_then(|x| x.parse::<u16>().ok());
+
+    pub static ref SMTP_TRIGGER_DOMAIN: Option<String> = std::env::var("SMTP_TRIGGER_DOMAIN")
+        .ok()
+        .map(|x| x.to_lowercase());
+}
+
+const MAX_LINE_LENGTH: u64 = 4096;
+const MAX_RECIPIENTS: usize = 20;
+const MAX_SESSIONS: usize = 100;
+/* the 5 minutes rfc 5321 recommends servers to wait for a command */
+const READ_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub async fn run_smtp_server(
+    db: DB,
+    rsmq: Option<rsmq_async::MultiplexedRsmq>,
+    addr: SocketAddr,
+    mut rx: tokio::sync::broadcast::Receiver<()>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!(addr = %addr.to_string(), "smtp trigger listener started");
+    let sessions = Arc::new(Semaphore::new(MAX_SESSIONS));
+
+    loop {
+        tokio::select! {
+            _ = rx.recv() => {
+                tracing::info!("Graceful shutdown of smtp trigger listener");
+                break;
+            }
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let permit = match sessions.clone().try_acquire_owned() {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            tracing::warn!(peer = %peer.to_string(), "too many smtp sessions");
+                            let _ = stream.try_write(b"421 Too many connections\r\n");
+                            continue;
+                        }
+                    };
+                    let db = db.clone();
+                    let rsmq = rsmq.clone();
+                    tokio::spawn(async move {
+                        let _permit = permit;
+                        if let Err(e) = handle_session(stream, &db, rsmq).await {
+                            tracing::warn!(peer = %peer.to_string(), "smtp session failed: {e}");
+                        }
+                    });
+                }
+                Err(e) => tracing::error!("Error accepting smtp connection: {e}"),
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn handle_session(
+    stream: TcpStream,
+    db: &DB,
+    rsmq: Option<rsmq_async::MultiplexedRsmq>,
+) -> anyhow::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+    let domain = SMTP_TRIGGER_DOMAIN.as_deref().unwrap_or("localhost");
+
+    reply(&mut write, &format!("220 {domain} windmill ESMTP")).await?;
+
+    let mut sender: Option<String> = None;
+    let mut recipients: Vec<(String, EmailTrigger)> = vec![];
+
+    loop {
+        let line = match read_line(&mut reader).await? {
+            Some(Line::Complete(line)) => String::from_utf8_lossy(&line).trim_end().to_string(),
+            Some(Line::TooLong) => {
+                reply(&mut write, "500 Line too long").await?;
+                continue;
+            }
+            None => return Ok(()),
+        };
+        let (verb, arg) = line.split_once(' ').unwrap_or((line.as_str(), ""));
+
+        let response = match verb.to_uppercase().as_str() {
+            "EHLO" => format!(
+                "250-{domain}\r\n250-SIZE {}\r\n250 8BITMIME",
+                *REQUEST_SIZE_LIMIT
+            ),
+            "HELO" => format!("250 {domain}"),
+            "MAIL" => match parse_path(arg, "FROM:") {
+                Some(address) => {
+                    sender = Some(address);
+                    recipients.clear();
+                    "250 OK".to_string()
+                }
+                None => "501 Syntax: MAIL FROM:<address>".to_string(),
+            },
+            "RCPT" => match (sender.as_ref(), parse_path(arg, "TO:")) {
+                (None, _) => "503 MAIL first".to_string(),
+                (_, None) => "501 Syntax: RCPT TO:<address>".to_string(),
+                _ if recipients.len() >= MAX_RECIPIENTS => "452 Too many recipients".to_string(),
+                (_, Some(address)) => match resolve_recipient(db, &address).await {
+                    Ok(Some(trigger)) => {
+                        recipients.push((address, trigger));
+                        "250 OK".to_string()
+                    }
+                    Ok(None) => "550 No such recipient".to_string(),
+                    Err(e) => {
+                        tracing::error!("could not resolve smtp recipient {address}: {e}");
+                        "451 Could not resolve recipient".to_string()
+                    }
+                },
+            },
+            "DATA" => match sender.take() {
+                Some(envelope_sender) if !recipients.is_empty() => {
+                    reply(&mut write, "354 End data with <CR><LF>.<CR><LF>").await?;
+                    let data = read_data(&mut reader).await?;
+                    let recipients = std::mem::take(&mut recipients);
+                    match data {
+                        Ok(data) => {
+                            deliver(db, rsmq.clone(), &envelope_sender, recipients, &data).await
+                        }
+                        Err(response) => response.to_string(),
+                    }
+                }
+                s => {
+                    sender = s;
+                    "503 RCPT first".to_string()
+                }
+            },
+            "RSET" => {
+                sender = None;
+                recipients.clear();
+                "250 OK".to_string()
+            }
+            "NOOP" => "250 OK".to_string(),
+            "QUIT" => {
+                reply(&mut write, &format!("221 {domain} Bye")).await?;
+                return Ok(());
+            }
+            _ => "502 Command not implemented".to_string(),
+        };
+        reply(&mut write, &response).await?;
+    }
+}
+
+async fn reply<W: AsyncWrite + Unpin>(write: &mut W, response: &str) -> anyhow::Result<()> {
+    write
+        .write_all(format!("{response}\r\n").as_bytes())
+        .await?;
+    write.flush().await?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Line {
+    Complete(Vec<u8>),
+    TooLong,
+}
+
+/* None on end of stream. The rest of a line longer than MAX_LINE_LENGTH is read and discarded
+ * so that the next one is read from its start */
+async fn read_line<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+) -> anyhow::Result<Option<Line>> {
+    let mut line = vec![];
+    let n = read_chunk(reader, &mut line).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.ends_with(b"\n") || (n as u64) < MAX_LINE_LENGTH {
+        return Ok(Some(Line::Complete(line)));
+    }
+    loop {
+        line.clear();
+        let n = read_chunk(reader, &mut line).await?;
+        if n == 0 || line.ends_with(b"\n") {
+            return Ok(Some(Line::TooLong));
+        }
+    }
+}
+
+/* a session idle for READ_TIMEOUT is closed so that it does not hold its slot forever */
+async fn read_chunk<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    buf: &mut Vec<u8>,
+) -> anyhow::Result<usize> {
+    let mut limited = (&mut *reader).take(MAX_LINE_LENGTH);
+    let n = tokio::time::timeout(READ_TIMEOUT, limited.read_until(b'\n', buf))
+        .await
+        .map_err(|_| anyhow::anyhow!("smtp session timed out"))??;
+    Ok(n)
+}
+
+/* reads the message until the terminating "." line, undoing the dot-stuffing. The message is
+ * still read to its end when it exceeds the size limit or has a line that is too long so that
+ * the session can go on, the reply to it being returned instead */
+async fn read_data<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+) -> anyhow::Result<Result<Vec<u8>, &'static str>> {
+    let mut data = vec![];
+    let mut error = None;
+    loop {
+        let line = match read_line(reader)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("connection closed during DATA"))?
+        {
+            Line::Complete(line) => line,
+            Line::TooLong => {
+                error = error.or(Some("500 Line too long"));
+                continue;
+            }
+        };
+        if line == b".\r\n" || line == b".\n" {
+            break;
+        }
+        let line = line.strip_prefix(b".").unwrap_or(&line);
+        if data.len() + line.len() > *REQUEST_SIZE_LIMIT {
+            error = error.or(Some("552 Message exceeds size limit"));
+        }
+        if error.is_none() {
+            data.extend_from_slice(line);
+        }
+    }
+    Ok(error.map_or(Ok(data), Err))
+}
+
+/* "FROM:<a@b.c> SIZE=123" -> a@b.c */
+fn parse_path(arg: &str, prefix: &str) -> Option<String> {
+    let arg = arg.trim();
+    if arg.len() < prefix.len() || !arg[..prefix.len()].eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let path = arg[prefix.len()..].trim_start();
+    let path = path.split_whitespace().next().unwrap_or("");
+    let path = path.strip_prefix('<')?.strip_suffix('>')?;
+    Some(path.to_string())
+}
+
+/* <workspace>+<path>+<token>@domain -> (workspace, path, token) */
+fn parse_recipient<'a>(
+    address: &'a str,
+    domain: Option<&str>,
+) -> Option<(&'a str, &'a str, &'a str)> {
+    let (local, address_domain) = address.rsplit_once('@')?;
+    if domain.map_or(false, |d| !d.eq_ignore_ascii_case(address_domain)) {
+        return None;
+    }
+    let mut parts = local.splitn(3, '+');
+    let workspace = parts.next().filter(|s| !s.is_empty())?;
+    let path = parts.next().filter(|s| !s.is_empty())?;
+    let token = parts.next().filter(|s| !s.is_empty())?;
+    Some((workspace, path, token))
+}
+
+/* takes the same time wherever the first difference is, so that the token cannot be guessed
+ * one character at a time */
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/* an allowed sender is either a full address, a domain starting with '@' or '*' */
+fn is_sender_allowed(allowed_senders: &[String], sender: &str) -> bool {
+    let sender = sender.to_lowercase();
+    allowed_senders.iter().any(|allowed| {
+        let allowed = allowed.to_lowercase();
+        allowed == "*"
+            || allowed == sender
+            || (allowed.starts_with('@') && sender.ends_with(&allowed))
+    })
+}
+
+async fn resolve_recipient(db: &DB, address: &str) -> error::Result<Option<EmailTrigger>> {
+    let Some((w_id, path, token)) = parse_recipient(address, SMTP_TRIGGER_DOMAIN.as_deref()) else {
+        return Ok(None);
+    };
+    let trigger = sqlx::query_as!(
+        EmailTrigger,
+        "SELECT * FROM email_trigger WHERE workspace_id = $1 AND path = $2 AND enabled = true",
+        w_id,
+        path
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(trigger.filter(|t| constant_time_eq(t.token.as_bytes(), token.as_bytes())))
+}
+
+/* the From header is what the sender sees, passed to the job along with the envelope sender
+ * that is only used when it is missing. Neither authenticates the email */
+fn sender_address(mail: &ParsedMail, envelope_sender: &str) -> String {
+    mail.headers
+        .get_first_value("From")
+        .and_then(|from| mailparse::addrparse(&from).ok())
+        .and_then(|addrs| addrs.extract_single_info())
+        .map(|info| info.addr)
+        .unwrap_or_else(|| envelope_sender.to_string())
+}
+
+fn collect_parts(
+    part: &ParsedMail,
+    text_body: &mut Option<String>,
+    html_body: &mut Option<String>,
+    attachments: &mut Vec<Value>,
+) -> error::Result<()> {
+    if !part.subparts.is_empty() {
+        for subpart in part.subparts.iter() {
+            collect_parts(subpart, text_body, html_body, attachments)?;
+        }
+        return Ok(());
+    }
+
+    let disposition = part.get_content_disposition();
+    let filename = disposition
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .cloned();
+    let parse_err = |e: mailparse::MailParseError| error::Error::BadRequest(e.to_string());
+
+    if disposition.disposition == DispositionType::Attachment || filename.is_some() {
+        let content = part.get_body_raw().map_err(parse_err)?;
+        attachments.push(json!({
+            "filename": filename,
+            "content_type": part.ctype.mimetype,
+            "content": base64::engine::general_purpose::STANDARD.encode(content),
+        }));
+    } else if part.ctype.mimetype == "text/plain" && text_body.is_none() {
+        *text_body = Some(part.get_body().map_err(parse_err)?);
+    } else if part.ctype.mimetype == "text/html" && html_body.is_none() {
+        *html_body = Some(part.get_body().map_err(parse_err)?);
+    }
+    Ok(())
+}
+
+fn email_args(
+    mail: &ParsedMail,
+    sender: &str,
+    envelope_sender: &str,
+    recipient: &str,
+) -> error::Result<Map<String, Value>> {
+    let mut text_body = None;
+    let mut html_body = None;
+    let mut attachments = vec![];
+    collect_parts(mail, &mut text_body, &mut html_body, &mut attachments)?;
+
+    let mut args = Map::new();
+    args.insert("from".to_string(), json!(sender));
+    args.insert("envelope_from".to_string(), json!(envelope_sender));
+    args.insert("to".to_string(), json!(recipient));
+    args.insert(
+        "subject".to_string(),
+        json!(mail.headers.get_first_value("Subject")),
+    );
+    args.insert("text_body".to_string(), json!(text_body));
+    args.insert("html_body".to_string(), json!(html_body));
+    args.insert("attachments".to_string(), Value::Array(attachments));
+    Ok(args)
+}
+
+async fn push_email_job(
+    db: &DB,
+    rsmq: Option<rsmq_async::MultiplexedRsmq>,
+    trigger: &EmailTrigger,
+    args: Map<String, Value>,
+) -> error::Result<Uuid> {
+    let mut tx: QueueTransaction<'_, _> = (rsmq, db.begin().await?).into();
+    let (payload, tag) = if trigger.is_flow {
+        (JobPayload::Flow(trigger.path.clone()), None)
+    } else {
+        let (hash, tag) = windmill_common::get_latest_deployed_hash_for_path(
+            tx.transaction_mut(),
+            &trigger.workspace_id,
+            &trigger.path,
+        )
+        .await?;
+        (
+            JobPayload::ScriptHash { hash, path: trigger.path.clone() },
+            tag,
+        )
+    };
+
+    let (uuid, tx) = windmill_queue::push(
+        tx,
+        &trigger.workspace_id,
+        payload,
+        args,
+        &trigger.edited_by,
+        &trigger.email,
+        username_to_permissioned_as(&trigger.edited_by),
+        None,
+        None,
+        None,
+        None,
+        None,
+        false,
+        false,
+        None,
+        true,
+        tag,
+        None,
+        None,
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(uuid)
+}
+
+async fn deliver(
+    db: &DB,
+    rsmq: Option<rsmq_async::MultiplexedRsmq>,
+    envelope_sender: &str,
+    recipients: Vec<(String, EmailTrigger)>,
+    data: &[u8],
+) -> String {
+    let mail = match mailparse::parse_mail(data) {
+        Ok(mail) => mail,
+        Err(e) => return format!("554 Could not parse message: {e}"),
+    };
+    let sender = sender_address(&mail, envelope_sender);
+
+    let mut queued = vec![];
+    let mut failed = false;
+    for (recipient, trigger) in recipients {
+        if !is_sender_allowed(&trigger.allowed_senders, envelope_sender) {
+            tracing::warn!(
+                workspace_id = %trigger.workspace_id,
+                path = %trigger.path,
+                "sender {envelope_sender} is not allowed by the email trigger"
+            );
+            continue;
+        }
+        let job = match email_args(&mail, &sender, envelope_sender, &recipient) {
+            Ok(args) => push_email_job(db, rsmq.clone(), &trigger, args).await,
+            Err(e) => Err(e),
+        };
+        match job {
+            Ok(uuid) => queued.push(uuid.to_string()),
+            Err(e) => {
+                tracing::error!(
+                    workspace_id = %trigger.workspace_id,
+                    path = %trigger.path,
+                    "could not push job for email trigger: {e}"
+                );
+                failed = true;
+            }
+        }
+    }
+
+    if !queued.is_empty() {
+        format!("250 OK queued as {}", queued.join(","))
+    } else if failed {
+        "451 Could not queue job".to_string()
+    } else {
+        "550 Sender not allowed".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_recipient() {
+        assert_eq!(
+            parse_recipient("demo+f/foo/bar+abc123@mail.windmill.dev", None),
+            Some(("demo", "f/foo/bar", "abc123"))
+        );
+        assert_eq!(
+            parse_recipient(
+                "demo+u/admin/s+abc123@MAIL.windmill.dev",
+                Some("mail.windmill.dev")
+            ),
+            Some(("demo", "u/admin/s", "abc123"))
+        );
+        assert_eq!(
+            parse_recipient("demo+f/foo/bar+abc123@other.dev", Some("mail.windmill.dev")),
+            None
+        );
+        assert_eq!(
+            parse_recipient("demo+f/foo/bar@mail.windmill.dev", None),
+            None
+        );
+        assert_eq!(
+            parse_recipient("demo+f/foo/bar+@mail.windmill.dev", None),
+            None
+        );
+        assert_eq!(parse_recipient("demo@mail.windmill.dev", None), None);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abc123", b"abc123"));
+        assert!(!constant_time_eq(b"abc123", b"abc124"));
+        assert!(!constant_time_eq(b"abc123", b"abc12"));
+        assert!(!constant_time_eq(b"", b"a"));
+    }
+
+    #[tokio::test]
+    async fn test_read_line() {
+        let long = "a".repeat(MAX_LINE_LENGTH as usize * 2);
+        let input = format!("{long}\r\nNOOP\r\n");
+        let mut reader = BufReader::new(input.as_bytes());
+        assert_eq!(read_line(&mut reader).await.unwrap(), Some(Line::TooLong));
+        assert_eq!(
+            read_line(&mut reader).await.unwrap(),
+            Some(Line::Complete(b"NOOP\r\n".to_vec()))
+        );
+        assert_eq!(read_line(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_data() {
+        let mut reader = BufReader::new(&b"Subject: a\r\n\r\n..b\r\n.\r\nQUIT\r\n"[..]);
+        assert_eq!(
+            read_data(&mut reader).await.unwrap(),
+            Ok(b"Subject: a\r\n\r\n.b\r\n".to_vec())
+        );
+
+        /* the message is read to its end even with a line that is too long */
+        let long = "a".repeat(MAX_LINE_LENGTH as usize + 1);
+        let input = format!("Subject: a\r\n\r\n{long}\r\nb\r\n.\r\nQUIT\r\n");
+        let mut reader = BufReader::new(input.as_bytes());
+        assert_eq!(
+            read_data(&mut reader).await.unwrap(),
+            Err("500 Line too long")
+        );
+        assert_eq!(
+            read_line(&mut reader).await.unwrap(),
+            Some(Line::Complete(b"QUIT\r\n".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_is_sender_allowed() {
+        let allowed = vec!["alice@windmill.dev".to_string(), "@acme.com".to_string()];
+        assert!(is_sender_allowed(&allowed, "Alice@windmill.dev"));
+        assert!(is_sender_allowed(&allowed, "bob@acme.com"));
+        assert!(!is_sender_allowed(&allowed, "bob@notacme.com"));
+        assert!(!is_sender_allowed(&allowed, "bob@windmill.dev"));
+        assert!(is_sender_allowed(&["*".to_string()], "bob@windmill.dev"));
+        assert!(!is_sender_allowed(&[], "bob@windmill.dev"));
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            parse_path("FROM:<a@b.c> SIZE=12", "FROM:"),
+            Some("a@b.c".to_string())
+        );
+        assert_eq!(parse_path("to: <a@b.c>", "TO:"), Some("a@b.c".to_string()));
+        assert_eq!(parse_path("TO:a@b.c", "TO:"), None);
+    }
+
+    #[test]
+    fn test_email_args() {
+        let raw = b"From: Alice <alice@windmill.dev>\r\n\
+Subject: Report\r\n\
+Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+hello\r\n\
+--b\r\n\
+Content-Type: text/csv\r\n\
+Content-Disposition: attachment; filename=\"a.csv\"\r\n\
+\r\n\
+a,b\r\n\
+--b--\r\n";
+        let mail = mailparse::parse_mail(raw).unwrap();
+        let sender = sender_address(&mail, "bounce@windmill.dev");
+        assert_eq!(sender, "alice@windmill.dev");
+        let args = email_args(
+            &mail,
+            &sender,
+            "bounce@windmill.dev",
+            "demo+f/foo/bar+abc123@localhost",
+        )
+        .unwrap();
+        assert_eq!(args["from"], json!("alice@windmill.dev"));
+        assert_eq!(args["envelope_from"], json!("bounce@windmill.dev"));
+        assert_eq!(args["subject"], json!("Report"));
+        assert_eq!(args["text_body"].as_str().map(str::trim), Some("hello"));
+        assert_eq!(args["html_body"], Value::Null);
+        let attachment = &args["attachments"][0];
+        assert_eq!(attachment["filename"], json!("a.csv"));
+        assert_eq!(attachment["content_type"], json!("text/csv"));
+        let content = base64::engine::general_purpose::STANDARD
+            .decode(attachment["content"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&content).trim(), "a,b");
+    }
+}
@@ -38,6 +38,7 @@ use tracing::{Instrument, Span};
 use windmill_audit::{audit_log, ActionKind};
 use windmill_common::{
     error::{self, Error, JsonResult, Result},
+    result_store::delete_stored_result,
     users::SUPERADMIN_SECRET_EMAIL,
     utils::{not_found_if_none, rd_string, require_admin, Pagination, StripPath},
 };
@@ -2037,8 +2038,9 @@ pub async fn delete_expired_items_perdiodically(
         }
 
         if *JOB_RETENTION_SECS > 0 {
-            let deleted_jobs = sqlx::query_scalar!(
-                "DELETE FROM completed_job WHERE started_at + ((duration_ms/1000 + $1) || ' s')::interval <= now() RETURNING id",
+            let deleted_jobs = sqlx::query!(
+                "DELETE FROM completed_job WHERE started_at + ((duration_ms/1000 + $1) || ' s')::interval <= now() \
+                 RETURNING id, result->'windmill_result_ref'->>'key' as result_key",
                 *JOB_RETENTION_SECS as i64
             )
             .fetch_all(db)
@@ -2046,11 +2048,23 @@ pub async fn delete_expired_items_perdiodically(
 
             match deleted_jobs {
                 Ok(deleted_jobs) => {
+                    let (deleted_jobs, stored_results): (Vec<_>, Vec<_>) = deleted_jobs
+                        .into_iter()
+                        .map(|r| (r.id, r.result_key))
+                        .unzip();
                     tracing::info!(
                         "deleted {} jobs completed JOB_RETENTION_SECS ago: {:?}",
                         deleted_jobs.len(),
                         deleted_jobs
-                    )
+                    );
+                    for key in stored_results.into_iter().flatten() {
+                        if let Err(e) = delete_stored_result(&key).await {
+                            tracing::error!(
+                                "Error deleting stored result {key}: {}",
+                                e.to_string()
+                            );
+                        }
+                    }
                 }
                 Err(e) => tracing::error!("Error deleting jobs: {}", e.to_string()),
             }
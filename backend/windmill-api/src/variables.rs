@@ -9,6 +9,7 @@
 use crate::{
     db::{UserDB, DB},
     oauth2::_refresh_token,
+    secret_backend::{delete_secrets, managed_secrets, resolve_secret, store_secret},
     users::{maybe_refresh_folders, require_owner_of_path, Authed},
     webhook_util::{WebhookMessage, WebhookShared},
 };
@@ -23,13 +24,14 @@ use serde_json::Value;
 use windmill_audit::{audit_log, ActionKind};
 use windmill_common::{
     error::{Error, JsonResult, Result},
-    utils::{not_found_if_none, StripPath},
+    utils::{not_found_if_none, paginate, Pagination, StripPath},
     variables::{get_reserved_variables, ContextualVariable, CreateVariable, ListableVariable},
 };
 
+use chrono::{DateTime, Utc};
 use magic_crypt::{MagicCrypt256, MagicCryptTrait};
-use serde::Deserialize;
-use sqlx::{Postgres, Transaction};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Postgres, Transaction};
 
 pub fn workspaced_service() -> Router {
     Router::new()
@@ -41,6 +43,9 @@ pub fn workspaced_service() -> Router {
         .route("/delete/*path", delete(delete_variable))
         .route("/create", post(create_variable))
         .route("/encrypt", post(encrypt_value))
+        .route("/history/*path", get(list_variable_versions))
+        .route("/history_diff/*path", get(diff_variable_version))
+        .route("/restore/*path", post(restore_variable_version))
 }
 
 async fn list_contextual_variables(
@@ -137,15 +142,15 @@ async fn get_variable(
         let value = variable.value.unwrap_or_else(|| "".to_string());
         ListableVariable {
             value: if variable.is_expired.unwrap_or(false) && variable.account.is_some() {
-                Some(_refresh_token(tx, &variable.path, w_id, variable.account.unwrap()).await?)
+                Some(
+                    _refresh_token(tx, &authed, &variable.path, w_id, variable.account.unwrap())
+                        .await?,
+                )
             } else if !value.is_empty() && decrypt_secret {
-                let mc = build_crypt(&mut tx, &w_id).await?;
+                let value = resolve_secret(&mut tx, &w_id, &variable.path, value).await?;
                 tx.commit().await?;
 
-                Some(
-                    mc.decrypt_base64_to_string(value)
-                        .map_err(|e| Error::InternalErr(e.to_string()))?,
-                )
+                Some(value)
             } else {
                 None
             },
@@ -198,7 +203,7 @@ async fn check_path_conflict<'c>(
     return Ok(());
 }
 
-async fn create_variable(
+pub(crate) async fn create_variable(
     authed: Authed,
     Extension(db): Extension<DB>,
     Extension(user_db): Extension<UserDB>,
@@ -212,9 +217,16 @@ async fn create_variable(
     let mut tx = user_db.begin(&authed).await?;
 
     check_path_conflict(&mut tx, &w_id, &variable.path).await?;
-    let value = if variable.is_secret && !already_encrypted.unwrap_or(false) {
-        let mc = build_crypt(&mut tx, &w_id).await?;
-        encrypt(&mc, &variable.value)
+    let value = if variable.is_secret {
+        store_secret(
+            &mut tx,
+            &authed,
+            &w_id,
+            &variable.path,
+            variable.value,
+            already_encrypted.unwrap_or(false),
+        )
+        .await?
     } else {
         variable.value
     };
@@ -282,15 +294,31 @@ async fn delete_variable(
     let path = path.to_path();
     let mut tx = user_db.begin(&authed).await?;
 
+    let deleted = sqlx::query!(
+        "DELETE FROM variable WHERE path = $1 AND workspace_id = $2 RETURNING is_secret, value",
+        path,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .map(|r| (r.is_secret, r.value));
     sqlx::query!(
-        "DELETE FROM variable WHERE path = $1 AND workspace_id = $2",
+        "DELETE FROM resource WHERE path = $1 AND workspace_id = $2",
         path,
         w_id
     )
     .execute(&mut tx)
     .await?;
+    let deleted_versions = sqlx::query_scalar!(
+        "DELETE FROM variable_version WHERE path = $1 AND workspace_id = $2 RETURNING \
+         CASE WHEN is_secret THEN value END",
+        path,
+        w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
     sqlx::query!(
-        "DELETE FROM resource WHERE path = $1 AND workspace_id = $2",
+        "DELETE FROM resource_version WHERE path = $1 AND workspace_id = $2",
         path,
         w_id
     )
@@ -309,6 +337,13 @@ async fn delete_variable(
 
     tx.commit().await?;
 
+    let secrets = deleted
+        .iter()
+        .filter(|(is_secret, _)| *is_secret)
+        .map(|(_, value)| value.as_str())
+        .chain(deleted_versions.iter().flatten().map(String::as_str));
+    delete_secrets(&managed_secrets(&w_id, secrets)).await?;
+
     webhook.send_message(
         w_id.clone(),
         WebhookMessage::DeleteVariable { workspace: w_id, path: path.to_owned() },
@@ -318,19 +353,19 @@ async fn delete_variable(
 }
 
 #[derive(Deserialize)]
-struct EditVariable {
-    path: Option<String>,
-    value: Option<String>,
-    is_secret: Option<bool>,
-    description: Option<String>,
+pub struct EditVariable {
+    pub path: Option<String>,
+    pub value: Option<String>,
+    pub is_secret: Option<bool>,
+    pub description: Option<String>,
 }
 
 #[derive(Deserialize)]
-struct AlreadyEncrypted {
-    already_encrypted: Option<bool>,
+pub struct AlreadyEncrypted {
+    pub already_encrypted: Option<bool>,
 }
 
-async fn update_variable(
+pub(crate) async fn update_variable(
     authed: Authed,
     Extension(user_db): Extension<UserDB>,
     Extension(webhook): Extension<WebhookShared>,
@@ -346,6 +381,8 @@ async fn update_variable(
 
     let mut tx = user_db.begin(&authed).await?;
 
+    save_variable_version(&mut tx, &w_id, path, &authed.username).await?;
+
     let mut sqlb = SqlBuilder::update_table("variable");
     sqlb.and_where_eq("path", "?".bind(&path));
     sqlb.and_where_eq("workspace_id", "?".bind(&w_id));
@@ -368,12 +405,21 @@ async fn update_variable(
             .unwrap_or(false)
         };
 
-        let value = if is_secret && !already_encrypted.unwrap_or(false) {
-            let mc = build_crypt(&mut tx, &w_id).await?;
-            encrypt(&mc, &nvalue)
+        let value = if is_secret {
+            let npath = ns.path.as_deref().unwrap_or(path);
+            store_secret(
+                &mut tx,
+                &authed,
+                &w_id,
+                npath,
+                nvalue,
+                already_encrypted.unwrap_or(false),
+            )
+            .await?
         } else {
             nvalue
         };
+        /* the replaced secret is kept in vault, the saved version of the variable refers to it */
         sqlb.set_str("value", &value);
     }
 
@@ -430,6 +476,7 @@ async fn update_variable(
             )
             .execute(&mut tx)
             .await?;
+            rename_versions(&mut tx, &w_id, path, &npath).await?;
         }
     }
 
@@ -463,6 +510,223 @@ async fn update_variable(
     Ok(format!("variable {} updated (npath: {:?})", path, npath))
 }
 
+#[derive(FromRow, Serialize)]
+pub struct VariableVersion {
+    pub id: i64,
+    pub path: String,
+    pub value: Option<String>,
+    pub is_secret: bool,
+    pub description: String,
+    pub replaced_by: String,
+    pub replaced_at: DateTime<Utc>,
+}
+
+/* Snapshots the variable as it is before being overwritten, secret values stay encrypted */
+pub async fn save_variable_version<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+    username: &str,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO variable_version
+            (workspace_id, path, value, is_secret, description, replaced_by)
+            SELECT workspace_id, path, value, is_secret, description, $3 FROM variable
+            WHERE path = $1 AND workspace_id = $2",
+        path,
+        w_id,
+        username,
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/* a variable and its linked resource are renamed together, and so are their histories */
+pub async fn rename_versions<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+    npath: &str,
+) -> Result<()> {
+    sqlx::query!(
+        "UPDATE variable_version SET path = $1 WHERE path = $2 AND workspace_id = $3",
+        npath,
+        path,
+        w_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE resource_version SET path = $1 WHERE path = $2 AND workspace_id = $3",
+        npath,
+        path,
+        w_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/* the versions are only visible to those who can see the variable */
+async fn require_variable<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+) -> Result<(String, bool)> {
+    let variable_o = sqlx::query!(
+        "SELECT value, is_secret FROM variable WHERE path = $1 AND workspace_id = $2",
+        path,
+        w_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(|r| (r.value, r.is_secret));
+    not_found_if_none(variable_o, "Variable", path)
+}
+
+async fn list_variable_versions(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+    Query(pagination): Query<Pagination>,
+) -> JsonResult<Vec<VariableVersion>> {
+    let path = path.to_path();
+    let (per_page, offset) = paginate(pagination);
+    let mut tx = user_db.begin(&authed).await?;
+
+    require_variable(&mut tx, &w_id, path).await?;
+    let versions = sqlx::query_as!(
+        VariableVersion,
+        "SELECT id, path, CASE WHEN is_secret THEN NULL ELSE value END as value, is_secret,
+            description, replaced_by, replaced_at
+            FROM variable_version WHERE path = $1 AND workspace_id = $2
+            ORDER BY id DESC LIMIT $3 OFFSET $4",
+        path,
+        w_id,
+        per_page as i64,
+        offset as i64,
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(versions))
+}
+
+#[derive(Deserialize)]
+struct VersionQuery {
+    version: i64,
+}
+
+#[derive(Serialize)]
+struct VariableDiff {
+    version: i64,
+    previous: String,
+    current: String,
+}
+
+async fn diff_variable_version(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+    Query(VersionQuery { version }): Query<VersionQuery>,
+) -> JsonResult<VariableDiff> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+
+    let (current, current_is_secret) = require_variable(&mut tx, &w_id, path).await?;
+    let version_o = sqlx::query!(
+        "SELECT value, is_secret FROM variable_version
+            WHERE id = $1 AND path = $2 AND workspace_id = $3",
+        version,
+        path,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .map(|r| (r.value, r.is_secret));
+    let (previous, previous_is_secret) =
+        not_found_if_none(version_o, "Variable version", version.to_string())?;
+    tx.commit().await?;
+
+    if current_is_secret || previous_is_secret {
+        return Err(Error::BadRequest(format!(
+            "cannot diff version {version} of {path}: secret values are not shown"
+        )));
+    }
+
+    Ok(Json(VariableDiff { version, previous, current }))
+}
+
+#[derive(Deserialize)]
+pub struct RestoreVersion {
+    pub version: i64,
+}
+
+async fn restore_variable_version(
+    authed: Authed,
+    Extension(user_db): Extension<UserDB>,
+    Extension(webhook): Extension<WebhookShared>,
+    Path((w_id, path)): Path<(String, StripPath)>,
+    Json(RestoreVersion { version }): Json<RestoreVersion>,
+) -> Result<String> {
+    let path = path.to_path();
+    let mut tx = user_db.begin(&authed).await?;
+
+    require_variable(&mut tx, &w_id, path).await?;
+    let version_o = sqlx::query!(
+        "SELECT value, is_secret, description FROM variable_version
+            WHERE id = $1 AND path = $2 AND workspace_id = $3",
+        version,
+        path,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .map(|r| (r.value, r.is_secret, r.description));
+    let (value, is_secret, description) =
+        not_found_if_none(version_o, "Variable version", version.to_string())?;
+
+    /* the restore is itself undoable */
+    save_variable_version(&mut tx, &w_id, path, &authed.username).await?;
+    let npath_o = sqlx::query_scalar!(
+        "UPDATE variable SET value = $1, is_secret = $2, description = $3
+            WHERE path = $4 AND workspace_id = $5 RETURNING path",
+        value,
+        is_secret,
+        description,
+        path,
+        w_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    not_found_if_none(npath_o, "Variable", path)?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "variables.restore",
+        ActionKind::Update,
+        &w_id,
+        Some(path),
+        Some([("version", version.to_string().as_str())].into()),
+    )
+    .await?;
+    tx.commit().await?;
+
+    webhook.send_message(
+        w_id.clone(),
+        WebhookMessage::UpdateVariable {
+            workspace: w_id,
+            old_path: path.to_owned(),
+            new_path: path.to_owned(),
+        },
+    );
+
+    Ok(format!("variable {path} restored to version {version}"))
+}
+
 fn replace_path(v: serde_json::Value, path: &str, npath: &str) -> Value {
     match v {
         Value::Object(v) => Value::Object(
@@ -505,3 +769,44 @@ pub async fn get_workspace_key<'c>(
 pub fn encrypt(mc: &MagicCrypt256, value: &str) -> String {
     mc.encrypt_str_to_base64(value)
 }
+
+/*
+ * Decrypts a secret variable value. Values encrypted with the key the workspace had before its
+ * last rotation, such as secrets imported already encrypted, are decrypted with that previous key
+ * and re-encrypted with the current one.
+ */
+pub async fn decrypt_secret<'c>(
+    tx: &mut Transaction<'c, Postgres>,
+    w_id: &str,
+    path: &str,
+    value: String,
+) -> Result<String> {
+    let mc = build_crypt(tx, w_id).await?;
+    let err = match mc.decrypt_base64_to_string(&value) {
+        Ok(decrypted) => return Ok(decrypted),
+        Err(e) => Error::InternalErr(e.to_string()),
+    };
+    let previous_key = sqlx::query_scalar!(
+        "SELECT previous_key FROM workspace_key WHERE workspace_id = $1 AND kind = 'cloud'",
+        w_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .flatten();
+    let decrypted = match previous_key {
+        Some(previous_key) => magic_crypt::new_magic_crypt!(previous_key, 256)
+            .decrypt_base64_to_string(&value)
+            .map_err(|_| err)?,
+        None => return Err(err),
+    };
+    sqlx::query!(
+        "UPDATE variable SET value = $1 WHERE path = $2 AND workspace_id = $3 AND value = $4",
+        encrypt(&mc, &decrypted),
+        path,
+        w_id,
+        value
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(decrypted)
+}
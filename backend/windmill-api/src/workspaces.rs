@@ -12,42 +12,51 @@ use std::str::FromStr;
 #[cfg(feature = "enterprise")]
 use crate::BASE_URL;
 use crate::{
-    apps::AppWithLastVersion,
+    apps::{self, AppWithLastVersion, CreateApp, EditApp, Policy},
     db::{UserDB, DB},
+    flows,
     folders::Folder,
-    resources::{Resource, ResourceType},
+    resources::{
+        self, CreateResource, CreateResourceType, EditResource, EditResourceType, Resource,
+        ResourceType,
+    },
+    scripts,
+    secret_backend::{resolve_secret, vault_ref},
     users::{Authed, WorkspaceInvite, VALID_USERNAME},
     utils::require_super_admin,
-    variables::build_crypt,
+    variables::{self, decrypt_secret, encrypt, AlreadyEncrypted, EditVariable},
     webhook_util::{InstanceEvent, WebhookShared},
 };
 #[cfg(feature = "enterprise")]
 use axum::response::Redirect;
 use axum::{
-    body::StreamBody,
+    body::{Bytes, StreamBody},
     extract::{Extension, Path, Query},
     headers,
     response::IntoResponse,
     routing::{delete, get, post},
     Json, Router,
 };
-use magic_crypt::MagicCryptTrait;
 #[cfg(feature = "enterprise")]
 use stripe::CustomerId;
 use windmill_audit::{audit_log, ActionKind};
 use windmill_common::{
     error::{to_anyhow, Error, JsonResult, Result},
-    flows::Flow,
-    scripts::{Schema, Script, ScriptLang},
-    utils::{paginate, rd_string, require_admin, Pagination},
-    variables::ExportableListableVariable,
+    flows::{Flow, NewFlow},
+    scripts::{ConcurrencyLimit, NewScript, Schema, Script, ScriptHash, ScriptKind, ScriptLang},
+    utils::{paginate, rd_string, require_admin, Pagination, StripPath},
+    variables::{CreateVariable, ExportableListableVariable},
 };
 
+use futures::StreamExt;
 use hyper::{header, StatusCode};
-use serde::{Deserialize, Serialize};
+use magic_crypt::MagicCryptTrait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
 use sqlx::{FromRow, Postgres, Transaction};
+use std::collections::HashMap;
 use tempfile::TempDir;
-use tokio::fs::File;
+use tokio::{fs::File, io::AsyncReadExt};
 use tokio_util::io::ReaderStream;
 
 pub fn workspaced_service() -> Router {
@@ -63,7 +72,9 @@ pub fn workspaced_service() -> Router {
         .route("/edit_webhook", post(edit_webhook))
         .route("/edit_auto_invite", post(edit_auto_invite))
         .route("/tarball", get(tarball_workspace))
-        .route("/premium_info", get(premium_info));
+        .route("/import_tarball", post(import_tarball_workspace))
+        .route("/premium_info", get(premium_info))
+        .route("/rotate_key", post(rotate_workspace_key));
 
     #[cfg(feature = "enterprise")]
     tracing::info!("stripe enabled");
@@ -233,6 +244,131 @@ async fn premium_info(
     Ok(Json(row))
 }
 
+/*
+ * Generates a new workspace key and re-encrypts every secret variable, and its versions, with
+ * it. The previous key is kept to decrypt the secrets that still get submitted encrypted with
+ * it, which are then re-encrypted with the new key on read. Secrets stored in vault are not
+ * affected, while resume and public app links signed with the previous key stop being valid.
+ * The rotation is aborted if a secret can be decrypted with neither the current nor the
+ * previous key, as it would be lost once the previous key is replaced.
+ */
+async fn rotate_workspace_key(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Path(w_id): Path<String>,
+) -> Result<String> {
+    require_admin(authed.is_admin, &authed.username)?;
+    let mut tx = db.begin().await?;
+
+    /* serializes concurrent rotations of the same workspace */
+    let keys = sqlx::query!(
+        "SELECT key, previous_key FROM workspace_key WHERE workspace_id = $1 AND kind = 'cloud' \
+         FOR UPDATE",
+        w_id
+    )
+    .fetch_one(&mut tx)
+    .await
+    .map_err(|e| Error::InternalErr(format!("fetching workspace key: {e}")))?;
+    let old_key = keys.key;
+
+    let new_key = rd_string(64);
+    let old_mc = magic_crypt::new_magic_crypt!(&old_key, 256);
+    let previous_mc = keys
+        .previous_key
+        .map(|previous_key| magic_crypt::new_magic_crypt!(previous_key, 256));
+    let new_mc = magic_crypt::new_magic_crypt!(&new_key, 256);
+    /* like decrypt_secret, falls back to the previous key for the secrets not re-encrypted yet */
+    let decrypt = |value: &str| {
+        old_mc.decrypt_base64_to_string(value).or_else(|e| {
+            previous_mc
+                .as_ref()
+                .and_then(|mc| mc.decrypt_base64_to_string(value).ok())
+                .ok_or(e)
+        })
+    };
+
+    let secrets = sqlx::query!(
+        "SELECT path, value FROM variable WHERE workspace_id = $1 AND is_secret AND value != '' \
+         FOR UPDATE",
+        w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    let mut rotated = 0;
+    for (path, value) in secrets.into_iter().map(|r| (r.path, r.value)) {
+        if vault_ref(&value).is_some() {
+            continue;
+        }
+        let decrypted = decrypt(&value).map_err(|e| {
+            Error::InternalErr(format!(
+                "could not decrypt secret variable {path}, key rotation aborted: {e}"
+            ))
+        })?;
+        sqlx::query!(
+            "UPDATE variable SET value = $1 WHERE path = $2 AND workspace_id = $3",
+            encrypt(&new_mc, &decrypted),
+            path,
+            w_id
+        )
+        .execute(&mut tx)
+        .await?;
+        rotated += 1;
+    }
+
+    /* older versions of secrets must stay restorable after the rotation */
+    let versions = sqlx::query!(
+        "SELECT id, path, value FROM variable_version WHERE workspace_id = $1 AND is_secret \
+         AND value != ''",
+        w_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    for (id, path, value) in versions.into_iter().map(|r| (r.id, r.path, r.value)) {
+        if vault_ref(&value).is_some() {
+            continue;
+        }
+        let decrypted = decrypt(&value).map_err(|e| {
+            Error::InternalErr(format!(
+                "could not decrypt version {id} of secret variable {path}, key rotation aborted: \
+                 {e}"
+            ))
+        })?;
+        sqlx::query!(
+            "UPDATE variable_version SET value = $1 WHERE id = $2",
+            encrypt(&new_mc, &decrypted),
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+
+    sqlx::query!(
+        "UPDATE workspace_key SET key = $1, previous_key = $2 WHERE workspace_id = $3 AND kind = 'cloud'",
+        new_key,
+        old_key,
+        w_id
+    )
+    .execute(&mut tx)
+    .await?;
+
+    audit_log(
+        &mut tx,
+        &authed.username,
+        "workspaces.rotate_key",
+        ActionKind::Update,
+        &w_id,
+        None,
+        Some([("secrets_reencrypted", rotated.to_string().as_str())].into()),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!(
+        "rotated key of workspace {w_id}, re-encrypted {rotated} secrets"
+    ))
+}
+
 #[cfg(feature = "enterprise")]
 #[derive(Deserialize)]
 struct PlanQuery {
@@ -830,11 +966,27 @@ async fn delete_workspace(
     sqlx::query!("DELETE FROM resource WHERE workspace_id = $1", &w_id)
         .execute(&mut tx)
         .await?;
+    sqlx::query!(
+        "DELETE FROM variable_version WHERE workspace_id = $1",
+        &w_id
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM resource_version WHERE workspace_id = $1",
+        &w_id
+    )
+    .execute(&mut tx)
+    .await?;
 
     sqlx::query!("DELETE FROM schedule WHERE workspace_id = $1", &w_id)
         .execute(&mut tx)
         .await?;
 
+    sqlx::query!("DELETE FROM email_trigger WHERE workspace_id = $1", &w_id)
+        .execute(&mut tx)
+        .await?;
+
     sqlx::query!("DELETE FROM completed_job WHERE workspace_id = $1", &w_id)
         .execute(&mut tx)
         .await?;
@@ -1058,7 +1210,7 @@ async fn exists_username(
     Ok("valid username".to_string())
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 struct ScriptMetadata {
     summary: String,
     description: String,
@@ -1066,6 +1218,24 @@ struct ScriptMetadata {
     is_template: bool,
     lock: Vec<String>,
     kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    concurrency_limit: Option<ConcurrencyLimit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    timeout: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    cache_ttl: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    memory_limit_mb: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    cpu_limit_millis: Option<i32>,
 }
 
 enum ArchiveImpl {
@@ -1215,6 +1385,7 @@ async fn tarball_workspace(
                 ScriptLang::Deno => "ts",
                 ScriptLang::Go => "go",
                 ScriptLang::Bash => "sh",
+                ScriptLang::Postgresql => "sql",
             };
             archive
                 .write_to_archive(&script.content, &format!("{}.{}", script.path, ext))
@@ -1226,6 +1397,13 @@ async fn tarball_workspace(
                 .lines()
                 .map(|x| x.to_string())
                 .collect();
+            let concurrency_limit = script
+                .concurrency_limit
+                .map(serde_json::from_value::<ConcurrencyLimit>)
+                .transpose()
+                .map_err(|e| {
+                    Error::InternalErr(format!("invalid concurrency limit of {}: {e}", script.hash))
+                })?;
             let metadata = ScriptMetadata {
                 summary: script.summary,
                 description: script.description,
@@ -1233,6 +1411,12 @@ async fn tarball_workspace(
                 is_template: script.is_template,
                 kind: script.kind.to_string(),
                 lock,
+                tag: script.tag,
+                concurrency_limit,
+                timeout: script.timeout,
+                cache_ttl: script.cache_ttl,
+                memory_limit_mb: script.memory_limit_mb,
+                cpu_limit_millis: script.cpu_limit_millis,
             };
             let metadata_str = serde_json::to_string_pretty(&metadata).unwrap();
             archive
@@ -1302,20 +1486,19 @@ async fn tarball_workspace(
         .fetch_all(&db)
         .await?;
 
-        let mc = build_crypt(&mut db.begin().await?, &w_id).await?;
+        let mut tx = db.begin().await?;
 
         for mut var in variables {
             if plain_secret.unwrap_or(false) && var.value.is_some() && var.is_secret {
-                var.value = Some(
-                    mc.decrypt_base64_to_string(var.value.unwrap())
-                        .map_err(|e| Error::InternalErr(e.to_string()))?,
-                );
+                var.value =
+                    Some(resolve_secret(&mut tx, &w_id, &var.path, var.value.unwrap()).await?);
             }
             let var_str = &to_string_without_metadata(&var, false).unwrap();
             archive
                 .write_to_archive(&var_str, &format!("{}.variable.json", var.path))
                 .await?;
         }
+        tx.commit().await?;
     }
 
     {
@@ -1354,3 +1537,577 @@ async fn tarball_workspace(
 
     Ok((headers, body))
 }
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum OnConflict {
+    Skip,
+    Overwrite,
+}
+
+#[derive(Deserialize)]
+struct ImportQueryParams {
+    dry_run: Option<bool>,
+    on_conflict: Option<OnConflict>,
+    plain_secret: Option<bool>,
+}
+
+/* items are named `<kind> <path>`, conflicts are either overwritten or skipped */
+#[derive(Serialize, Default)]
+struct ImportReport {
+    dry_run: bool,
+    created: Vec<String>,
+    overwritten: Vec<String>,
+    skipped: Vec<String>,
+    errors: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ImportedFolder {
+    display_name: String,
+    owners: Vec<String>,
+    #[serde(default)]
+    extra_perms: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct ImportedResourceType {
+    schema: Option<serde_json::Value>,
+    description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ImportedVariable {
+    value: Option<String>,
+    is_secret: bool,
+    #[serde(default)]
+    description: String,
+}
+
+#[derive(Deserialize)]
+struct ImportedResource {
+    value: Option<serde_json::Value>,
+    description: Option<String>,
+    resource_type: String,
+}
+
+#[derive(Deserialize)]
+struct ImportedFlow {
+    summary: String,
+    #[serde(default)]
+    description: String,
+    value: serde_json::Value,
+    schema: Option<Schema>,
+}
+
+#[derive(Deserialize)]
+struct ImportedApp {
+    summary: String,
+    value: serde_json::Value,
+    policy: Policy,
+}
+
+/* the content of a workspace archive as written by `tarball_workspace` */
+#[derive(Default)]
+struct WorkspaceArchive {
+    folders: Vec<(String, ImportedFolder)>,
+    resource_types: Vec<(String, ImportedResourceType)>,
+    variables: Vec<(String, ImportedVariable)>,
+    resources: Vec<(String, ImportedResource)>,
+    scripts: Vec<(String, ScriptLang, String)>,
+    script_metadata: HashMap<String, ScriptMetadata>,
+    flows: Vec<(String, ImportedFlow)>,
+    apps: Vec<(String, ImportedApp)>,
+}
+
+fn parse_archive_entry<T: DeserializeOwned>(name: &str, content: &str) -> Result<T> {
+    serde_json::from_str(content)
+        .map_err(|e| Error::BadRequest(format!("invalid archive entry {name}: {e}")))
+}
+
+async fn read_workspace_archive(body: &[u8]) -> Result<WorkspaceArchive> {
+    let mut archive = WorkspaceArchive::default();
+    let mut entries = tokio_tar::Archive::new(body).entries()?;
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path()?.to_string_lossy().to_string();
+        let mut content = String::new();
+        entry.read_to_string(&mut content).await?;
+
+        if let Some(folder) = name
+            .strip_prefix("f/")
+            .and_then(|x| x.strip_suffix("/folder.meta.json"))
+        {
+            let folder_meta = parse_archive_entry(&name, &content)?;
+            archive.folders.push((folder.to_string(), folder_meta));
+        } else if let Some(path) = name.strip_suffix(".script.json") {
+            let metadata = parse_archive_entry(&name, &content)?;
+            archive.script_metadata.insert(path.to_string(), metadata);
+        } else if let Some(rt) = name.strip_suffix(".resource-type.json") {
+            let resource_type = parse_archive_entry(&name, &content)?;
+            archive.resource_types.push((rt.to_string(), resource_type));
+        } else if let Some(path) = name.strip_suffix(".resource.json") {
+            let resource = parse_archive_entry(&name, &content)?;
+            archive.resources.push((path.to_string(), resource));
+        } else if let Some(path) = name.strip_suffix(".variable.json") {
+            let variable = parse_archive_entry(&name, &content)?;
+            archive.variables.push((path.to_string(), variable));
+        } else if let Some(path) = name.strip_suffix(".flow.json") {
+            let flow = parse_archive_entry(&name, &content)?;
+            archive.flows.push((path.to_string(), flow));
+        } else if let Some(path) = name.strip_suffix(".app.json") {
+            let app = parse_archive_entry(&name, &content)?;
+            archive.apps.push((path.to_string(), app));
+        } else {
+            let language = match name.rsplit_once('.') {
+                Some((path, "py")) => Some((path, ScriptLang::Python3)),
+                Some((path, "ts")) => Some((path, ScriptLang::Deno)),
+                Some((path, "go")) => Some((path, ScriptLang::Go)),
+                Some((path, "sh")) => Some((path, ScriptLang::Bash)),
+                Some((path, "sql")) => Some((path, ScriptLang::Postgresql)),
+                _ => None,
+            };
+            match language {
+                Some((path, language)) => {
+                    archive.scripts.push((path.to_string(), language, content))
+                }
+                None => tracing::warn!("ignoring unknown workspace archive entry {name}"),
+            }
+        }
+    }
+    Ok(archive)
+}
+
+impl ImportReport {
+    /* whether the item is to be written, recording what happens to it */
+    fn plan(&mut self, item: String, exists: bool, on_conflict: OnConflict) -> bool {
+        match (exists, on_conflict) {
+            (false, _) => {
+                self.created.push(item);
+                true
+            }
+            (true, OnConflict::Overwrite) => {
+                self.overwritten.push(item);
+                true
+            }
+            (true, OnConflict::Skip) => {
+                self.skipped.push(item);
+                false
+            }
+        }
+    }
+
+    /* a failed write is moved from the created or overwritten items to the errors */
+    fn record<T>(&mut self, item: &str, result: Result<T>) {
+        if let Err(e) = result {
+            self.created.retain(|x| x != item);
+            self.overwritten.retain(|x| x != item);
+            self.errors.push(format!("{item}: {e}"));
+        }
+    }
+}
+
+/*
+ * Imports an archive produced by `tarball_workspace` into the workspace. Items that already
+ * exist are skipped unless on_conflict=overwrite, and nothing is written with dry_run=true.
+ * Secret variables are re-encrypted with the key of the workspace: they must either be in plain
+ * text (plain_secret=true, as exported with plain_secret=true), vault references, or encrypted
+ * with the current or previous key of the workspace.
+ */
+async fn import_tarball_workspace(
+    authed: Authed,
+    Extension(db): Extension<DB>,
+    Extension(user_db): Extension<UserDB>,
+    Extension(rsmq): Extension<Option<rsmq_async::MultiplexedRsmq>>,
+    Extension(webhook): Extension<WebhookShared>,
+    Path(w_id): Path<String>,
+    Query(ImportQueryParams { dry_run, on_conflict, plain_secret }): Query<ImportQueryParams>,
+    body: Bytes,
+) -> JsonResult<ImportReport> {
+    require_admin(authed.is_admin, &authed.username)?;
+    let dry_run = dry_run.unwrap_or(false);
+    let on_conflict = on_conflict.unwrap_or(OnConflict::Skip);
+    let archive = read_workspace_archive(&body).await?;
+    let mut report = ImportReport { dry_run, ..Default::default() };
+
+    for (name, folder) in archive.folders {
+        let item = format!("folder {name}");
+        let exists = sqlx::query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM folder WHERE name = $1 AND workspace_id = $2)",
+            name,
+            w_id
+        )
+        .fetch_one(&db)
+        .await?
+        .unwrap_or(false);
+        if !report.plan(item.clone(), exists, on_conflict) || dry_run {
+            continue;
+        }
+        let r = sqlx::query!(
+            "INSERT INTO folder (workspace_id, name, display_name, owners, extra_perms)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (workspace_id, name) DO UPDATE
+                SET display_name = $3, owners = $4, extra_perms = $5",
+            w_id,
+            name,
+            folder.display_name,
+            &folder.owners,
+            folder.extra_perms,
+        )
+        .execute(&db)
+        .await
+        .map_err(Error::from);
+        report.record(&item, r);
+    }
+
+    for (name, rt) in archive.resource_types {
+        let item = format!("resource_type {name}");
+        let exists = sqlx::query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM resource_type WHERE name = $1 AND workspace_id = $2)",
+            name,
+            w_id
+        )
+        .fetch_one(&db)
+        .await?
+        .unwrap_or(false);
+        if !report.plan(item.clone(), exists, on_conflict) || dry_run {
+            continue;
+        }
+        let r = if exists {
+            resources::update_resource_type(
+                authed.clone(),
+                Extension(user_db.clone()),
+                Extension(webhook.clone()),
+                Path((w_id.clone(), name)),
+                Json(EditResourceType { schema: rt.schema, description: rt.description }),
+            )
+            .await
+            .map(|_| ())
+        } else {
+            resources::create_resource_type(
+                authed.clone(),
+                Extension(user_db.clone()),
+                Extension(webhook.clone()),
+                Path(w_id.clone()),
+                Json(CreateResourceType { name, schema: rt.schema, description: rt.description }),
+            )
+            .await
+            .map(|_| ())
+        };
+        report.record(&item, r);
+    }
+
+    for (path, variable) in archive.variables {
+        let item = format!("variable {path}");
+        let exists = sqlx::query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM variable WHERE path = $1 AND workspace_id = $2)",
+            path,
+            w_id
+        )
+        .fetch_one(&db)
+        .await?
+        .unwrap_or(false);
+        if !report.plan(item.clone(), exists, on_conflict) {
+            continue;
+        }
+        let value = variable.value.unwrap_or_default();
+        let value = if variable.is_secret
+            && !value.is_empty()
+            && !plain_secret.unwrap_or(false)
+            && vault_ref(&value).is_none()
+        {
+            let mut tx = db.begin().await?;
+            let decrypted = decrypt_secret(&mut tx, &w_id, &path, value).await;
+            tx.commit().await?;
+            match decrypted {
+                Ok(decrypted) => decrypted,
+                Err(_) => {
+                    report.record::<()>(
+                        &item,
+                        Err(Error::BadRequest(
+                            "secret encrypted with the key of another workspace, export it with \
+                             plain_secret=true"
+                                .to_string(),
+                        )),
+                    );
+                    continue;
+                }
+            }
+        } else {
+            value
+        };
+        if dry_run {
+            continue;
+        }
+        let r = if exists {
+            variables::update_variable(
+                authed.clone(),
+                Extension(user_db.clone()),
+                Extension(webhook.clone()),
+                Extension(db.clone()),
+                Path((w_id.clone(), StripPath(path))),
+                Query(AlreadyEncrypted { already_encrypted: Some(false) }),
+                Json(EditVariable {
+                    path: None,
+                    value: Some(value),
+                    is_secret: Some(variable.is_secret),
+                    description: Some(variable.description),
+                }),
+            )
+            .await
+            .map(|_| ())
+        } else {
+            variables::create_variable(
+                authed.clone(),
+                Extension(db.clone()),
+                Extension(user_db.clone()),
+                Extension(webhook.clone()),
+                Path(w_id.clone()),
+                Query(AlreadyEncrypted { already_encrypted: Some(false) }),
+                Json(CreateVariable {
+                    path,
+                    value,
+                    is_secret: variable.is_secret,
+                    description: variable.description,
+                    account: None,
+                    is_oauth: None,
+                }),
+            )
+            .await
+            .map(|_| ())
+        };
+        report.record(&item, r);
+    }
+
+    for (path, resource) in archive.resources {
+        let item = format!("resource {path}");
+        let exists = sqlx::query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM resource WHERE path = $1 AND workspace_id = $2)",
+            path,
+            w_id
+        )
+        .fetch_one(&db)
+        .await?
+        .unwrap_or(false);
+        if !report.plan(item.clone(), exists, on_conflict) || dry_run {
+            continue;
+        }
+        let r = if exists {
+            resources::update_resource(
+                authed.clone(),
+                Extension(user_db.clone()),
+                Extension(webhook.clone()),
+                Extension(db.clone()),
+                Path((w_id.clone(), StripPath(path))),
+                Json(EditResource {
+                    path: None,
+                    description: resource.description,
+                    value: resource.value,
+                }),
+            )
+            .await
+            .map(|_| ())
+        } else {
+            resources::create_resource(
+                authed.clone(),
+                Extension(user_db.clone()),
+                Extension(webhook.clone()),
+                Extension(db.clone()),
+                Path(w_id.clone()),
+                Json(CreateResource {
+                    path,
+                    value: resource.value,
+                    description: resource.description,
+                    resource_type: resource.resource_type,
+                }),
+            )
+            .await
+            .map(|_| ())
+        };
+        report.record(&item, r);
+    }
+
+    let mut script_metadata = archive.script_metadata;
+    for (path, language, content) in archive.scripts {
+        let item = format!("script {path}");
+        let Some(metadata) = script_metadata.remove(&path) else {
+            report
+                .errors
+                .push(format!("{item}: missing {path}.script.json"));
+            continue;
+        };
+        let kind = match serde_json::from_value::<ScriptKind>(json!(metadata.kind)) {
+            Ok(kind) => kind,
+            Err(e) => {
+                report.errors.push(format!("{item}: invalid kind: {e}"));
+                continue;
+            }
+        };
+        let parent_hash = sqlx::query_scalar!(
+            "SELECT hash FROM script WHERE path = $1 AND workspace_id = $2 AND archived = false \
+             ORDER BY created_at DESC LIMIT 1",
+            path,
+            w_id
+        )
+        .fetch_optional(&db)
+        .await?
+        .map(ScriptHash);
+        if !report.plan(item.clone(), parent_hash.is_some(), on_conflict) || dry_run {
+            continue;
+        }
+        let ns = NewScript {
+            path,
+            parent_hash,
+            summary: metadata.summary,
+            description: metadata.description,
+            content,
+            schema: metadata.schema,
+            is_template: Some(metadata.is_template),
+            lock: Some(metadata.lock),
+            language,
+            kind: Some(kind),
+            tag: metadata.tag,
+            draft_only: None,
+            concurrency_limit: metadata.concurrency_limit,
+            timeout: metadata.timeout,
+            cache_ttl: metadata.cache_ttl,
+            memory_limit_mb: metadata.memory_limit_mb,
+            cpu_limit_millis: metadata.cpu_limit_millis,
+        };
+        let r = scripts::create_script(
+            authed.clone(),
+            Extension(user_db.clone()),
+            Extension(rsmq.clone()),
+            Extension(webhook.clone()),
+            Extension(db.clone()),
+            Path(w_id.clone()),
+            Json(ns),
+        )
+        .await;
+        report.record(&item, r);
+    }
+
+    for (path, flow) in archive.flows {
+        let item = format!("flow {path}");
+        let exists = sqlx::query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM flow WHERE path = $1 AND workspace_id = $2)",
+            path,
+            w_id
+        )
+        .fetch_one(&db)
+        .await?
+        .unwrap_or(false);
+        if !report.plan(item.clone(), exists, on_conflict) || dry_run {
+            continue;
+        }
+        let nf = NewFlow {
+            path: path.clone(),
+            summary: flow.summary,
+            description: flow.description,
+            value: flow.value,
+            schema: flow.schema,
+            draft_only: None,
+        };
+        let r = if exists {
+            flows::update_flow(
+                authed.clone(),
+                Extension(user_db.clone()),
+                Extension(rsmq.clone()),
+                Extension(db.clone()),
+                Extension(webhook.clone()),
+                Path((w_id.clone(), StripPath(path))),
+                Json(nf),
+            )
+            .await
+            .map(|_| ())
+        } else {
+            flows::create_flow(
+                authed.clone(),
+                Extension(db.clone()),
+                Extension(user_db.clone()),
+                Extension(rsmq.clone()),
+                Extension(webhook.clone()),
+                Path(w_id.clone()),
+                Json(nf),
+            )
+            .await
+            .map(|_| ())
+        };
+        report.record(&item, r);
+    }
+
+    for (path, app) in archive.apps {
+        let item = format!("app {path}");
+        let exists = sqlx::query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM app WHERE path = $1 AND workspace_id = $2)",
+            path,
+            w_id
+        )
+        .fetch_one(&db)
+        .await?
+        .unwrap_or(false);
+        if !report.plan(item.clone(), exists, on_conflict) || dry_run {
+            continue;
+        }
+        let r = if exists {
+            apps::update_app(
+                authed.clone(),
+                Extension(user_db.clone()),
+                Extension(webhook.clone()),
+                Path((w_id.clone(), StripPath(path))),
+                Json(EditApp {
+                    path: None,
+                    summary: Some(app.summary),
+                    value: Some(app.value),
+                    policy: Some(app.policy),
+                }),
+            )
+            .await
+            .map(|_| ())
+        } else {
+            apps::create_app(
+                authed.clone(),
+                Extension(user_db.clone()),
+                Extension(webhook.clone()),
+                Path(w_id.clone()),
+                Json(CreateApp {
+                    path,
+                    summary: app.summary,
+                    value: app.value,
+                    policy: app.policy,
+                    draft_only: None,
+                }),
+            )
+            .await
+            .map(|_| ())
+        };
+        report.record(&item, r);
+    }
+
+    if !dry_run {
+        let mut tx = db.begin().await?;
+        let summary = format!(
+            "{} created, {} overwritten, {} skipped, {} errors",
+            report.created.len(),
+            report.overwritten.len(),
+            report.skipped.len(),
+            report.errors.len()
+        );
+        audit_log(
+            &mut tx,
+            &authed.username,
+            "workspaces.import_tarball",
+            ActionKind::Create,
+            &w_id,
+            None,
+            Some([("summary", summary.as_str())].into()),
+        )
+        .await?;
+        tx.commit().await?;
+    }
+
+    Ok(Json(report))
+}
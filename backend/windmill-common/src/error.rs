@@ -40,6 +40,10 @@ pub enum Error {
     RequireAdmin(String),
     #[error("{0}")]
     ExecutionErr(String),
+    #[error("Execution timed out after {0} seconds")]
+    ExecutionTimeout(u64),
+    #[error("Memory limit exceeded ({0} MB)")]
+    MemoryLimitExceeded(i32),
     #[error("IO error: {0}")]
     #[cfg(feature = "tokio")]
     IoErr(#[from] io::Error),
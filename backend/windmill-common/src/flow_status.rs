@@ -19,6 +19,7 @@ const HOURS: Duration = MINUTES.saturating_mul(60);
 
 pub const MAX_RETRY_ATTEMPTS: u16 = 1000;
 pub const MAX_RETRY_INTERVAL: Duration = HOURS.saturating_mul(6);
+pub const DEFAULT_WHILE_LOOP_MAX_ITERATIONS: u32 = 1000;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FlowStatus {
@@ -44,6 +45,17 @@ pub struct Iterator {
     pub itered: Vec<serde_json::Value>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WhileLoopStatus {
+    pub iteration: usize,
+}
+
+/// set on a module whose failure was caught by its `on_error` modules
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OnErrorStatus {
+    pub failed_job: Uuid,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BranchAllStatus {
     pub branch: usize,
@@ -96,11 +108,15 @@ pub enum FlowStatusModule {
         #[serde(skip_serializing_if = "Option::is_none")]
         iterator: Option<Iterator>,
         #[serde(skip_serializing_if = "Option::is_none")]
+        whileloop: Option<WhileLoopStatus>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         flow_jobs: Option<Vec<Uuid>>,
         #[serde(skip_serializing_if = "Option::is_none")]
         branch_chosen: Option<BranchChosen>,
         #[serde(skip_serializing_if = "Option::is_none")]
         branchall: Option<BranchAllStatus>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        on_error: Option<OnErrorStatus>,
         #[serde(default = "default_false")]
         parallel: bool,
     },
@@ -114,6 +130,8 @@ pub enum FlowStatusModule {
         #[serde(default)]
         #[serde(skip_serializing_if = "Vec::is_empty")]
         approvers: Vec<Approval>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        on_error: Option<OnErrorStatus>,
     },
     Failure {
         id: String,
@@ -122,6 +140,8 @@ pub enum FlowStatusModule {
         flow_jobs: Option<Vec<Uuid>>,
         #[serde(skip_serializing_if = "Option::is_none")]
         branch_chosen: Option<BranchChosen>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        on_error: Option<OnErrorStatus>,
     },
 }
 
@@ -17,7 +17,7 @@ use crate::{
     more_serde::{
         default_empty_string, default_false, default_id, default_null, default_true, is_default,
     },
-    scripts::{Schema, ScriptHash, ScriptLang},
+    scripts::{ConcurrencyLimit, Schema, ScriptHash, ScriptLang},
 };
 
 #[derive(Serialize)]
@@ -74,6 +74,14 @@ pub struct FlowValue {
     #[serde(default)]
     #[serde(skip_serializing_if = "is_default")]
     pub same_worker: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub concurrency_limit: Option<ConcurrencyLimit>,
+    /// id of a top-level module whose result is returned to the callers waiting for the result
+    /// of the flow as soon as it is available, while the rest of the flow keeps running
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub early_return: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -169,6 +177,16 @@ pub struct FlowModule {
     pub retry: Option<Retry>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sleep: Option<InputTransform>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_ttl: Option<i32>,
+    /// modules run with the error as `error` argument when this module fails, their result is
+    /// used as the result of this module and the flow continues
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_error: Option<Vec<FlowModule>>,
 }
 
 impl FlowModule {
@@ -240,6 +258,15 @@ pub enum FlowModuleValue {
         #[serde(default = "default_false")]
         parallel: bool,
     },
+    /// repeats `modules` until the `stop_after_if` expression of the module is true on the
+    /// result of the last iteration, or fails once `max_iterations` is reached
+    WhileloopFlow {
+        modules: Vec<FlowModule>,
+        #[serde(default = "default_false")]
+        skip_failures: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_iterations: Option<u32>,
+    },
     BranchOne {
         branches: Vec<BranchOneModules>,
         default: Vec<FlowModule>,
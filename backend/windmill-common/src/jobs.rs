@@ -80,6 +80,24 @@ pub struct QueuedJob {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub leaf_jobs: Option<serde_json::Value>,
     pub tag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrent_limit: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency_time_window_s: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_ttl: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_limit_mb: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_limit_millis: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flow_version: Option<i64>,
 }
 
 impl QueuedJob {
@@ -142,6 +160,15 @@ impl Default for QueuedJob {
             root_job: None,
             leaf_jobs: None,
             tag: "deno".to_string(),
+            priority: None,
+            concurrency_key: None,
+            concurrent_limit: None,
+            concurrency_time_window_s: None,
+            timeout: None,
+            cache_ttl: None,
+            memory_limit_mb: None,
+            cpu_limit_millis: None,
+            flow_version: None,
         }
     }
 }
@@ -220,3 +247,74 @@ pub async fn get_payload_tag_from_prefixed_path<'c>(
     };
     Ok((payload, tag))
 }
+
+pub const JOB_UPDATES_CHANNEL: &str = "job_updates";
+
+/* postgres rejects NOTIFY payloads of 8000 bytes or more, keep room for the envelope */
+const MAX_NOTIFY_LOGS_BYTES: usize = 7000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobUpdateNotification {
+    /// `log_offset` is the length in chars of the job logs once `logs` has been appended
+    Logs {
+        job_id: Uuid,
+        logs: String,
+        log_offset: i32,
+    },
+    FlowStatus {
+        job_id: Uuid,
+    },
+    Completed {
+        job_id: Uuid,
+        success: bool,
+    },
+}
+
+impl JobUpdateNotification {
+    pub fn job_id(&self) -> &Uuid {
+        match self {
+            JobUpdateNotification::Logs { job_id, .. }
+            | JobUpdateNotification::FlowStatus { job_id }
+            | JobUpdateNotification::Completed { job_id, .. } => job_id,
+        }
+    }
+}
+
+pub async fn notify_job_update<'c, E: sqlx::PgExecutor<'c>>(
+    db: E,
+    update: &JobUpdateNotification,
+) -> error::Result<()> {
+    let payload = serde_json::to_string(update).map_err(error::to_anyhow)?;
+    /* not checked by query!, sqlx has no type for the void returned by pg_notify */
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(JOB_UPDATES_CHANNEL)
+        .bind(payload)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/* splits logs on char boundaries so that each json escaped piece fits in a notification */
+pub fn split_logs_for_notify(logs: &str) -> Vec<&str> {
+    let mut pieces = vec![];
+    let mut start = 0;
+    let mut escaped_len = 0;
+    for (i, c) in logs.char_indices() {
+        let c_len = match c {
+            '"' | '\\' | '\n' | '\r' | '\t' | '\u{08}' | '\u{0c}' => 2,
+            c if (c as u32) < 0x20 => 6,
+            c => c.len_utf8(),
+        };
+        if escaped_len + c_len > MAX_NOTIFY_LOGS_BYTES {
+            pieces.push(&logs[start..i]);
+            start = i;
+            escaped_len = 0;
+        }
+        escaped_len += c_len;
+    }
+    if start < logs.len() {
+        pieces.push(&logs[start..]);
+    }
+    pieces
+}
@@ -24,6 +24,8 @@ pub mod users;
 pub mod utils;
 pub mod variables;
 
+#[cfg(feature = "tokio")]
+pub mod result_store;
 #[cfg(feature = "tracing_init")]
 pub mod tracing_init;
 
@@ -0,0 +1,236 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2023
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+/*
+ * Results larger than RESULT_STORE_THRESHOLD bytes are written to a result store instead of the
+ * completed_job row, which only keeps a pointer to them: `{"windmill_result_ref": {"store", "key",
+ * "size"}}`. The store is a local directory (RESULT_STORE_DIR) for single-node setups, or an S3
+ * compatible bucket (RESULT_STORE_S3_BUCKET) accessed through rclone, configured like the s3 cache
+ * from the environment (AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY, RCLONE_S3_ENDPOINT, ...).
+ */
+
+use std::process::Stdio;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::Command,
+};
+use uuid::Uuid;
+
+use crate::error::{self, Error};
+
+pub const RESULT_REF_KEY: &str = "windmill_result_ref";
+pub const DEFAULT_RESULT_STORE_THRESHOLD: usize = 1024 * 1024; // 1MB
+
+#[derive(Debug, Clone)]
+pub enum ResultStore {
+    Fs { dir: String },
+    S3 { bucket: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ResultRef {
+    pub store: String,
+    pub key: String,
+    pub size: usize,
+}
+
+lazy_static::lazy_static! {
+    pub static ref RESULT_STORE: Option<ResultStore> = std::env::var("RESULT_STORE_S3_BUCKET")
+        .ok()
+        .map(|bucket| ResultStore::S3 { bucket })
+        .or_else(|| std::env::var("RESULT_STORE_DIR").ok().map(|dir| ResultStore::Fs { dir }));
+
+    pub static ref RESULT_STORE_THRESHOLD: usize = std::env::var("RESULT_STORE_THRESHOLD")
+        .ok()
+        .and_then(|x| x.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_RESULT_STORE_THRESHOLD);
+}
+
+impl ResultStore {
+    fn name(&self) -> &'static str {
+        match self {
+            ResultStore::Fs { .. } => "fs",
+            ResultStore::S3 { .. } => "s3",
+        }
+    }
+
+    fn s3_path(bucket: &str, key: &str) -> String {
+        format!(":s3,env_auth=true:{bucket}/results/{key}")
+    }
+
+    async fn write(&self, key: &str, content: &[u8]) -> error::Result<()> {
+        match self {
+            ResultStore::Fs { dir } => {
+                let path = format!("{dir}/{key}");
+                if let Some(parent) = std::path::Path::new(&path).parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::write(&path, content).await?;
+            }
+            ResultStore::S3 { bucket } => {
+                let mut child = Command::new("rclone")
+                    .args(["rcat", &Self::s3_path(bucket, key), "--s3-no-check-bucket"])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()?;
+                let mut stdin = child.stdin.take().expect("rclone stdin is piped");
+                stdin.write_all(content).await?;
+                drop(stdin);
+                let output = child.wait_with_output().await?;
+                if !output.status.success() {
+                    return Err(Error::InternalErr(format!(
+                        "could not write result {key} to bucket {bucket}: {}",
+                        String::from_utf8_lossy(&output.stderr)
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn read(&self, key: &str) -> error::Result<Vec<u8>> {
+        match self {
+            ResultStore::Fs { dir } => {
+                let mut content = vec![];
+                tokio::fs::File::open(format!("{dir}/{key}"))
+                    .await
+                    .map_err(|e| Error::NotFound(format!("result {key} in the result store: {e}")))?
+                    .read_to_end(&mut content)
+                    .await?;
+                Ok(content)
+            }
+            ResultStore::S3 { bucket } => {
+                let output = Command::new("rclone")
+                    .args(["cat", &Self::s3_path(bucket, key)])
+                    .stdin(Stdio::null())
+                    .output()
+                    .await?;
+                if !output.status.success() {
+                    return Err(Error::NotFound(format!(
+                        "result {key} in bucket {bucket}: {}",
+                        String::from_utf8_lossy(&output.stderr)
+                    )));
+                }
+                Ok(output.stdout)
+            }
+        }
+    }
+
+    async fn delete(&self, key: &str) -> error::Result<()> {
+        match self {
+            ResultStore::Fs { dir } => match tokio::fs::remove_file(format!("{dir}/{key}")).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+            ResultStore::S3 { bucket } => {
+                let output = Command::new("rclone")
+                    .args(["deletefile", &Self::s3_path(bucket, key)])
+                    .stdin(Stdio::null())
+                    .output()
+                    .await?;
+                if !output.status.success() {
+                    return Err(Error::InternalErr(format!(
+                        "could not delete result {key} from bucket {bucket}: {}",
+                        String::from_utf8_lossy(&output.stderr)
+                    )));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn result_ref(result: &Value) -> Option<ResultRef> {
+    match result {
+        Value::Object(o) if o.len() == 1 => o
+            .get(RESULT_REF_KEY)
+            .and_then(|r| serde_json::from_value(r.clone()).ok()),
+        _ => None,
+    }
+}
+
+/* Moves the result of a job to the result store if one is configured and the result is above the
+ * threshold, returning the pointer to save in its place. Otherwise the result is returned as is */
+pub async fn store_result(w_id: &str, job_id: &Uuid, result: Value) -> error::Result<Value> {
+    let store = match RESULT_STORE.as_ref() {
+        Some(store) if result_ref(&result).is_none() => store,
+        _ => return Ok(result),
+    };
+    let content = serde_json::to_vec(&result).map_err(|e| Error::InternalErr(e.to_string()))?;
+    if content.len() <= *RESULT_STORE_THRESHOLD {
+        return Ok(result);
+    }
+    let key = format!("{w_id}/{job_id}.json");
+    store.write(&key, &content).await?;
+    let result_ref = ResultRef { store: store.name().to_string(), key, size: content.len() };
+    Ok(serde_json::json!({ RESULT_REF_KEY: result_ref }))
+}
+
+/* Replaces a pointer to the result store by the result it points to */
+pub async fn resolve_result(result: Value) -> error::Result<Value> {
+    let result_ref = match result_ref(&result) {
+        Some(result_ref) => result_ref,
+        None => return Ok(result),
+    };
+    let store = RESULT_STORE.as_ref().ok_or_else(|| {
+        Error::InternalErr(format!(
+            "result {} is in the {} result store but no result store is configured",
+            result_ref.key, result_ref.store
+        ))
+    })?;
+    let content = store.read(&result_ref.key).await?;
+    serde_json::from_slice(&content).map_err(|e| {
+        Error::InternalErr(format!(
+            "invalid result {} in the result store: {e}",
+            result_ref.key
+        ))
+    })
+}
+
+pub async fn resolve_optional_result(result: Option<Value>) -> error::Result<Option<Value>> {
+    match result {
+        Some(result) => resolve_result(result).await.map(Some),
+        None => Ok(None),
+    }
+}
+
+/* Deletes the stored result a pointer refers to, once its job is deleted */
+pub async fn delete_stored_result(key: &str) -> error::Result<()> {
+    match RESULT_STORE.as_ref() {
+        Some(store) => store.delete(key).await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_result_ref() {
+        let pointer = json!({ RESULT_REF_KEY: { "store": "fs", "key": "test-workspace/job.json", "size": 42 } });
+        assert_eq!(
+            result_ref(&pointer),
+            Some(ResultRef {
+                store: "fs".to_string(),
+                key: "test-workspace/job.json".to_string(),
+                size: 42
+            })
+        );
+        /* a result that merely contains the key is not a pointer */
+        assert_eq!(result_ref(&json!({ RESULT_REF_KEY: {}, "foo": 1 })), None);
+        assert_eq!(result_ref(&json!({ RESULT_REF_KEY: "foo" })), None);
+        assert_eq!(result_ref(&json!([1, 2])), None);
+    }
+}
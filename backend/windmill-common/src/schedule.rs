@@ -26,8 +26,44 @@ pub struct Schedule {
     pub email: String,
     pub error: Option<String>,
     pub on_failure: Option<String>,
+    pub on_recovery: Option<String>,
+    pub on_success: Option<String>,
+    pub failure_threshold: Option<i32>,
+    pub failure_count: i32,
+    pub last_failure: Option<serde_json::Value>,
+    pub retry: Option<serde_json::Value>,
+    pub retry_attempts: i32,
+    pub priority: Option<i16>,
+    pub catch_up: CatchUp,
+    pub catch_up_limit: Option<i32>,
+    pub last_tick: Option<DateTime<chrono::Utc>>,
+    /* no job runs before, the schedule resuming with the first tick after it */
+    pub paused_until: Option<DateTime<chrono::Utc>>,
+    /* one-shot schedules run once at run_at, their cron expression is ignored */
+    pub run_at: Option<DateTime<chrono::Utc>>,
 }
 
+/*
+ * What to do with the ticks missed since the last tick a job ran for, e.g. while the instance was
+ * down or the schedule disabled: skip them, run the latest one only or run all of them, up to
+ * catch_up_limit (or MAX_CATCH_UP_TICKS) of the latest ones
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type))]
+#[cfg_attr(
+    feature = "sqlx",
+    sqlx(type_name = "SCHEDULE_CATCH_UP", rename_all = "lowercase")
+)]
+#[serde(rename_all = "lowercase")]
+pub enum CatchUp {
+    #[default]
+    Skip,
+    Once,
+    All,
+}
+
+pub const MAX_CATCH_UP_TICKS: i32 = 100;
+
 pub fn schedule_to_user(path: &str) -> String {
     format!("schedule-{}", path.replace('/', "-"))
 }
@@ -29,6 +29,7 @@ pub enum ScriptLang {
     Python3,
     Go,
     Bash,
+    Postgresql,
 }
 
 impl ScriptLang {
@@ -38,6 +39,7 @@ impl ScriptLang {
             ScriptLang::Python3 => "python3",
             ScriptLang::Go => "go",
             ScriptLang::Bash => "bash",
+            ScriptLang::Postgresql => "postgresql",
         }
     }
 }
@@ -139,6 +141,16 @@ pub struct Script {
     pub tag: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub draft_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency_limit: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_ttl: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_limit_mb: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_limit_millis: Option<i32>,
 }
 
 #[derive(Serialize)]
@@ -189,6 +201,26 @@ pub struct NewScript {
     pub kind: Option<ScriptKind>,
     pub tag: Option<String>,
     pub draft_only: Option<bool>,
+    pub concurrency_limit: Option<ConcurrencyLimit>,
+    pub timeout: Option<i32>,
+    pub cache_ttl: Option<i32>,
+    pub memory_limit_mb: Option<i32>,
+    pub cpu_limit_millis: Option<i32>,
+}
+
+/// Caps how many jobs sharing the same concurrency key may run at once. Without a
+/// `time_window_s`, only jobs currently running count towards `max_concurrent`, otherwise every
+/// job started within the last `time_window_s` seconds does. The key defaults to the path of the
+/// script or flow and may reference the job arguments as `$args[name]`.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq)]
+pub struct ConcurrencyLimit {
+    pub max_concurrent: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub time_window_s: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub key: Option<String>,
 }
 
 #[derive(Deserialize)]
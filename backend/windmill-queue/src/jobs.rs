@@ -20,11 +20,16 @@ use windmill_common::{
     flow_status::{FlowStatus, JobResult, MAX_RETRY_ATTEMPTS, MAX_RETRY_INTERVAL},
     flows::{FlowModule, FlowModuleValue, FlowValue},
     jobs::{JobKind, JobPayload, QueuedJob, RawCode},
-    scripts::{ScriptHash, ScriptLang},
+    result_store::{resolve_optional_result, resolve_result},
+    scripts::{ConcurrencyLimit, ScriptHash, ScriptLang},
     METRICS_ENABLED,
 };
 
-use crate::QueueTransaction;
+use windmill_parser_bash::{
+    bash_requirements_tag, parse_bash_requirements, BASH_REQUIREMENTS_TAG_PREFIX,
+};
+
+use crate::{QueueTransaction, RedisOp};
 
 lazy_static::lazy_static! {
     pub static ref HTTP_CLIENT: Client = reqwest::ClientBuilder::new()
@@ -47,8 +52,23 @@ lazy_static::lazy_static! {
         "Total number of jobs pulled from the queue."
     )
     .unwrap();
+    static ref RSMQ_POLL_OFFSET: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    static ref RSMQ_QUEUES: std::sync::Mutex<Option<(std::time::Instant, Vec<String>)>> = std::sync::Mutex::new(None);
+
     pub static ref CLOUD_HOSTED: bool = std::env::var("CLOUD_HOSTED").is_ok();
 
+    static ref CONCURRENCY_LIMIT_RETRY_DELAY_S: u64 = std::env::var("CONCURRENCY_LIMIT_RETRY_DELAY_S")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(5);
+
+    /* the highest timeout a job can be given, defaults to the worker TIMEOUT */
+    pub static ref MAX_TIMEOUT: u64 = std::env::var("MAX_TIMEOUT")
+        .or_else(|_| std::env::var("TIMEOUT"))
+        .ok()
+        .and_then(|x| x.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_TIMEOUT);
+
     pub static ref ACCEPTED_TAGS: Vec<String> = std::env::var("WORKER_TAGS")
         .ok()
         .map(|x| x.split(',').map(|x| x.to_string()).collect())
@@ -57,18 +77,120 @@ lazy_static::lazy_static! {
             "python3".to_string(),
             "go".to_string(),
             "bash".to_string(),
+            "postgresql".to_string(),
             "dependency".to_string(),
             "flow".to_string(),
             "hub".to_string(),
             "other".to_string()]);
 
-    pub static ref ACCEPTED_TAGS_FILTER: String = format!(" AND ({})",
-        ACCEPTED_TAGS.clone().into_iter().map(|x| format!("(tag = '{x}')")).join(" OR "));
+    /* Bash jobs with requirements are tagged `bash:<binaries>`. Workers with the default tags take
+     * them all, and fail them if a binary is missing. Workers with WORKER_TAGS only take the ones
+     * whose binaries are all listed as `bash:<binary>` tags */
+    static ref ACCEPTED_BASH_BINARIES: Option<Vec<String>> = std::env::var("WORKER_TAGS")
+        .ok()
+        .map(|_| ACCEPTED_TAGS
+            .iter()
+            .filter_map(|x| x.strip_prefix(BASH_REQUIREMENTS_TAG_PREFIX))
+            .map(|x| x.to_string())
+            .collect());
+
+    pub static ref ACCEPTED_TAGS_FILTER: String = format!(" AND ({}{})",
+        ACCEPTED_TAGS.clone().into_iter().map(|x| format!("(tag = '{x}')")).join(" OR "),
+        match ACCEPTED_BASH_BINARIES.as_ref() {
+            None => format!(" OR (tag LIKE '{BASH_REQUIREMENTS_TAG_PREFIX}%')"),
+            Some(binaries) if binaries.is_empty() => String::new(),
+            Some(binaries) => format!(
+                " OR (tag LIKE '{BASH_REQUIREMENTS_TAG_PREFIX}%' AND string_to_array(substr(tag, {}), ',') <@ ARRAY[{}]::text[])",
+                BASH_REQUIREMENTS_TAG_PREFIX.len() + 1,
+                binaries.iter().map(|x| format!("'{x}'")).join(", ")
+            ),
+        });
 }
 
 const MAX_FREE_EXECS: i32 = 1000;
 const MAX_FREE_CONCURRENT_RUNS: i32 = 15;
 const RSMQ_MAIN_QUEUE: &'static str = "main_queue";
+const RSMQ_QUEUES_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Every (priority, tag, workspace) triple gets its own rsmq queue so that workers only poll the
+/// tags they accept, can be restricted to a subset of workspaces with
+/// WHITELIST_WORKSPACES/BLACKLIST_WORKSPACES, the same way as with postgres, and can pop the
+/// queues of higher priority first.
+pub fn rsmq_queue_name(w_id: &str, tag: &str, priority: Option<i16>) -> String {
+    format!(
+        "{RSMQ_MAIN_QUEUE}_{}_{}_{w_id}",
+        priority.unwrap_or(0),
+        rsmq_tag(tag)
+    )
+}
+
+/* rsmq queue names only allow alphanumeric characters, '-' and '_', so the tag is hex encoded.
+ * Unlike replacing the other characters, this keeps distinct tags in distinct queues and lets
+ * the tag be decoded back when filtering the queues to poll */
+fn rsmq_tag(tag: &str) -> String {
+    hex::encode(tag)
+}
+
+fn is_tag_accepted(tag: &str) -> bool {
+    ACCEPTED_TAGS.iter().any(|t| t == tag)
+        || tag
+            .strip_prefix(BASH_REQUIREMENTS_TAG_PREFIX)
+            .map_or(false, |binaries| match ACCEPTED_BASH_BINARIES.as_ref() {
+                None => !binaries.is_empty(),
+                Some(accepted) => are_bash_binaries_accepted(binaries, accepted),
+            })
+}
+
+/* same check as the `string_to_array(...) <@ ARRAY[...]` of ACCEPTED_TAGS_FILTER */
+fn are_bash_binaries_accepted(binaries: &str, accepted: &[String]) -> bool {
+    binaries
+        .split(',')
+        .all(|binary| accepted.iter().any(|a| a == binary))
+}
+
+struct RsmqQueue<'a> {
+    priority: i16,
+    tag: String,
+    w_id: &'a str,
+}
+
+fn parse_rsmq_queue_name(queue: &str) -> Option<RsmqQueue<'_>> {
+    let mut parts = queue
+        .strip_prefix(RSMQ_MAIN_QUEUE)?
+        .strip_prefix('_')?
+        .splitn(3, '_');
+    let priority = parts.next()?.parse().ok()?;
+    let tag = String::from_utf8(hex::decode(parts.next()?).ok()?).ok()?;
+    let w_id = parts.next()?;
+    Some(RsmqQueue { priority, tag, w_id })
+}
+
+/* Concurrency keys are scoped to the workspace. The key defaults to the path of the script or
+ * flow, and the `$args[name]` occurrences of a custom key are replaced by the value of the
+ * corresponding argument, e.g. to limit the concurrency per customer */
+fn concurrency_key(
+    w_id: &str,
+    custom_key: Option<&str>,
+    script_path: Option<&str>,
+    job_id: Uuid,
+    args: &serde_json::Value,
+) -> String {
+    let mut key = match (custom_key, script_path) {
+        (Some(key), _) => key.to_string(),
+        (None, Some(path)) => path.to_string(),
+        (None, None) => job_id.to_string(),
+    };
+    if custom_key.is_some() {
+        for (name, value) in args.as_object().into_iter().flatten() {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                v => v.to_string(),
+            };
+            key = key.replace(&format!("$args[{name}]"), &value);
+        }
+    }
+    format!("{w_id}/{key}").chars().take(255).collect()
+}
 
 pub async fn cancel_job<'c, R: rsmq_async::RsmqConnection + Clone>(
     username: &str,
@@ -79,9 +201,9 @@ pub async fn cancel_job<'c, R: rsmq_async::RsmqConnection + Clone>(
     rsmq: Option<R>,
     force_rerun: bool,
 ) -> error::Result<(Transaction<'c, Postgres>, Option<Uuid>)> {
-    let job_option = sqlx::query_scalar!(
+    let canceled = sqlx::query!(
         "UPDATE queue SET  canceled = true, canceled_by = $1, canceled_reason = $2, scheduled_for = now(), suspend = 0, running = CASE WHEN $3 THEN false ELSE running END  WHERE id = $4 \
-         AND workspace_id = $5 RETURNING id",
+         AND workspace_id = $5 RETURNING id, tag, priority",
         username,
         reason,
         force_rerun,
@@ -90,11 +212,13 @@ pub async fn cancel_job<'c, R: rsmq_async::RsmqConnection + Clone>(
     )
     .fetch_optional(&mut tx)
     .await?;
-    if let Some(mut rsmq) = rsmq {
-        rsmq.change_message_visibility(RSMQ_MAIN_QUEUE, &id.to_string(), 0)
+    if let (Some(mut rsmq), Some(canceled)) = (rsmq, canceled.as_ref()) {
+        let queue = rsmq_queue_name(w_id, &canceled.tag, canceled.priority);
+        rsmq.change_message_visibility(&queue, &id.to_string(), 0)
             .await
             .map_err(|e| anyhow::anyhow!(e))?;
     }
+    let job_option = canceled.map(|r| r.id);
 
     let mut jobs = job_option.map(|j| vec![j]).unwrap_or_default();
     while !jobs.is_empty() {
@@ -121,40 +245,53 @@ pub async fn pull<R: rsmq_async::RsmqConnection + Clone>(
     blacklist_workspaces: Option<Vec<String>>,
     rsmq: Option<R>,
 ) -> windmill_common::error::Result<Option<QueuedJob>> {
-    let mut workspaces_filter = String::new();
-    if let Some(whitelist) = whitelist_workspaces {
-        workspaces_filter.push_str(&format!(
-            " AND workspace_id IN ({})",
-            whitelist
-                .into_iter()
-                .map(|x| format!("'{x}'"))
-                .collect::<Vec<String>>()
-                .join(",")
-        ));
-        if let Some(_rsmq) = rsmq {
-            todo!("REDIS: Implement workspace filters for redis");
-        }
-    }
-    if let Some(blacklist) = blacklist_workspaces {
-        workspaces_filter.push_str(&format!(
-            " AND workspace_id NOT IN ({})",
-            blacklist
-                .into_iter()
-                .map(|x| format!("'{x}'"))
-                .collect::<Vec<String>>()
-                .join(",")
-        ));
-        if let Some(_rsmq) = rsmq {
-            todo!("REDIS: Implement workspace filters for redis");
+    loop {
+        let job = pull_next(
+            db,
+            whitelist_workspaces.as_ref(),
+            blacklist_workspaces.as_ref(),
+            rsmq.clone(),
+        )
+        .await?;
+
+        let job = match job {
+            Some(job) if concurrency_limit_reached(db, &job).await? => {
+                delay_for_concurrency(&job, rsmq.clone()).await?;
+                continue;
+            }
+            job => job,
+        };
+
+        if job.is_some() && *METRICS_ENABLED {
+            QUEUE_PULL_COUNT.inc();
         }
+
+        return Ok(job);
     }
+}
 
+async fn pull_next<R: rsmq_async::RsmqConnection + Clone>(
+    db: &Pool<Postgres>,
+    whitelist_workspaces: Option<&Vec<String>>,
+    blacklist_workspaces: Option<&Vec<String>>,
+    rsmq: Option<R>,
+) -> windmill_common::error::Result<Option<QueuedJob>> {
     let job: Option<QueuedJob> = if let Some(mut rsmq) = rsmq {
+        let queues =
+            rsmq_queues_to_poll(&mut rsmq, whitelist_workspaces, blacklist_workspaces).await?;
+
         // TODO: REDIS: Race conditions / replace last_ping
-        let msg = rsmq
-            .pop_message::<Vec<u8>>(RSMQ_MAIN_QUEUE)
-            .await
-            .map_err(|e| anyhow::anyhow!(e))?;
+        let mut msg = None;
+        for queue in queues {
+            match rsmq.pop_message::<Vec<u8>>(&queue).await {
+                Ok(Some(m)) => {
+                    msg = Some(m);
+                    break;
+                }
+                Ok(None) | Err(rsmq_async::RsmqError::QueueNotFound) => (),
+                Err(e) => return Err(anyhow::anyhow!(e).into()),
+            }
+        }
 
         if let Some(msg) = msg {
             let uuid = Uuid::from_bytes_le(
@@ -163,22 +300,51 @@ pub async fn pull<R: rsmq_async::RsmqConnection + Clone>(
                     .map_err(|_| anyhow::anyhow!("Failed to parsed Redis message"))?,
             );
 
-            sqlx::query_as::<_, QueuedJob>(
+            /* the tag is checked again in case the message was sent to the queue of another tag */
+            let job = sqlx::query_as::<_, QueuedJob>(&format!(
                 "UPDATE queue
             SET running = true
             , started_at = coalesce(started_at, now())
             , last_ping = now()
             , suspend_until = null
-            WHERE id = $1
+            WHERE id = $1 {}
             RETURNING *",
-            )
+                *ACCEPTED_TAGS_FILTER
+            ))
             .bind(uuid)
             .fetch_optional(db)
-            .await?
+            .await?;
+
+            if job.is_none() {
+                requeue_rsmq_message(db, &mut rsmq, uuid).await?;
+            }
+            job
         } else {
             None
         }
     } else {
+        let mut workspaces_filter = String::new();
+        if let Some(whitelist) = whitelist_workspaces {
+            workspaces_filter.push_str(&format!(
+                " AND workspace_id IN ({})",
+                whitelist
+                    .iter()
+                    .map(|x| format!("'{x}'"))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            ));
+        }
+        if let Some(blacklist) = blacklist_workspaces {
+            workspaces_filter.push_str(&format!(
+                " AND workspace_id NOT IN ({})",
+                blacklist
+                    .iter()
+                    .map(|x| format!("'{x}'"))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            ));
+        }
+
         let accepted_tags_filter = &*ACCEPTED_TAGS_FILTER;
         /* Jobs can be started if they:
          * - haven't been started before,
@@ -186,7 +352,8 @@ pub async fn pull<R: rsmq_async::RsmqConnection + Clone>(
          * - are flows with a step that needed resume,
          *   suspend_until is non-null
          *   and suspend = 0 when the resume messages are received
-         *   or suspend_until <= now() if it has timed out */
+         *   or suspend_until <= now() if it has timed out
+         * Jobs of higher priority are started first, a job without priority has priority 0 */
         sqlx::query_as::<_, QueuedJob>(&format!(
             "UPDATE queue
             SET running = true
@@ -203,7 +370,7 @@ pub async fn pull<R: rsmq_async::RsmqConnection + Clone>(
                             OR suspend_until <= now()))) 
                     {workspaces_filter}
                     {accepted_tags_filter}
-                ORDER BY scheduled_for
+                ORDER BY COALESCE(priority, 0) DESC, scheduled_for
                 FOR UPDATE SKIP LOCKED
                 LIMIT 1
             )
@@ -213,11 +380,212 @@ pub async fn pull<R: rsmq_async::RsmqConnection + Clone>(
         .await?
     };
 
-    if job.is_some() && *METRICS_ENABLED {
-        QUEUE_PULL_COUNT.inc();
+    Ok(job)
+}
+
+/* A popped message whose job is not accepted by this worker is sent back to the queue of the job
+ * instead of being lost. Jobs that do not exist anymore or that are already running are dropped */
+async fn requeue_rsmq_message<R: rsmq_async::RsmqConnection>(
+    db: &Pool<Postgres>,
+    rsmq: &mut R,
+    id: Uuid,
+) -> error::Result<()> {
+    let job = sqlx::query!(
+        "SELECT workspace_id, tag, priority FROM queue WHERE id = $1 AND running = false",
+        id
+    )
+    .fetch_optional(db)
+    .await?;
+    if let Some(job) = job {
+        let queue = rsmq_queue_name(&job.workspace_id, &job.tag, job.priority);
+        tracing::warn!(
+            "job {id} with tag {} popped by a worker not accepting it, sent back to {queue}",
+            job.tag
+        );
+        RedisOp::SendMessage(queue, id.to_bytes_le().to_vec(), None)
+            .apply(rsmq)
+            .await
+            .map_err(|e| anyhow::anyhow!(e))?;
     }
+    Ok(())
+}
 
-    Ok(job)
+/* A job only waits for a concurrency slot when it is started for the first time: flows resumed
+ * after a suspend already hold their slot, and their started_at is then older than last_ping.
+ * Checks for the same key are serialized with an advisory lock, so that two jobs started at the
+ * same time cannot both take the last slot nor both give it up */
+async fn concurrency_limit_reached(db: &Pool<Postgres>, job: &QueuedJob) -> error::Result<bool> {
+    let (key, limit) = match (job.concurrency_key.as_ref(), job.concurrent_limit) {
+        (Some(key), Some(limit)) if job.started_at == job.last_ping => (key, limit),
+        _ => return Ok(false),
+    };
+
+    let mut tx = db.begin().await?;
+    /* not checked by query!, sqlx has no type for the void returned by the lock */
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind(key)
+        .execute(&mut tx)
+        .await?;
+    let count = sqlx::query_scalar!(
+        r#"SELECT
+            (SELECT COUNT(*) FROM queue
+             WHERE concurrency_key = $1 AND running = true AND id != $2
+               AND ($3::INTEGER IS NULL OR started_at > now() - make_interval(secs => $3)))
+          + (SELECT COUNT(*) FROM completed_job
+             WHERE $3::INTEGER IS NOT NULL AND concurrency_key = $1
+               AND started_at > now() - make_interval(secs => $3)) as "count!""#,
+        key,
+        job.id,
+        job.concurrency_time_window_s
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    let reached = count >= limit as i64;
+    if reached {
+        sqlx::query!(
+            "UPDATE queue SET running = false, started_at = null, last_ping = null, \
+             scheduled_for = now() + make_interval(secs => $2) WHERE id = $1",
+            job.id,
+            *CONCURRENCY_LIMIT_RETRY_DELAY_S as f64
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    Ok(reached)
+}
+
+/* The job has already been put back in the queue by concurrency_limit_reached, with redis it
+ * also needs to be sent again since popping it removed it from its rsmq queue */
+async fn delay_for_concurrency<R: rsmq_async::RsmqConnection + Clone>(
+    job: &QueuedJob,
+    rsmq: Option<R>,
+) -> error::Result<()> {
+    tracing::info!(
+        "job {} is waiting for a concurrency slot of {}",
+        job.id,
+        job.concurrency_key.as_deref().unwrap_or_default()
+    );
+    if let Some(mut rsmq) = rsmq {
+        let queue = rsmq_queue_name(&job.workspace_id, &job.tag, job.priority);
+        rsmq.send_message(
+            &queue,
+            job.id.to_bytes_le().to_vec(),
+            Some(*CONCURRENCY_LIMIT_RETRY_DELAY_S),
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!(e))?;
+    }
+    Ok(())
+}
+
+/* The queues a worker polls are all the existing queues of its accepted tags, restricted to the
+ * whitelisted workspaces if a whitelist is set and minus the ones of the blacklisted workspaces.
+ * Queues of higher priority come first. Within a priority, the starting queue is rotated on every
+ * pull so that a busy workspace cannot starve the others. The existing queues are listed again
+ * every RSMQ_QUEUES_REFRESH_INTERVAL only, so a new queue can take that long to be polled */
+async fn rsmq_queues_to_poll<R: rsmq_async::RsmqConnection>(
+    rsmq: &mut R,
+    whitelist_workspaces: Option<&Vec<String>>,
+    blacklist_workspaces: Option<&Vec<String>>,
+) -> error::Result<Vec<String>> {
+    let cached = RSMQ_QUEUES
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|(listed_at, _)| listed_at.elapsed() < RSMQ_QUEUES_REFRESH_INTERVAL)
+        .map(|(_, queues)| queues.clone());
+    let queues = match cached {
+        Some(queues) => queues,
+        None => {
+            let queues = rsmq.list_queues().await.map_err(|e| anyhow::anyhow!(e))?;
+            *RSMQ_QUEUES.lock().unwrap() = Some((std::time::Instant::now(), queues.clone()));
+            queues
+        }
+    };
+    let offset = RSMQ_POLL_OFFSET.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    Ok(filter_rsmq_queues(
+        queues,
+        whitelist_workspaces,
+        blacklist_workspaces,
+        offset,
+    ))
+}
+
+fn filter_rsmq_queues(
+    queues: Vec<String>,
+    whitelist_workspaces: Option<&Vec<String>>,
+    blacklist_workspaces: Option<&Vec<String>>,
+    offset: usize,
+) -> Vec<String> {
+    let mut queues = queues
+        .into_iter()
+        .filter_map(|queue| {
+            let parsed = parse_rsmq_queue_name(&queue)?;
+            let accepted = is_tag_accepted(&parsed.tag)
+                && whitelist_workspaces
+                    .map(|wl| wl.iter().any(|w| w == parsed.w_id))
+                    .unwrap_or(true)
+                && !blacklist_workspaces
+                    .map(|bl| bl.iter().any(|b| b == parsed.w_id))
+                    .unwrap_or(false);
+            let priority = parsed.priority;
+            accepted.then_some((priority, queue))
+        })
+        .collect::<Vec<_>>();
+
+    if !queues.is_empty() {
+        let len = queues.len();
+        queues.rotate_left(offset % len);
+    }
+    // stable sort to keep the rotation within a same priority
+    queues.sort_by_key(|(priority, _)| std::cmp::Reverse(*priority));
+
+    queues.into_iter().map(|(_, queue)| queue).collect()
+}
+
+/* Before the queues were partitioned, every job was sent to a single main_queue that no worker
+ * polls anymore. Its messages are moved to the queue of their job. Messages that are still
+ * delayed are not visible yet and are moved by a later call, so this is run periodically */
+pub async fn drain_rsmq_main_queue<R: rsmq_async::RsmqConnection>(
+    db: &Pool<Postgres>,
+    rsmq: &mut R,
+) -> error::Result<()> {
+    loop {
+        let msg = match rsmq
+            .receive_message::<Vec<u8>>(RSMQ_MAIN_QUEUE, Some(30))
+            .await
+        {
+            Ok(Some(msg)) => msg,
+            Ok(None) | Err(rsmq_async::RsmqError::QueueNotFound) => return Ok(()),
+            Err(e) => return Err(anyhow::anyhow!(e).into()),
+        };
+        let id = Uuid::from_bytes_le(
+            msg.message
+                .clone()
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Failed to parsed Redis message"))?,
+        );
+        let job = sqlx::query!(
+            "SELECT workspace_id, tag, priority, scheduled_for FROM queue WHERE id = $1",
+            id
+        )
+        .fetch_optional(db)
+        .await?;
+        if let Some(job) = job {
+            let queue = rsmq_queue_name(&job.workspace_id, &job.tag, job.priority);
+            tracing::info!("moving job {id} from {RSMQ_MAIN_QUEUE} to {queue}");
+            RedisOp::SendMessage(queue, msg.message, Some(job.scheduled_for))
+                .apply(rsmq)
+                .await
+                .map_err(|e| anyhow::anyhow!(e))?;
+        }
+        rsmq.delete_message(RSMQ_MAIN_QUEUE, &msg.id)
+            .await
+            .map_err(|e| anyhow::anyhow!(e))?;
+    }
 }
 
 pub async fn get_result_by_id(
@@ -256,16 +624,23 @@ pub async fn get_result_by_id(
             .into_iter()
             .filter_map(|x| x)
             .collect::<Vec<serde_json::Value>>();
-            serde_json::json!(rows)
+            let mut results = Vec::with_capacity(rows.len());
+            for row in rows {
+                results.push(resolve_result(row).await?);
+            }
+            serde_json::json!(results)
         }
-        JobResult::SingleJob(x) => sqlx::query_scalar!(
-            "SELECT result FROM completed_job WHERE id = $1 AND workspace_id = $2",
-            x,
-            w_id,
+        JobResult::SingleJob(x) => resolve_optional_result(
+            sqlx::query_scalar!(
+                "SELECT result FROM completed_job WHERE id = $1 AND workspace_id = $2",
+                x,
+                w_id,
+            )
+            .fetch_optional(&db)
+            .await?
+            .flatten(),
         )
-        .fetch_optional(&db)
         .await?
-        .flatten()
         .unwrap_or(serde_json::Value::Null),
     };
 
@@ -281,6 +656,8 @@ pub async fn delete_job<'c, R: rsmq_async::RsmqConnection + Clone + Send>(
     if *METRICS_ENABLED {
         QUEUE_DELETE_COUNT.inc();
     }
+    /* the rsmq message of the job was removed from its queue when it was popped, and the
+     * message of a job deleted before that is dropped once popped since it has no queue row */
     let job_removed = sqlx::query_scalar!(
         "DELETE FROM queue WHERE workspace_id = $1 AND id = $2 RETURNING 1",
         w_id,
@@ -311,6 +688,91 @@ pub async fn get_queued_job<'c>(
     Ok(r)
 }
 
+/* a while loop runs its modules at least once before its stop condition is checked, so it cannot
+ * be limited to 0 iterations. Nested modules are checked too so that the flow is rejected when
+ * it is saved rather than when the loop is reached */
+fn check_whileloops(modules: &[FlowModule]) -> error::Result<()> {
+    for module in modules {
+        match &module.value {
+            FlowModuleValue::WhileloopFlow { max_iterations: Some(0), .. } => {
+                return Err(Error::BadRequest(format!(
+                    "max_iterations of while loop module {} must be at least 1",
+                    module.id
+                )));
+            }
+            FlowModuleValue::WhileloopFlow { modules, .. }
+            | FlowModuleValue::ForloopFlow { modules, .. } => check_whileloops(modules)?,
+            FlowModuleValue::BranchOne { branches, default } => {
+                for branch in branches {
+                    check_whileloops(&branch.modules)?;
+                }
+                check_whileloops(default)?;
+            }
+            FlowModuleValue::BranchAll { branches, .. } => {
+                for branch in branches {
+                    check_whileloops(&branch.modules)?;
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/* the settings of a script that apply to each of its jobs */
+struct ScriptRunSettings {
+    language: ScriptLang,
+    concurrency_limit: Option<serde_json::Value>,
+    timeout: Option<i32>,
+    cache_ttl: Option<i32>,
+    memory_limit_mb: Option<i32>,
+    cpu_limit_millis: Option<i32>,
+}
+
+/*
+ * The value of the latest version of a flow and the id of that version, stored as the flow_version
+ * of its jobs so that they keep running the version they were pushed with
+ */
+async fn get_flow_version<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
+    tx: &mut QueueTransaction<'c, R>,
+    workspace_id: &str,
+    path: &str,
+) -> Result<(FlowValue, Option<i64>), Error> {
+    let flow = sqlx::query!(
+        "SELECT value, versions[array_upper(versions, 1)] as version FROM flow WHERE path = $1 AND workspace_id = $2",
+        path,
+        workspace_id
+    )
+    .fetch_optional(tx.transaction_mut())
+    .await?
+    .ok_or_else(|| Error::InternalErr(format!("not found flow at path {:?}", path)))?;
+    let version = flow.version;
+    let value = serde_json::from_value::<FlowValue>(flow.value).map_err(|err| {
+        Error::InternalErr(format!(
+            "could not convert json to flow for {path}: {err:?}"
+        ))
+    })?;
+    Ok((value, version))
+}
+
+pub const DEFAULT_TIMEOUT: u64 = 900;
+
+/* premium workspaces of the cloud may run their jobs 6 times longer */
+pub async fn max_timeout<'c, E: sqlx::Executor<'c, Database = Postgres>>(
+    _db: E,
+    _w_id: &str,
+) -> Result<u64, Error> {
+    #[cfg(feature = "enterprise")]
+    if *CLOUD_HOSTED
+        && sqlx::query_scalar!("SELECT premium FROM workspace WHERE id = $1", _w_id)
+            .fetch_one(_db)
+            .await?
+    {
+        return Ok(*MAX_TIMEOUT * 6);
+    }
+    Ok(*MAX_TIMEOUT)
+}
+
 // #[instrument(level = "trace", skip_all)]
 pub async fn push<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
     mut tx: QueueTransaction<'c, R>,
@@ -330,7 +792,25 @@ pub async fn push<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
     pre_run_error: Option<&windmill_common::error::Error>,
     visible_to_owner: bool,
     mut tag: Option<String>,
+    priority: Option<i16>,
+    mut timeout: Option<i32>,
+    mut cache_ttl: Option<i32>,
 ) -> Result<(Uuid, QueueTransaction<'c, R>), Error> {
+    if matches!(
+        job_payload,
+        JobPayload::Flow(_) | JobPayload::RawFlow { .. }
+    ) {
+        /* a flow is never killed as a whole, each of its steps has its own timeout */
+        timeout = None;
+    } else if let Some(timeout) = timeout {
+        let max_timeout = max_timeout(&mut tx, workspace_id).await?;
+        if timeout as u64 > max_timeout {
+            Err(Error::BadRequest(format!(
+                "timeout of {timeout} seconds exceeds the maximum of {max_timeout} seconds"
+            )))?
+        }
+    }
+
     let args_json = serde_json::Value::Object(args);
     let job_id: Uuid = if let Some(job_id) = job_id {
         let conflicting_id = sqlx::query_scalar!(
@@ -439,11 +919,19 @@ pub async fn push<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
         }
     }
 
+    let mut concurrency_limit: Option<ConcurrencyLimit> = None;
+    /* resource limits are only set on scripts, the worker caps them by its own limits */
+    let mut memory_limit_mb: Option<i32> = None;
+    let mut cpu_limit_millis: Option<i32> = None;
+    let mut flow_version: Option<i64> = None;
     let (script_hash, script_path, raw_code_tuple, job_kind, mut raw_flow, language) =
         match job_payload {
             JobPayload::ScriptHash { hash, path } => {
-                let language = sqlx::query_scalar!(
-                    "SELECT language as \"language: ScriptLang\" FROM script WHERE hash = $1 AND workspace_id = $2",
+                let script = sqlx::query_as!(
+                    ScriptRunSettings,
+                    "SELECT language as \"language: ScriptLang\", concurrency_limit, timeout, \
+                     cache_ttl, memory_limit_mb, cpu_limit_millis FROM script WHERE hash = $1 AND \
+                     workspace_id = $2",
                     hash.0,
                     workspace_id
                 )
@@ -454,13 +942,26 @@ pub async fn push<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
                         "fetching language for hash {hash} in {workspace_id}: {e}"
                     ))
                 })?;
+                concurrency_limit = script
+                    .concurrency_limit
+                    .map(serde_json::from_value::<ConcurrencyLimit>)
+                    .transpose()
+                    .map_err(|e| {
+                        Error::InternalErr(format!(
+                            "invalid concurrency limit for hash {hash} in {workspace_id}: {e}"
+                        ))
+                    })?;
+                timeout = timeout.or(script.timeout);
+                cache_ttl = cache_ttl.or(script.cache_ttl);
+                memory_limit_mb = script.memory_limit_mb;
+                cpu_limit_millis = script.cpu_limit_millis;
                 (
                     Some(hash.0),
                     Some(path),
                     None,
                     JobKind::Script,
                     None,
-                    Some(language),
+                    Some(script.language),
                 )
             }
             JobPayload::ScriptHub { path } => {
@@ -491,19 +992,8 @@ pub async fn push<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
                 Some(language),
             ),
             JobPayload::FlowDependencies { path } => {
-                let value_json = sqlx::query_scalar!(
-                    "SELECT value FROM flow WHERE path = $1 AND workspace_id = $2",
-                    path,
-                    workspace_id
-                )
-                .fetch_optional(&mut tx)
-                .await?
-                .ok_or_else(|| Error::InternalErr(format!("not found flow at path {:?}", path)))?;
-                let value = serde_json::from_value::<FlowValue>(value_json).map_err(|err| {
-                    Error::InternalErr(format!(
-                        "could not convert json to flow for {path}: {err:?}"
-                    ))
-                })?;
+                let (value, version) = get_flow_version(&mut tx, workspace_id, &path).await?;
+                flow_version = version;
                 (
                     None,
                     Some(path),
@@ -517,19 +1007,8 @@ pub async fn push<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
                 (None, path, None, JobKind::FlowPreview, Some(value), None)
             }
             JobPayload::Flow(flow) => {
-                let value_json = sqlx::query_scalar!(
-                    "SELECT value FROM flow WHERE path = $1 AND workspace_id = $2",
-                    flow,
-                    workspace_id
-                )
-                .fetch_optional(&mut tx)
-                .await?
-                .ok_or_else(|| Error::InternalErr(format!("not found flow at path {:?}", flow)))?;
-                let value = serde_json::from_value::<FlowValue>(value_json).map_err(|err| {
-                    Error::InternalErr(format!(
-                        "could not convert json to flow for {flow}: {err:?}"
-                    ))
-                })?;
+                let (value, version) = get_flow_version(&mut tx, workspace_id, &flow).await?;
+                flow_version = version;
                 (None, Some(flow), None, JobKind::Flow, Some(value), None)
             }
             JobPayload::Identity => (None, None, None, JobKind::Identity, None, None),
@@ -538,6 +1017,31 @@ pub async fn push<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
     let is_running = same_worker;
     if let Some(flow) = raw_flow.as_ref() {
         same_worker = same_worker || flow.same_worker;
+        if job_kind != JobKind::FlowDependencies {
+            concurrency_limit = flow.concurrency_limit.clone();
+        }
+
+        if let Some(early_return) = &flow.early_return {
+            match flow.modules.iter().find(|m| &m.id == early_return) {
+                None => Err(Error::BadRequest(format!(
+                    "early return module {early_return} is not a top-level module of the flow"
+                )))?,
+                /* the result of a loop or a branch is only known once all of its jobs are done */
+                Some(FlowModule {
+                    value:
+                        FlowModuleValue::ForloopFlow { .. }
+                        | FlowModuleValue::WhileloopFlow { .. }
+                        | FlowModuleValue::BranchOne { .. }
+                        | FlowModuleValue::BranchAll { .. },
+                    ..
+                }) => Err(Error::BadRequest(format!(
+                    "early return module {early_return} cannot be a loop or a branch"
+                )))?,
+                _ => (),
+            }
+        }
+
+        check_whileloops(&flow.modules)?;
 
         for module in flow.modules.iter() {
             if let Some(retry) = &module.retry {
@@ -572,6 +1076,10 @@ pub async fn push<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
                 retry: None,
                 sleep: None,
                 suspend: None,
+                priority: None,
+                timeout: None,
+                cache_ttl: None,
+                on_error: None,
             });
             raw_flow = Some(FlowValue { modules, ..flow.clone() });
         }
@@ -583,7 +1091,20 @@ pub async fn push<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
 
     let flow_status = raw_flow.as_ref().map(FlowStatus::new);
 
-    let tag = if job_kind == JobKind::Dependencies || job_kind == JobKind::FlowDependencies {
+    /* bash scripts declaring requirements are routed to the workers that have them, and so is
+     * the dependency job checking them */
+    let requirements_tag = if language == Some(ScriptLang::Bash) {
+        raw_code
+            .as_ref()
+            .and_then(|code| parse_bash_requirements(code).ok())
+            .and_then(|requirements| bash_requirements_tag(&requirements))
+    } else {
+        None
+    };
+
+    let tag = if job_kind == JobKind::Dependencies && requirements_tag.is_some() {
+        requirements_tag.unwrap()
+    } else if job_kind == JobKind::Dependencies || job_kind == JobKind::FlowDependencies {
         "dependency".to_string()
     } else if job_kind == JobKind::Flow || job_kind == JobKind::FlowPreview {
         "flow".to_string()
@@ -596,7 +1117,7 @@ pub async fn push<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
         if tag == Some("".to_string()) {
             tag = None;
         }
-        tag.unwrap_or_else(|| {
+        tag.or(requirements_tag).unwrap_or_else(|| {
             language
                 .as_ref()
                 .map(|x| x.as_str())
@@ -605,12 +1126,36 @@ pub async fn push<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
         })
     };
 
+    let rsmq_queue = rsmq_queue_name(workspace_id, &tag, priority);
+
+    if matches!(&concurrency_limit, Some(limit) if limit.max_concurrent < 1) {
+        Err(Error::BadRequest(
+            "max_concurrent of a concurrency limit must be at least 1".to_string(),
+        ))?
+    }
+    if matches!(timeout, Some(timeout) if timeout < 1) {
+        Err(Error::BadRequest(
+            "timeout must be at least 1 second".to_string(),
+        ))?
+    }
+
+    let concurrency_key = concurrency_limit.as_ref().map(|limit| {
+        concurrency_key(
+            workspace_id,
+            limit.key.as_deref(),
+            script_path.as_deref(),
+            job_id,
+            &args_json,
+        )
+    });
+
     let uuid = sqlx::query_scalar!(
         "INSERT INTO queue
             (workspace_id, id, running, parent_job, created_by, permissioned_as, scheduled_for, 
                 script_hash, script_path, raw_code, raw_lock, args, job_kind, schedule_path, raw_flow, \
-         flow_status, is_flow_step, language, started_at, same_worker, pre_run_error, email, visible_to_owner, root_job, tag)
-            VALUES ($1, $2, $3, $4, $5, $6, COALESCE($7, now()), $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, CASE WHEN $3 THEN now() END, $19, $20, $21, $22, $23, $24) \
+         flow_status, is_flow_step, language, started_at, same_worker, pre_run_error, email, visible_to_owner, root_job, tag, priority, \
+         concurrency_key, concurrent_limit, concurrency_time_window_s, timeout, cache_ttl, memory_limit_mb, cpu_limit_millis, flow_version)
+            VALUES ($1, $2, $3, $4, $5, $6, COALESCE($7, now()), $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, CASE WHEN $3 THEN now() END, $19, $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30, $31, $32, $33) \
          RETURNING id",
         workspace_id,
         job_id,
@@ -635,7 +1180,16 @@ pub async fn push<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
         email,
         visible_to_owner,
         root_job,
-        tag
+        tag,
+        priority,
+        concurrency_key,
+        concurrency_limit.as_ref().map(|x| x.max_concurrent),
+        concurrency_limit.as_ref().and_then(|x| x.time_window_s),
+        timeout,
+        cache_ttl,
+        memory_limit_mb,
+        cpu_limit_millis,
+        flow_version
     )
     .fetch_one(&mut tx)
     .await
@@ -679,7 +1233,7 @@ pub async fn push<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
         .await?;
     }
     if let Some(ref mut rsmq) = tx.rsmq {
-        rsmq.send_message(job_id.to_bytes_le().to_vec(), scheduled_for_o);
+        rsmq.send_message(rsmq_queue, job_id.to_bytes_le().to_vec(), scheduled_for_o);
     }
 
     Ok((uuid, tx))
@@ -693,3 +1247,94 @@ pub fn canceled_job_to_result(job: &QueuedJob) -> serde_json::Value {
     let canceler = job.canceled_by.as_deref().unwrap_or_else(|| "unknown");
     serde_json::json!({"message": format!("Job canceled: {reason} by {canceler}"), "name": "Canceled", "reason": reason, "canceler": canceler})
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rsmq_queue_name() {
+        let queue = rsmq_queue_name("my_workspace", "deno", Some(2));
+        let parsed = parse_rsmq_queue_name(&queue).unwrap();
+        assert_eq!(parsed.priority, 2);
+        assert_eq!(parsed.tag, "deno");
+        assert_eq!(parsed.w_id, "my_workspace");
+
+        let queue = rsmq_queue_name("w", "deno", None);
+        assert_eq!(parse_rsmq_queue_name(&queue).unwrap().priority, 0);
+
+        assert!(parse_rsmq_queue_name(RSMQ_MAIN_QUEUE).is_none());
+        assert!(parse_rsmq_queue_name("other_queue_0_deno_w").is_none());
+        assert!(parse_rsmq_queue_name("main_queue_high_deno_w").is_none());
+    }
+
+    #[test]
+    fn test_filter_rsmq_queues() {
+        let a = rsmq_queue_name("a", "deno", None);
+        let b = rsmq_queue_name("b", "deno", None);
+        let c = rsmq_queue_name("c", "python3", Some(1));
+        let queues = vec![
+            a.clone(),
+            b.clone(),
+            c.clone(),
+            rsmq_queue_name("a", "gpu", None),
+            RSMQ_MAIN_QUEUE.to_string(),
+        ];
+
+        /* higher priorities first, then rotated by the offset */
+        assert_eq!(
+            filter_rsmq_queues(queues.clone(), None, None, 0),
+            vec![c.clone(), a.clone(), b.clone()]
+        );
+        assert_eq!(
+            filter_rsmq_queues(queues.clone(), None, None, 1),
+            vec![c.clone(), b.clone(), a.clone()]
+        );
+
+        let whitelist = vec!["a".to_string()];
+        assert_eq!(
+            filter_rsmq_queues(queues.clone(), Some(&whitelist), None, 0),
+            vec![a.clone()]
+        );
+        let blacklist = vec!["a".to_string(), "c".to_string()];
+        assert_eq!(
+            filter_rsmq_queues(queues.clone(), None, Some(&blacklist), 0),
+            vec![b.clone()]
+        );
+        assert_eq!(
+            filter_rsmq_queues(queues, Some(&whitelist), Some(&blacklist), 0),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_filter_rsmq_bash_requirements_queues() {
+        let tag = format!("{BASH_REQUIREMENTS_TAG_PREFIX}curl,jq");
+        let queue = rsmq_queue_name("a", &tag, None);
+        assert_eq!(
+            filter_rsmq_queues(vec![queue.clone()], None, None, 0),
+            vec![queue]
+        );
+
+        let accepted = vec!["curl".to_string(), "jq".to_string()];
+        assert!(are_bash_binaries_accepted("curl,jq", &accepted));
+        assert!(are_bash_binaries_accepted("jq", &accepted));
+        assert!(!are_bash_binaries_accepted("curl,wget", &accepted));
+        assert!(!are_bash_binaries_accepted("curl-jq", &accepted));
+    }
+
+    #[test]
+    fn test_rsmq_tag_is_lossless() {
+        for tag in ["gpu.large", "gpu_large", "gpu-large", "gpu:large"] {
+            let queue = rsmq_queue_name("w", tag, None);
+            assert!(queue
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+            assert_eq!(parse_rsmq_queue_name(&queue).unwrap().tag, tag);
+        }
+        assert_ne!(
+            rsmq_queue_name("w", "gpu.large", None),
+            rsmq_queue_name("w", "gpu-large", None)
+        );
+    }
+}
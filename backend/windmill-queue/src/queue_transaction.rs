@@ -1,29 +1,39 @@
 use std::fmt::Debug;
 
 use futures_core::{future::BoxFuture, stream::BoxStream};
-use rsmq_async::{RedisBytes, RsmqConnection};
+use rsmq_async::RsmqConnection;
 use sqlx::{Postgres, Transaction};
 
 pub enum RedisOp {
-    SendMessage(RedisBytes, Option<chrono::DateTime<chrono::Utc>>),
-    DeleteMessage(String),
+    SendMessage(String, Vec<u8>, Option<chrono::DateTime<chrono::Utc>>),
+    DeleteMessage(String, String),
 }
 
 impl RedisOp {
     pub async fn apply<R: RsmqConnection>(self, rsmq: &mut R) -> Result<(), rsmq_async::RsmqError> {
         match self {
-            RedisOp::SendMessage(bytes, time) => {
-                rsmq.send_message(
-                    "main_queue",
-                    bytes,
-                    time.map(|t| (t - chrono::Utc::now()).num_seconds())
-                        .and_then(|e| e.try_into().ok()),
-                )
-                .await?;
-            }
-            RedisOp::DeleteMessage(id) => {
-                rsmq.delete_message("main_queue", &id).await?;
+            RedisOp::SendMessage(queue, bytes, time) => {
+                let delay = time
+                    .map(|t| (t - chrono::Utc::now()).num_seconds())
+                    .and_then(|e| e.try_into().ok());
+                match rsmq.send_message(&queue, bytes.clone(), delay).await {
+                    // queues are created lazily the first time a job is pushed to them
+                    Err(rsmq_async::RsmqError::QueueNotFound) => {
+                        match rsmq.create_queue(&queue, None, None, None).await {
+                            Ok(_) | Err(rsmq_async::RsmqError::QueueExists) => (),
+                            Err(e) => return Err(e),
+                        }
+                        rsmq.send_message(&queue, bytes, delay).await?;
+                    }
+                    r => {
+                        r?;
+                    }
+                }
             }
+            RedisOp::DeleteMessage(queue, id) => match rsmq.delete_message(&queue, &id).await {
+                Ok(_) | Err(rsmq_async::RsmqError::QueueNotFound) => (),
+                Err(e) => return Err(e),
+            },
         };
 
         Ok(())
@@ -50,17 +60,18 @@ impl<R: RsmqConnection> RedisTransaction<R> {
         Ok(())
     }
 
-    pub fn send_message<E: Into<RedisBytes>>(
+    pub fn send_message<E: Into<Vec<u8>>>(
         &mut self,
+        queue: String,
         bytes: E,
         delay_until: Option<chrono::DateTime<chrono::Utc>>,
     ) {
         self.queued_ops
-            .push(RedisOp::SendMessage(bytes.into(), delay_until))
+            .push(RedisOp::SendMessage(queue, bytes.into(), delay_until))
     }
 
-    pub fn delete_message(&mut self, id: String) {
-        self.queued_ops.push(RedisOp::DeleteMessage(id))
+    pub fn delete_message(&mut self, queue: String, id: String) {
+        self.queued_ops.push(RedisOp::DeleteMessage(queue, id))
     }
 }
 
@@ -8,13 +8,15 @@
 
 use crate::push;
 use crate::QueueTransaction;
-use sqlx::{query_scalar, Postgres, Transaction};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Postgres, Transaction};
+use std::collections::VecDeque;
 use std::str::FromStr;
 use windmill_common::jobs::JobPayload;
 use windmill_common::schedule::schedule_to_user;
 use windmill_common::{
     error::{self, Result},
-    schedule::Schedule,
+    schedule::{CatchUp, Schedule, MAX_CATCH_UP_TICKS},
     users::username_to_permissioned_as,
     utils::{now_from_db, StripPath},
 };
@@ -23,53 +25,238 @@ pub async fn push_scheduled_job<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
     mut tx: QueueTransaction<'c, R>,
     schedule: Schedule,
 ) -> Result<QueueTransaction<'c, R>> {
+    let ticks = if let Some(run_at) = schedule.run_at {
+        /* a one-shot schedule only runs once, at run_at */
+        if schedule
+            .last_tick
+            .map_or(false, |last_tick| last_tick >= run_at)
+        {
+            return Ok(tx);
+        }
+        vec![run_at]
+    } else {
+        let sched = cron::Schedule::from_str(&schedule.schedule)
+            .map_err(|e| error::Error::BadRequest(e.to_string()))?;
+
+        let tz = chrono_tz::Tz::from_str(&schedule.timezone)
+            .map_err(|e| error::Error::BadRequest(e.to_string()))?;
+
+        let now = now_from_db(&mut tx).await?;
+
+        /* a paused schedule resumes with the first tick after paused_until */
+        let start = schedule
+            .paused_until
+            .map_or(now, |paused_until| paused_until.max(now));
+        let next = sched
+            .after(&start.with_timezone(&tz))
+            .next()
+            .expect("a schedule should have a next event");
+
+        // Scheduled events must be stored in the database in UTC
+        let next = next.with_timezone(&chrono::Utc);
+
+        let mut ticks = missed_ticks(&schedule, &sched, &tz, now);
+        ticks.push(next);
+        ticks
+    };
+    let ticks = not_queued_ticks(&mut tx, &schedule, ticks).await?;
+
+    if ticks.is_empty() {
+        return Ok(tx);
+    }
+
+    let args = schedule_args(&schedule)?;
+    let (payload, tag) = schedule_payload(&mut tx, &schedule).await?;
+
+    sqlx::query!(
+        "UPDATE schedule SET error = NULL WHERE workspace_id = $1 AND path = $2",
+        &schedule.workspace_id,
+        &schedule.path
+    )
+    .execute(&mut tx)
+    .await?;
+
+    for tick in ticks {
+        tx = push_schedule_tick(
+            tx,
+            &schedule,
+            payload.clone(),
+            args.clone(),
+            tag.clone(),
+            tick,
+        )
+        .await?;
+    }
+    Ok(tx) // TODO: Bubble up pushed UUID from here
+}
+
+pub const MAX_BACKFILL_TICKS: usize = 1000;
+
+/*
+ * Enqueues a job for each tick of the schedule between from and to (both included, to being
+ * capped at now), passing the tick as the `tick_arg` arg. Ticks which already have a queued job
+ * are left out. Returns the ticks jobs were pushed for.
+ */
+pub async fn push_schedule_backfill<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
+    mut tx: QueueTransaction<'c, R>,
+    schedule: &Schedule,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    tick_arg: &str,
+) -> Result<(Vec<DateTime<Utc>>, QueueTransaction<'c, R>)> {
+    if schedule.run_at.is_some() {
+        return Err(error::Error::BadRequest(
+            "a one-shot schedule cannot be backfilled".to_string(),
+        ));
+    }
     let sched = cron::Schedule::from_str(&schedule.schedule)
         .map_err(|e| error::Error::BadRequest(e.to_string()))?;
 
     let tz = chrono_tz::Tz::from_str(&schedule.timezone)
         .map_err(|e| error::Error::BadRequest(e.to_string()))?;
 
-    let now = now_from_db(&mut tx).await?.with_timezone(&tz);
+    let to = to.min(now_from_db(&mut tx).await?);
+    let ticks = sched
+        .after(&(from - Duration::seconds(1)).with_timezone(&tz))
+        .map(|tick| tick.with_timezone(&Utc))
+        .take_while(|tick| *tick <= to)
+        .take(MAX_BACKFILL_TICKS + 1)
+        .collect::<Vec<_>>();
+    if ticks.len() > MAX_BACKFILL_TICKS {
+        return Err(error::Error::BadRequest(format!(
+            "a backfill cannot enqueue more than {MAX_BACKFILL_TICKS} jobs, use a shorter range"
+        )));
+    }
+    let ticks = not_queued_ticks(&mut tx, schedule, ticks).await?;
 
-    let next = sched
-        .after(&now)
-        .next()
-        .expect("a schedule should have a next event");
+    if ticks.is_empty() {
+        return Ok((ticks, tx));
+    }
 
-    // println!("next event ({:?}): {}", tz, next);
-    // println!("next event(UTC): {}", next.with_timezone(&chrono::Utc));
+    let args = schedule_args(schedule)?;
+    let (payload, tag) = schedule_payload(&mut tx, schedule).await?;
 
-    // Scheduled events must be stored in the database in UTC
-    let next = next.with_timezone(&chrono::Utc);
+    for tick in ticks.iter() {
+        let mut args = args.clone();
+        args.insert(
+            tick_arg.to_string(),
+            serde_json::Value::String(tick.to_rfc3339()),
+        );
+        tx = push_schedule_tick(tx, schedule, payload.clone(), args, tag.clone(), *tick).await?;
+    }
+    Ok((ticks, tx))
+}
 
-    let already_exists: bool = query_scalar!(
-        "SELECT EXISTS (SELECT 1 FROM queue WHERE workspace_id = $1 AND schedule_path = $2 AND scheduled_for = $3)",
-        &schedule.workspace_id,
-        &schedule.path,
-        next
-    )
-    .fetch_one(&mut tx)
-    .await?
-    .unwrap_or(false);
+/* Pushes again, with the same args and after the retry delay, the failed job of a schedule */
+pub async fn push_schedule_retry<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
+    mut tx: QueueTransaction<'c, R>,
+    schedule: &Schedule,
+    args: serde_json::Map<String, serde_json::Value>,
+    retry_in: std::time::Duration,
+) -> Result<QueueTransaction<'c, R>> {
+    let now = now_from_db(&mut tx).await?;
+    let retry_at = Duration::from_std(retry_in)
+        .ok()
+        .and_then(|retry_in| now.checked_add_signed(retry_in))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC);
+    let (payload, tag) = schedule_payload(&mut tx, schedule).await?;
+    push_schedule_tick(tx, schedule, payload, args, tag, retry_at).await
+}
 
-    if already_exists {
-        return Ok(tx);
+/*
+ * Whether a job scheduled for `at` is one of the ticks of the schedule, rather than a retry that
+ * is pushed at the time of the retry delay
+ */
+pub fn is_schedule_tick(schedule: &Schedule, at: DateTime<Utc>) -> bool {
+    if let Some(run_at) = schedule.run_at {
+        return at == run_at;
     }
+    let (Ok(sched), Ok(tz)) = (
+        cron::Schedule::from_str(&schedule.schedule),
+        chrono_tz::Tz::from_str(&schedule.timezone),
+    ) else {
+        return false;
+    };
+    sched
+        .after(&(at - Duration::seconds(1)).with_timezone(&tz))
+        .next()
+        .map_or(false, |tick| tick.with_timezone(&Utc) == at)
+}
 
-    let mut args: serde_json::Map<String, serde_json::Value> = serde_json::Map::new();
+/*
+ * The ticks after the last tick a job ran for and up to now which are to be caught up according
+ * to the catch-up policy of the schedule, the latest ones being kept. The ticks of a pause are
+ * not missed.
+ */
+fn missed_ticks(
+    schedule: &Schedule,
+    sched: &cron::Schedule,
+    tz: &chrono_tz::Tz,
+    now: DateTime<Utc>,
+) -> Vec<DateTime<Utc>> {
+    let Some(last_tick) = schedule.last_tick else {
+        return vec![];
+    };
+    let last_tick = schedule
+        .paused_until
+        .map_or(last_tick, |paused_until| paused_until.max(last_tick));
+    let limit = match schedule.catch_up {
+        CatchUp::Skip => return vec![],
+        CatchUp::Once => 1,
+        CatchUp::All => schedule
+            .catch_up_limit
+            .unwrap_or(MAX_CATCH_UP_TICKS)
+            .clamp(1, MAX_CATCH_UP_TICKS) as usize,
+    };
 
-    if let Some(args_v) = schedule.args {
-        if let serde_json::Value::Object(args_m) = args_v {
-            args = args_m
-        } else {
-            return Err(error::Error::ExecutionErr(
-                "args of scripts needs to be dict".to_string(),
-            ));
+    let mut ticks = VecDeque::with_capacity(limit);
+    for tick in sched
+        .after(&last_tick.with_timezone(tz))
+        .map(|tick| tick.with_timezone(&Utc))
+        .take_while(|tick| *tick <= now)
+    {
+        if ticks.len() == limit {
+            ticks.pop_front();
         }
+        ticks.push_back(tick);
     }
+    ticks.into()
+}
+
+async fn not_queued_ticks<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
+    tx: &mut QueueTransaction<'c, R>,
+    schedule: &Schedule,
+    mut ticks: Vec<DateTime<Utc>>,
+) -> Result<Vec<DateTime<Utc>>> {
+    let queued = sqlx::query_scalar!(
+        "SELECT scheduled_for FROM queue WHERE workspace_id = $1 AND schedule_path = $2 AND \
+         scheduled_for = ANY($3)",
+        schedule.workspace_id,
+        schedule.path,
+        &ticks
+    )
+    .fetch_all(tx)
+    .await?;
+    ticks.retain(|tick| !queued.contains(tick));
+    Ok(ticks)
+}
 
-    let (payload, tag) = if schedule.is_flow {
-        (JobPayload::Flow(schedule.script_path), None)
+fn schedule_args(schedule: &Schedule) -> Result<serde_json::Map<String, serde_json::Value>> {
+    match &schedule.args {
+        Some(serde_json::Value::Object(args_m)) => Ok(args_m.clone()),
+        Some(_) => Err(error::Error::ExecutionErr(
+            "args of scripts needs to be dict".to_string(),
+        )),
+        None => Ok(serde_json::Map::new()),
+    }
+}
+
+async fn schedule_payload<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
+    tx: &mut QueueTransaction<'c, R>,
+    schedule: &Schedule,
+) -> Result<(JobPayload, Option<String>)> {
+    if schedule.is_flow {
+        Ok((JobPayload::Flow(schedule.script_path.clone()), None))
     } else {
         let (hash, tag) = windmill_common::get_latest_hash_for_path(
             tx.transaction_mut(),
@@ -77,20 +264,21 @@ pub async fn push_scheduled_job<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
             &schedule.script_path,
         )
         .await?;
-        (
-            JobPayload::ScriptHash { hash, path: schedule.script_path },
+        Ok((
+            JobPayload::ScriptHash { hash, path: schedule.script_path.clone() },
             tag,
-        )
-    };
-
-    sqlx::query!(
-        "UPDATE schedule SET error = NULL WHERE workspace_id = $1 AND path = $2",
-        &schedule.workspace_id,
-        &schedule.path
-    )
-    .execute(&mut tx)
-    .await?;
+        ))
+    }
+}
 
+async fn push_schedule_tick<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
+    tx: QueueTransaction<'c, R>,
+    schedule: &Schedule,
+    payload: JobPayload,
+    args: serde_json::Map<String, serde_json::Value>,
+    tag: Option<String>,
+    tick: DateTime<Utc>,
+) -> Result<QueueTransaction<'c, R>> {
     let (_, tx) = push(
         tx,
         &schedule.workspace_id,
@@ -99,7 +287,7 @@ pub async fn push_scheduled_job<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
         &schedule_to_user(&schedule.path),
         &schedule.email,
         username_to_permissioned_as(&schedule.edited_by),
-        Some(next),
+        Some(tick),
         Some(schedule.path.clone()),
         None,
         None,
@@ -109,9 +297,12 @@ pub async fn push_scheduled_job<'c, R: rsmq_async::RsmqConnection + Send + 'c>(
         None,
         true,
         tag,
+        schedule.priority,
+        None,
+        None,
     )
     .await?;
-    Ok(tx) // TODO: Bubble up pushed UUID from here
+    Ok(tx)
 }
 
 pub async fn get_schedule_opt<'c>(
@@ -121,7 +312,11 @@ pub async fn get_schedule_opt<'c>(
 ) -> Result<Option<Schedule>> {
     let schedule_opt = sqlx::query_as!(
         Schedule,
-        "SELECT * FROM schedule WHERE path = $1 AND workspace_id = $2",
+        r#"SELECT workspace_id, path, edited_by, edited_at, schedule, timezone, enabled, script_path,
+         is_flow, args, extra_perms, email, error, on_failure, on_recovery, on_success,
+         failure_threshold, failure_count, last_failure, retry, retry_attempts, priority,
+         catch_up as "catch_up: _", catch_up_limit, last_tick, paused_until, run_at
+         FROM schedule WHERE path = $1 AND workspace_id = $2"#,
         path,
         w_id
     )
@@ -0,0 +1,194 @@
+/*
+ * Author: Ruben Fiszel
+ * Copyright: Windmill Labs, Inc 2023
+ * This file and its contents are licensed under the AGPLv3 License.
+ * Please see the included NOTICE for copyright information and
+ * LICENSE-AGPL for a copy of the license.
+ */
+
+/*
+ * Memory and cpu limits of the job processes that do not run in nsjail, whose config already
+ * caps them. A script can set its own limits, capped by the ones of the worker
+ * (JOB_MEMORY_LIMIT_MB, JOB_CPU_LIMIT_MILLIS).
+ *
+ * When JOB_CGROUP_DIR points to a cgroup v2 directory delegated to the worker (with the memory
+ * and cpu controllers enabled in its cgroup.subtree_control), each job process gets its own
+ * child cgroup with memory.max and cpu.max set. Otherwise, the cpu limit falls back to a cpu time
+ * rlimit of cpu_limit * timeout and the memory limit is enforced by `handle_child`, which kills
+ * the job once its peak memory goes above the limit.
+ */
+
+use std::time::Duration;
+
+use sqlx::{Pool, Postgres};
+use tokio::process::Command;
+use uuid::Uuid;
+
+/* signal sent when the soft cpu time rlimit is reached */
+pub const SIGXCPU: i32 = 24;
+
+const CPU_PERIOD_US: i64 = 100_000;
+
+lazy_static::lazy_static! {
+    static ref JOB_MEMORY_LIMIT_MB: Option<i32> = std::env::var("JOB_MEMORY_LIMIT_MB")
+        .ok()
+        .and_then(|x| x.parse::<i32>().ok())
+        .filter(|x| *x > 0);
+
+    static ref JOB_CPU_LIMIT_MILLIS: Option<i32> = std::env::var("JOB_CPU_LIMIT_MILLIS")
+        .ok()
+        .and_then(|x| x.parse::<i32>().ok())
+        .filter(|x| *x > 0);
+
+    static ref JOB_CGROUP_DIR: Option<String> = std::env::var("JOB_CGROUP_DIR").ok();
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JobLimits {
+    pub memory_limit_mb: Option<i32>,
+    pub cpu_limit_millis: Option<i32>,
+}
+
+impl JobLimits {
+    pub fn is_empty(&self) -> bool {
+        self.memory_limit_mb.is_none() && self.cpu_limit_millis.is_none()
+    }
+
+    pub fn memory_limit_kb(&self) -> Option<i32> {
+        self.memory_limit_mb.map(|mb| mb.saturating_mul(1024))
+    }
+}
+
+fn min_limit(script: Option<i32>, worker: Option<i32>) -> Option<i32> {
+    match (script, worker) {
+        (Some(script), Some(worker)) => Some(script.min(worker)),
+        (script, worker) => script.or(worker),
+    }
+}
+
+pub async fn job_limits(job_id: &Uuid, db: &Pool<Postgres>) -> JobLimits {
+    let (memory_limit_mb, cpu_limit_millis) = sqlx::query!(
+        "SELECT memory_limit_mb, cpu_limit_millis FROM queue WHERE id = $1",
+        job_id
+    )
+    .fetch_optional(db)
+    .await
+    .unwrap_or_else(|e| {
+        tracing::error!(%e, "error getting resource limits for job {job_id}: {e}");
+        None
+    })
+    .map_or((None, None), |r| (r.memory_limit_mb, r.cpu_limit_millis));
+    JobLimits {
+        memory_limit_mb: min_limit(memory_limit_mb, *JOB_MEMORY_LIMIT_MB),
+        cpu_limit_millis: min_limit(cpu_limit_millis, *JOB_CPU_LIMIT_MILLIS),
+    }
+}
+
+/* How the limits of a running job process are enforced */
+pub enum Enforcement {
+    None,
+    Cgroup { dir: String },
+    Rlimit,
+}
+
+impl Enforcement {
+    /* whether the kernel killed the job process for going over its memory limit */
+    pub async fn oom_killed(&self) -> bool {
+        match self {
+            Enforcement::Cgroup { dir } => {
+                tokio::fs::read_to_string(format!("{dir}/memory.events"))
+                    .await
+                    .map(|events| {
+                        events.lines().any(|line| {
+                            line.strip_prefix("oom_kill ")
+                                .and_then(|count| count.trim().parse::<u64>().ok())
+                                .map_or(false, |count| count > 0)
+                        })
+                    })
+                    .unwrap_or(false)
+            }
+            _ => false,
+        }
+    }
+
+    /* the cgroup can only be removed once all of its processes exited */
+    pub async fn cleanup(self) {
+        if let Enforcement::Cgroup { dir } = self {
+            if let Err(e) = tokio::fs::remove_dir(&dir).await {
+                tracing::warn!("could not remove job cgroup {dir}: {e}");
+            }
+        }
+    }
+}
+
+/*
+ * The limits are applied to the job process after it was spawned, so it runs unconstrained until
+ * they are and a child it forks in the meantime is neither moved to the cgroup of the job nor
+ * given its rlimit. This window is short compared to the jobs the limits are meant for, while
+ * setting them from a pre_exec hook would need unsafe code only calling async-signal-safe
+ * functions.
+ */
+pub async fn apply_limits(
+    job_id: &Uuid,
+    pid: u32,
+    limits: &JobLimits,
+    timeout: Duration,
+) -> Enforcement {
+    if limits.is_empty() {
+        return Enforcement::None;
+    }
+    if let Some(root) = JOB_CGROUP_DIR.as_ref() {
+        let dir = format!("{root}/job-{job_id}-{pid}");
+        match apply_cgroup_limits(&dir, pid, limits).await {
+            Ok(()) => return Enforcement::Cgroup { dir },
+            Err(e) => {
+                tracing::warn!(
+                    "could not limit job {job_id} with cgroup {dir}, falling back to rlimits: {e}"
+                );
+                let _ = tokio::fs::remove_dir(&dir).await;
+            }
+        }
+    }
+    if let Some(cpu_limit_millis) = limits.cpu_limit_millis {
+        let cpu_secs = ((cpu_limit_millis as u64 * timeout.as_secs() + 999) / 1000).max(1);
+        /* SIGXCPU at the soft limit, SIGKILL at the hard one */
+        match Command::new("prlimit")
+            .args([
+                "--pid",
+                &pid.to_string(),
+                &format!("--cpu={cpu_secs}:{}", cpu_secs + 5),
+            ])
+            .output()
+            .await
+        {
+            Ok(output) if output.status.success() => (),
+            Ok(output) => tracing::warn!(
+                "could not set the cpu rlimit of job {job_id}: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            Err(e) => tracing::warn!("could not set the cpu rlimit of job {job_id}: {e}"),
+        }
+    }
+    Enforcement::Rlimit
+}
+
+async fn apply_cgroup_limits(dir: &str, pid: u32, limits: &JobLimits) -> std::io::Result<()> {
+    tokio::fs::create_dir(dir).await?;
+    if let Some(memory_limit_mb) = limits.memory_limit_mb {
+        let bytes = memory_limit_mb as i64 * 1024 * 1024;
+        tokio::fs::write(format!("{dir}/memory.max"), bytes.to_string()).await?;
+        /* swap would let the job go over its limit without being killed, not always available */
+        let _ = tokio::fs::write(format!("{dir}/memory.swap.max"), "0").await;
+        /* kill every process of the job rather than just the biggest one */
+        let _ = tokio::fs::write(format!("{dir}/memory.oom.group"), "1").await;
+    }
+    if let Some(cpu_limit_millis) = limits.cpu_limit_millis {
+        let quota = cpu_limit_millis as i64 * CPU_PERIOD_US / 1000;
+        tokio::fs::write(
+            format!("{dir}/cpu.max"),
+            format!("{} {CPU_PERIOD_US}", quota.max(1000)),
+        )
+        .await?;
+    }
+    tokio::fs::write(format!("{dir}/cgroup.procs"), pid.to_string()).await
+}
@@ -6,6 +6,8 @@
  * LICENSE-AGPL for a copy of the license.
  */
 
+use std::collections::BTreeMap;
+
 use serde_json::json;
 use sqlx::{Pool, Postgres};
 use tracing::instrument;
@@ -13,13 +15,21 @@ use uuid::Uuid;
 use windmill_common::{
     error::Error,
     flow_status::FlowStatusModule,
-    jobs::{get_payload_tag_from_prefixed_path, JobKind, QueuedJob},
+    flows::Retry,
+    jobs::{
+        get_payload_tag_from_prefixed_path, notify_job_update, JobKind, JobUpdateNotification,
+        QueuedJob,
+    },
+    result_store::{resolve_result, store_result},
     schedule::{schedule_to_user, Schedule},
     users::username_to_permissioned_as,
+    utils::calculate_hash,
     METRICS_ENABLED,
 };
 use windmill_queue::{
-    delete_job, push, schedule::get_schedule_opt, QueueTransaction, CLOUD_HOSTED,
+    delete_job, push,
+    schedule::{get_schedule_opt, is_schedule_tick, push_schedule_retry},
+    QueueTransaction, CLOUD_HOSTED,
 };
 
 #[instrument(level = "trace", skip_all)]
@@ -35,10 +45,88 @@ pub async fn add_completed_job_error<R: rsmq_async::RsmqConnection + Clone + Sen
         metrics.map(|m| m.worker_execution_failed.inc());
     }
     let result = serde_json::json!({ "error": e });
-    let _ = add_completed_job(db, &queued_job, false, false, result.clone(), logs, rsmq).await?;
+    let _ = add_completed_job(
+        db,
+        &queued_job,
+        false,
+        false,
+        false,
+        result.clone(),
+        logs,
+        rsmq,
+    )
+    .await?;
     Ok(result)
 }
 
+/* the cache key of a job with a cache_ttl, a hash of its script, or of its code for a raw
+ * script, and of its arguments */
+fn cache_key(queued_job: &QueuedJob) -> Option<String> {
+    queued_job.cache_ttl?;
+    let code = match (&queued_job.script_hash, &queued_job.raw_code) {
+        (Some(hash), _) => format!("hash:{}", hash.0),
+        (None, Some(code)) => format!("code:{code}"),
+        _ => return None,
+    };
+    let args = queued_job
+        .args
+        .as_ref()
+        .map(sorted_keys)
+        .unwrap_or_default();
+    Some(calculate_hash(&format!("{code}\nargs:{args}")))
+}
+
+/* objects keep the order of their keys, sort them so that equal arguments hash the same */
+fn sorted_keys(value: &serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(m) => serde_json::Value::Object(
+            m.iter()
+                .map(|(k, v)| (k.clone(), sorted_keys(v)))
+                .collect::<BTreeMap<_, _>>()
+                .into_iter()
+                .collect(),
+        ),
+        serde_json::Value::Array(a) => {
+            serde_json::Value::Array(a.iter().map(sorted_keys).collect())
+        }
+        v => v.clone(),
+    }
+}
+
+/* A job with a cache_ttl completes with the result of the latest successful run, younger than
+ * the ttl, with the same cache key and run as the same user, so that a result is never served
+ * to a user who could not have computed it. Cache hits are never served themselves so that a
+ * result cannot outlive its ttl */
+pub async fn get_cached_result(
+    db: &Pool<Postgres>,
+    queued_job: &QueuedJob,
+) -> Result<Option<(Uuid, serde_json::Value)>, Error> {
+    let (cache_ttl, cache_key) = match (queued_job.cache_ttl, cache_key(queued_job)) {
+        (Some(ttl), Some(key)) => (ttl, key),
+        _ => return Ok(None),
+    };
+    let cached = sqlx::query!(
+        "SELECT id, result FROM completed_job
+         WHERE workspace_id = $1 AND cache_key = $2 AND permissioned_as = $3
+           AND success = true AND cache_hit = false AND deleted = false
+           AND started_at > now() - make_interval(secs => $4)
+         ORDER BY started_at DESC LIMIT 1",
+        queued_job.workspace_id,
+        cache_key,
+        queued_job.permissioned_as,
+        cache_ttl as f64
+    )
+    .fetch_optional(db)
+    .await?;
+    match cached {
+        Some(cached) if cached.result.is_some() => Ok(Some((
+            cached.id,
+            resolve_result(cached.result.unwrap()).await?,
+        ))),
+        _ => Ok(None),
+    }
+}
+
 fn flatten_jobs(modules: Vec<FlowStatusModule>) -> Vec<Uuid> {
     modules
         .into_iter()
@@ -63,6 +151,7 @@ pub async fn add_completed_job<R: rsmq_async::RsmqConnection + Clone + Send>(
     queued_job: &QueuedJob,
     success: bool,
     skipped: bool,
+    cached: bool,
     result: serde_json::Value,
     logs: String,
     rsmq: Option<R>,
@@ -98,6 +187,7 @@ pub async fn add_completed_job<R: rsmq_async::RsmqConnection + Clone + Send>(
         .ok()
         .flatten()
         .flatten();
+    let result = store_result(&queued_job.workspace_id, &queued_job.id, result).await?;
     let mut tx: QueueTransaction<'_, R> = (rsmq, db.begin().await?).into();
     let job_id = queued_job.id.clone();
     let duration = sqlx::query_scalar!(
@@ -132,9 +222,13 @@ pub async fn add_completed_job<R: rsmq_async::RsmqConnection + Clone + Send>(
                    , visible_to_owner
                    , mem_peak
                    , tag
+                   , concurrency_key
+                   , cache_hit
+                   , cache_key
+                   , flow_version
                 )
             VALUES ($1, $2, $3, $4, $5, $6, COALESCE($26, (EXTRACT('epoch' FROM (now())) - EXTRACT('epoch' FROM (COALESCE($6, now()))))*1000), $7, $8, $9,\
-                    $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25, $27, $28, $29, $30)
+                    $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25, $27, $28, $29, $30, $31, $32, $33, $34)
          ON CONFLICT (id) DO UPDATE SET success = $7, result = $11, logs = concat(cj.logs, $12) RETURNING duration_ms",
         queued_job.workspace_id,
         queued_job.id,
@@ -166,6 +260,10 @@ pub async fn add_completed_job<R: rsmq_async::RsmqConnection + Clone + Send>(
         queued_job.visible_to_owner,
         mem_peak,
         queued_job.tag,
+        queued_job.concurrency_key,
+        cached,
+        cache_key(queued_job),
+        queued_job.flow_version,
     )
     .fetch_one(&mut tx)
     .await
@@ -184,11 +282,18 @@ pub async fn add_completed_job<R: rsmq_async::RsmqConnection + Clone + Send>(
             queued_job.schedule_path.as_ref().unwrap(),
             queued_job.script_path.as_ref().unwrap(),
             &queued_job.workspace_id,
+            queued_job.scheduled_for,
+            queued_job.args.as_ref(),
             success,
-            if success { None } else { Some(result) },
+            result,
         )
         .await?;
     }
+    notify_job_update(
+        tx.transaction_mut(),
+        &JobUpdateNotification::Completed { job_id, success },
+    )
+    .await?;
     tx.commit().await?;
 
     #[cfg(feature = "enterprise")]
@@ -223,8 +328,10 @@ pub async fn handle_maybe_scheduled_job<'c, R: rsmq_async::RsmqConnection + Clon
     schedule_path: &str,
     script_path: &str,
     w_id: &str,
+    scheduled_for: chrono::DateTime<chrono::Utc>,
+    args: Option<&serde_json::Value>,
     success: bool,
-    result: Option<serde_json::Value>,
+    result: serde_json::Value,
 ) -> windmill_common::error::Result<QueueTransaction<'c, R>> {
     let schedule = get_schedule_opt(tx.transaction_mut(), w_id, schedule_path).await?;
 
@@ -235,48 +342,158 @@ pub async fn handle_maybe_scheduled_job<'c, R: rsmq_async::RsmqConnection + Clon
         return Ok(tx);
     }
 
-    let schedule = schedule.unwrap();
+    let mut schedule = schedule.unwrap();
+
+    /*
+     * the missed ticks to catch up are the ones after the latest tick a job ran for. A retry is
+     * scheduled after the retry delay, past ticks that are still to run, and is not a tick
+     */
+    if is_schedule_tick(&schedule, scheduled_for) {
+        schedule.last_tick = sqlx::query_scalar!(
+            "UPDATE schedule SET last_tick = GREATEST(last_tick, $1) WHERE workspace_id = $2 AND \
+             path = $3 RETURNING last_tick",
+            scheduled_for,
+            w_id,
+            schedule_path
+        )
+        .fetch_one(&mut tx)
+        .await?;
+    }
 
     if schedule.enabled && script_path == schedule.script_path {
-        if !success {
-            if let Some(on_failure_path) = schedule.on_failure.clone() {
-                let on_failure_result = handle_on_failure(
-                    tx,
-                    schedule_path,
-                    script_path,
-                    w_id,
-                    &on_failure_path,
-                    result,
-                    &schedule.email,
-                    &schedule_to_user(&schedule.path),
-                    username_to_permissioned_as(&schedule.edited_by),
-                )
-                .await;
+        /*
+         * the failure handler runs once per streak of consecutive failures, when it reaches the
+         * failure threshold, the recovery handler on the first success after it did
+         */
+        let threshold = schedule.failure_threshold.unwrap_or(1);
+        let retry_in = schedule
+            .retry
+            .clone()
+            .filter(|_| !success)
+            .and_then(|retry| serde_json::from_value::<Retry>(retry).ok())
+            .and_then(|retry| retry.interval(schedule.retry_attempts as u16));
+        let handler = if success {
+            sqlx::query!(
+                "UPDATE schedule SET failure_count = 0, last_failure = NULL, retry_attempts = 0 \
+                 WHERE workspace_id = $1 AND path = $2",
+                w_id,
+                schedule_path
+            )
+            .execute(&mut tx)
+            .await?;
+            let mut handlers = vec![];
+            if schedule.failure_count >= threshold {
+                if let Some(on_recovery_path) = schedule.on_recovery.clone() {
+                    let mut args = serde_json::Map::new();
+                    args.insert("failure_count".to_string(), json!(schedule.failure_count));
+                    args.insert("previous_error".to_string(), json!(schedule.last_failure));
+                    args.insert("result".to_string(), result.clone());
+                    handlers.push(("on_recovery", on_recovery_path, args));
+                }
+            }
+            if let Some(on_success_path) = schedule.on_success.clone() {
+                let mut args = serde_json::Map::new();
+                args.insert("result".to_string(), result);
+                handlers.push(("on_success", on_success_path, args));
+            }
+            handlers
+        } else if let Some(retry_in) = retry_in {
+            /*
+             * a failure is only counted, and handled, once its retries are exhausted. The
+             * attempts are those of the schedule rather than of a tick: a success of another
+             * tick while a retry is pending resets them, and the retries of the failure start over
+             */
+            sqlx::query!(
+                "UPDATE schedule SET retry_attempts = retry_attempts + 1 WHERE workspace_id = \
+                 $1 AND path = $2",
+                w_id,
+                schedule_path
+            )
+            .execute(&mut tx)
+            .await?;
+            let args = match args {
+                Some(serde_json::Value::Object(args)) => args.clone(),
+                _ => serde_json::Map::new(),
+            };
+            tx = push_schedule_retry(tx, &schedule, args, retry_in).await?;
+            vec![]
+        } else {
+            let failure_count = sqlx::query_scalar!(
+                "UPDATE schedule SET failure_count = failure_count + 1, last_failure = $1, \
+                 retry_attempts = 0 WHERE workspace_id = $2 AND path = $3 RETURNING \
+                 failure_count",
+                result,
+                w_id,
+                schedule_path
+            )
+            .fetch_one(&mut tx)
+            .await?;
+            match schedule.on_failure.clone() {
+                Some(on_failure_path) if failure_count == threshold => {
+                    let mut args = match result {
+                        serde_json::Value::Object(args) => args,
+                        result => [("error".to_string(), result)].into_iter().collect(),
+                    };
+                    args.insert("failure_count".to_string(), json!(failure_count));
+                    args.insert("previous_error".to_string(), json!(schedule.last_failure));
+                    vec![("on_failure", on_failure_path, args)]
+                }
+                _ => vec![],
+            }
+        };
 
-                match on_failure_result {
-                    Ok(ntx) => {
-                        tx = ntx;
-                    }
-                    Err(err) => {
-                        sqlx::query!(
-                        "UPDATE schedule SET enabled = false, error = $1 WHERE workspace_id = $2 AND path = $3",
-                        format!("Could not trigger error handler: {err}"),
-                        &schedule.workspace_id,
-                        &schedule.path
+        for (handler, handler_path, args) in handler {
+            let handler_result = push_schedule_handler(
+                tx,
+                handler,
+                schedule_path,
+                script_path,
+                w_id,
+                &handler_path,
+                args,
+                &schedule_to_user(&schedule.path),
+                &schedule.email,
+                username_to_permissioned_as(&schedule.edited_by),
+            )
+            .await;
+
+            match handler_result {
+                Ok(ntx) => {
+                    tx = ntx;
+                }
+                Err(err) => {
+                    sqlx::query!(
+                        "UPDATE schedule SET enabled = false, error = $1 WHERE workspace_id = $2 \
+                         AND path = $3",
+                        format!("Could not trigger {handler} handler: {err}"),
+                        schedule.workspace_id,
+                        schedule.path
                     )
                     .execute(db)
                     .await?;
-                        tracing::warn!(
-                            "Could not trigger error handler for {}: {}",
-                            schedule_path,
-                            err
-                        );
-                        return Err(err);
-                    }
+                    tracing::warn!(
+                        "Could not trigger {} handler for {}: {}",
+                        handler,
+                        schedule_path,
+                        err
+                    );
+                    return Err(err);
                 }
             }
         }
 
+        /* a one-shot schedule is done once its job ran, retries included */
+        if schedule.run_at.is_some() && retry_in.is_none() {
+            sqlx::query!(
+                "UPDATE schedule SET enabled = false WHERE workspace_id = $1 AND path = $2",
+                w_id,
+                schedule_path
+            )
+            .execute(&mut tx)
+            .await?;
+            return Ok(tx);
+        }
+
         let res = windmill_queue::schedule::push_scheduled_job(
             tx,
             Schedule {
@@ -296,6 +513,19 @@ pub async fn handle_maybe_scheduled_job<'c, R: rsmq_async::RsmqConnection + Clon
                 email: schedule.email,
                 error: None,
                 on_failure: schedule.on_failure,
+                on_recovery: schedule.on_recovery,
+                on_success: schedule.on_success,
+                failure_threshold: schedule.failure_threshold,
+                failure_count: schedule.failure_count,
+                last_failure: schedule.last_failure,
+                retry: schedule.retry,
+                retry_attempts: schedule.retry_attempts,
+                priority: schedule.priority,
+                catch_up: schedule.catch_up,
+                catch_up_limit: schedule.catch_up_limit,
+                last_tick: schedule.last_tick,
+                paused_until: schedule.paused_until,
+                run_at: schedule.run_at,
             },
         )
         .await;
@@ -319,25 +549,21 @@ pub async fn handle_maybe_scheduled_job<'c, R: rsmq_async::RsmqConnection + Clon
     }
 }
 
-async fn handle_on_failure<'c, R: rsmq_async::RsmqConnection + Clone + Send + 'c>(
+async fn push_schedule_handler<'c, R: rsmq_async::RsmqConnection + Clone + Send + 'c>(
     mut tx: QueueTransaction<'c, R>,
+    handler: &str,
     schedule_path: &str,
     script_path: &str,
     w_id: &str,
-    on_failure_path: &str,
-    result: Option<serde_json::Value>,
+    handler_path: &str,
+    mut args: serde_json::Map<String, serde_json::Value>,
     username: &str,
     email: &str,
     permissioned_as: String,
 ) -> windmill_common::error::Result<QueueTransaction<'c, R>> {
     let (payload, tag) =
-        get_payload_tag_from_prefixed_path(on_failure_path, tx.transaction_mut(), w_id).await?;
+        get_payload_tag_from_prefixed_path(handler_path, tx.transaction_mut(), w_id).await?;
 
-    let mut args = result
-        .unwrap_or_else(|| json!({}))
-        .as_object()
-        .unwrap()
-        .clone();
     args.insert("schedule_path".to_string(), json!(schedule_path));
     args.insert("path".to_string(), json!(script_path));
     let (uuid, tx) = push(
@@ -358,10 +584,14 @@ async fn handle_on_failure<'c, R: rsmq_async::RsmqConnection + Clone + Send + 'c
         None,
         true,
         tag,
+        None,
+        None,
+        None,
     )
     .await?;
     tracing::info!(
-        "Pushed on_failure job {} for {} to queue",
+        "Pushed {} job {} for {} to queue",
+        handler,
         uuid,
         schedule_path
     );
@@ -1,8 +1,10 @@
 mod common;
 mod global_cache;
 mod go_executor;
+mod job_limits;
 mod jobs;
 mod js_eval;
+mod pg_executor;
 mod python_executor;
 mod worker;
 mod worker_flow;
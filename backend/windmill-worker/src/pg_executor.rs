@@ -0,0 +1,248 @@
+use base64::Engine;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Map, Value};
+use sqlx::{
+    postgres::{PgArguments, PgColumn, PgConnectOptions, PgRow, PgSslMode},
+    query::Query,
+    Column, ConnectOptions, Connection, Postgres, Row, TypeInfo, ValueRef,
+};
+use uuid::Uuid;
+use windmill_common::{
+    error::{self, Error},
+    jobs::QueuedJob,
+};
+use windmill_parser::{Arg, Typ};
+use windmill_parser_sql::{parse_pg_file, PG_DATABASE_ARG};
+
+use crate::{common::set_logs, job_timeout, transform_json_value, AuthedClientBackgroundTask};
+
+/* the value of a `postgresql` resource */
+#[derive(Deserialize)]
+struct PgDatabase {
+    host: String,
+    port: Option<u16>,
+    user: Option<String>,
+    password: Option<String>,
+    dbname: String,
+    sslmode: Option<String>,
+}
+
+/*
+ * Runs the script body as a single parameterized statement against the `database` resource,
+ * binding $1, $2, ... from the arguments declared in its header, and returns the rows as a list
+ * of objects keyed by column name.
+ */
+#[tracing::instrument(level = "trace", skip_all)]
+pub async fn do_postgresql(
+    job: &QueuedJob,
+    logs: &mut String,
+    db: &sqlx::Pool<sqlx::Postgres>,
+    client: &AuthedClientBackgroundTask,
+    query: &str,
+) -> error::Result<Value> {
+    logs.push_str("\n\n--- POSTGRESQL CODE EXECUTION ---\n");
+    set_logs(logs, &job.id, db).await;
+
+    let args = match &job.args {
+        Some(args) => {
+            let client = client.get_authed().await;
+            transform_json_value("args", &client, &job.workspace_id, args.clone()).await?
+        }
+        None => json!({}),
+    };
+
+    let database = args.get(PG_DATABASE_ARG).cloned().ok_or_else(|| {
+        Error::BadRequest(format!(
+            "Missing `{PG_DATABASE_ARG}` argument, a postgresql resource"
+        ))
+    })?;
+    let database = serde_json::from_value::<PgDatabase>(database).map_err(|e| {
+        Error::BadRequest(format!(
+            "`{PG_DATABASE_ARG}` is not a valid postgresql resource: {e}"
+        ))
+    })?;
+
+    let mut options = PgConnectOptions::new()
+        .host(&database.host)
+        .database(&database.dbname);
+    if let Some(port) = database.port {
+        options = options.port(port);
+    }
+    if let Some(user) = &database.user {
+        options = options.username(user);
+    }
+    if let Some(password) = &database.password {
+        options = options.password(password);
+    }
+    if let Some(sslmode) = &database.sslmode {
+        options = options.ssl_mode(
+            sslmode
+                .parse::<PgSslMode>()
+                .map_err(|e| Error::BadRequest(format!("Invalid sslmode `{sslmode}`: {e}")))?,
+        );
+    }
+    options.disable_statement_logging();
+
+    let sig = parse_pg_file(query).map_err(|e| Error::BadRequest(e.to_string()))?;
+    let mut statement = sqlx::query(query);
+    for arg in &sig {
+        /* a missing or null argument falls back to its declared default */
+        let value = args
+            .get(&arg.name)
+            .filter(|v| !v.is_null())
+            .or(arg.default.as_ref())
+            .cloned()
+            .unwrap_or(Value::Null);
+        statement = bind_arg(statement, arg, value)?;
+    }
+
+    let timeout = job_timeout(&job.id, &job.workspace_id, db).await;
+    let rows = tokio::time::timeout(timeout, async {
+        let mut conn = options.connect().await?;
+        let rows = statement.fetch_all(&mut conn).await?;
+        conn.close().await?;
+        Ok(rows) as Result<Vec<PgRow>, sqlx::Error>
+    })
+    .await
+    .map_err(|_| Error::ExecutionTimeout(timeout.as_secs()))?
+    .map_err(|e| Error::ExecutionErr(e.to_string()))?;
+
+    logs.push_str(&format!("{} row(s) returned\n", rows.len()));
+    set_logs(logs, &job.id, db).await;
+
+    Ok(Value::Array(
+        rows.iter()
+            .map(row_to_json)
+            .collect::<error::Result<Vec<_>>>()?,
+    ))
+}
+
+fn bind_arg<'q>(
+    statement: Query<'q, Postgres, PgArguments>,
+    arg: &Arg,
+    value: Value,
+) -> error::Result<Query<'q, Postgres, PgArguments>> {
+    let otyp = arg.otyp.as_deref().unwrap_or("text");
+    let statement = match &arg.typ {
+        Typ::Int => statement.bind(from_arg::<Option<i64>>(arg, value)?),
+        Typ::Float => statement.bind(from_arg::<Option<f64>>(arg, value)?),
+        Typ::Bool => statement.bind(from_arg::<Option<bool>>(arg, value)?),
+        Typ::Object(_) => statement.bind((!value.is_null()).then_some(sqlx::types::Json(value))),
+        Typ::Datetime if otyp == "date" => {
+            statement.bind(parse_datetime(arg, value)?.map(|d| d.date_naive()))
+        }
+        Typ::Datetime if otyp == "timestamp" || otyp == "timestamp without time zone" => {
+            statement.bind(parse_datetime(arg, value)?.map(|d| d.naive_utc()))
+        }
+        Typ::Datetime => statement.bind(parse_datetime(arg, value)?),
+        Typ::Bytes => statement.bind(
+            from_arg::<Option<String>>(arg, value)?
+                .map(|s| base64::engine::general_purpose::STANDARD.decode(s))
+                .transpose()
+                .map_err(|e| {
+                    Error::BadRequest(format!("Argument `{}` is not valid base64: {e}", arg.name))
+                })?,
+        ),
+        Typ::List(inner) => match inner.as_ref() {
+            Typ::Int => statement.bind(from_arg::<Option<Vec<i64>>>(arg, value)?),
+            Typ::Float => statement.bind(from_arg::<Option<Vec<f64>>>(arg, value)?),
+            Typ::Bool => statement.bind(from_arg::<Option<Vec<bool>>>(arg, value)?),
+            _ => statement.bind(
+                from_arg::<Option<Vec<Value>>>(arg, value)?
+                    .map(|l| l.into_iter().map(value_to_string).collect::<Vec<_>>()),
+            ),
+        },
+        _ if otyp == "uuid" => statement.bind(from_arg::<Option<Uuid>>(arg, value)?),
+        _ => statement.bind((!value.is_null()).then(|| value_to_string(value))),
+    };
+    Ok(statement)
+}
+
+fn from_arg<T: DeserializeOwned>(arg: &Arg, value: Value) -> error::Result<T> {
+    serde_json::from_value(value).map_err(|e| {
+        Error::BadRequest(format!(
+            "Argument `{}` is not a valid {}: {e}",
+            arg.name,
+            arg.otyp.as_deref().unwrap_or("text")
+        ))
+    })
+}
+
+/* datetimes without a timezone are taken as utc */
+fn parse_datetime(arg: &Arg, value: Value) -> error::Result<Option<DateTime<Utc>>> {
+    let s = match from_arg::<Option<String>>(arg, value)? {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    if let Ok(d) = DateTime::parse_from_rfc3339(&s) {
+        return Ok(Some(d.with_timezone(&Utc)));
+    }
+    s.parse::<NaiveDateTime>()
+        .or_else(|_| {
+            s.parse::<NaiveDate>()
+                .map(|d| d.and_time(NaiveTime::default()))
+        })
+        .map(|d| Some(DateTime::<Utc>::from_utc(d, Utc)))
+        .map_err(|e| {
+            Error::BadRequest(format!(
+                "Argument `{}` is not a valid datetime: {e}",
+                arg.name
+            ))
+        })
+}
+
+fn value_to_string(value: Value) -> String {
+    match value {
+        Value::String(s) => s,
+        v => v.to_string(),
+    }
+}
+
+fn row_to_json(row: &PgRow) -> error::Result<Value> {
+    let mut object = Map::new();
+    for column in row.columns() {
+        object.insert(column.name().to_string(), column_to_json(row, column)?);
+    }
+    Ok(Value::Object(object))
+}
+
+fn column_to_json(row: &PgRow, column: &PgColumn) -> error::Result<Value> {
+    let i = column.ordinal();
+    if row.try_get_raw(i)?.is_null() {
+        return Ok(Value::Null);
+    }
+    let value = match column.type_info().name() {
+        "BOOL" => json!(row.try_get::<bool, _>(i)?),
+        "INT2" => json!(row.try_get::<i16, _>(i)?),
+        "INT4" => json!(row.try_get::<i32, _>(i)?),
+        "INT8" => json!(row.try_get::<i64, _>(i)?),
+        "FLOAT4" => json!(row.try_get::<f32, _>(i)?),
+        "FLOAT8" => json!(row.try_get::<f64, _>(i)?),
+        "TEXT" | "VARCHAR" | "BPCHAR" | "NAME" => json!(row.try_get::<String, _>(i)?),
+        "JSON" | "JSONB" => row.try_get::<Value, _>(i)?,
+        "UUID" => json!(row.try_get::<Uuid, _>(i)?),
+        "TIMESTAMPTZ" => json!(row.try_get::<DateTime<Utc>, _>(i)?),
+        "TIMESTAMP" => json!(row.try_get::<NaiveDateTime, _>(i)?),
+        "DATE" => json!(row.try_get::<NaiveDate, _>(i)?),
+        "TIME" => json!(row.try_get::<NaiveTime, _>(i)?),
+        "BYTEA" => {
+            json!(base64::engine::general_purpose::STANDARD.encode(row.try_get::<Vec<u8>, _>(i)?))
+        }
+        "BOOL[]" => json!(row.try_get::<Vec<bool>, _>(i)?),
+        "INT2[]" => json!(row.try_get::<Vec<i16>, _>(i)?),
+        "INT4[]" => json!(row.try_get::<Vec<i32>, _>(i)?),
+        "INT8[]" => json!(row.try_get::<Vec<i64>, _>(i)?),
+        "FLOAT4[]" => json!(row.try_get::<Vec<f32>, _>(i)?),
+        "FLOAT8[]" => json!(row.try_get::<Vec<f64>, _>(i)?),
+        "TEXT[]" | "VARCHAR[]" | "BPCHAR[]" | "NAME[]" => json!(row.try_get::<Vec<String>, _>(i)?),
+        "UUID[]" => json!(row.try_get::<Vec<Uuid>, _>(i)?),
+        typ => {
+            let name = column.name();
+            return Err(Error::ExecutionErr(format!(
+                "Column `{name}` of type {typ} cannot be returned as json, cast it (e.g. `{name}::text`)"
+            )));
+        }
+    };
+    Ok(value)
+}
@@ -13,6 +13,7 @@ use once_cell::sync::OnceCell;
 use sqlx::{Pool, Postgres};
 use windmill_api_client::Client;
 use windmill_parser::Typ;
+use windmill_parser_bash::BashRequirement;
 use std::{
     borrow::Borrow, collections::HashMap, io, os::unix::process::ExitStatusExt, panic,
     process::Stdio, time::{Duration},
@@ -26,9 +27,9 @@ use windmill_common::{
     flows::{FlowModuleValue, FlowValue},
     scripts::{ScriptHash, ScriptLang, get_full_hub_script_by_path},
     utils::{rd_string, StripPath},
-    variables, BASE_URL, users::SUPERADMIN_SECRET_EMAIL, METRICS_ENABLED, jobs::{JobKind, QueuedJob}, IS_READY,
+    variables, BASE_URL, users::SUPERADMIN_SECRET_EMAIL, METRICS_ENABLED, jobs::{JobKind, QueuedJob, JobUpdateNotification, notify_job_update, split_logs_for_notify}, IS_READY,
 };
-use windmill_queue::{canceled_job_to_result, get_queued_job, pull, CLOUD_HOSTED, HTTP_CLIENT};
+use windmill_queue::{canceled_job_to_result, get_queued_job, pull, CLOUD_HOSTED, HTTP_CLIENT, MAX_TIMEOUT};
 
 use serde_json::{json, Value};
 
@@ -56,10 +57,11 @@ use rand::Rng;
 use crate::global_cache::{copy_cache_to_tmp_cache, cache_global, copy_tmp_cache_to_cache, copy_denogo_cache_from_bucket_as_tar, copy_all_piptars_from_bucket};
 
 use crate::{
-    jobs::{add_completed_job, add_completed_job_error},
+    jobs::{add_completed_job, add_completed_job_error, get_cached_result},
+    job_limits::{apply_limits, job_limits, Enforcement, JobLimits, SIGXCPU},
     worker_flow::{
         handle_flow, update_flow_status_after_job_completion, update_flow_status_in_progress,
-    }, python_executor::{create_dependencies_dir, pip_compile, handle_python_job, handle_python_reqs}, common::{read_result, set_logs}, go_executor::{handle_go_job, install_go_dependencies},
+    }, python_executor::{create_dependencies_dir, pip_compile, handle_python_job, handle_python_reqs}, common::{read_result, set_logs}, go_executor::{handle_go_job, install_go_dependencies}, pg_executor::do_postgresql,
 };
 
 
@@ -79,7 +81,8 @@ pub async fn create_token_for_owner_in_bg(db: &Pool<Postgres>, job: &QueuedJob)
                 &job.workspace_id,
                 &job.permissioned_as,
                 "ephemeral-script",
-                *SESSION_TOKEN_EXPIRY,
+                // the token must outlive jobs whose timeout is longer than the default one
+                job.timeout.map(|t| t.saturating_mul(2)).unwrap_or(0).max(*SESSION_TOKEN_EXPIRY),
                 &job.email,
             )
             .await.expect("could not create job token");
@@ -147,7 +150,7 @@ pub struct Metrics {
     pub worker_execution_failed: prometheus::IntCounter,
 }
 
-pub const DEFAULT_TIMEOUT: u64 = 900;
+pub use windmill_queue::DEFAULT_TIMEOUT;
 pub const DEFAULT_SLEEP_QUEUE: u64 = 50;
 
 lazy_static::lazy_static! {
@@ -336,7 +339,8 @@ pub async fn run_worker<R: rsmq_async::RsmqConnection + Send + Sync + Clone + 's
         Some(ScriptLang::Python3),
         Some(ScriptLang::Deno),
         Some(ScriptLang::Go),
-        Some(ScriptLang::Bash)];
+        Some(ScriptLang::Bash),
+        Some(ScriptLang::Postgresql)];
 
     let worker_execution_duration: HashMap<_, _>  = all_langs.clone().into_iter().map(|x| (x.clone(), prometheus::register_histogram!(
         prometheus::HistogramOpts::new(
@@ -451,8 +455,8 @@ pub async fn run_worker<R: rsmq_async::RsmqConnection + Send + Sync + Clone + 's
     let rsmq2 = rsmq.clone();
     let worker_name2 = worker_name.clone();
     let send_result = tokio::spawn(async move {
-        while let Some(JobCompleted { job, logs, result}) = job_completed_rx.recv().await {
-            if let Err(e) = add_completed_job(&db2, &job, true, false, result, logs, rsmq2.clone()).await {
+        while let Some(JobCompleted { job, logs, result, cached }) = job_completed_rx.recv().await {
+            if let Err(e) = add_completed_job(&db2, &job, true, false, cached, result, logs, rsmq2.clone()).await {
                 tracing::error!(worker = %worker_name2, "failed to add completed job: {}", e);
             }
         }
@@ -778,6 +782,10 @@ pub async fn handle_job_error<R: rsmq_async::RsmqConnection + Send + Sync + Clon
 ) {
     let err = match err {
         Error::JsonErr(err) => err,
+        Error::ExecutionTimeout(_) => json!({"message": err.to_string(), "name": "TimeoutErr"}),
+        Error::MemoryLimitExceeded(_) => {
+            json!({"message": err.to_string(), "name": "MemoryLimitErr"})
+        }
         _ => json!({"message": err.to_string(), "name": "InternalErr"}),
     };
 
@@ -876,6 +884,7 @@ struct JobCompleted {
     job: QueuedJob,
     result: serde_json::Value,
     logs: String,
+    cached: bool,
 }
 #[tracing::instrument(level = "trace", skip_all)]
 async fn handle_queued_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>(
@@ -940,6 +949,7 @@ async fn handle_queued_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>(
             );
 
             logs.push_str(&format!("job {} on worker {}\n", &job.id, &worker_name));
+            let mut cached = false;
             let result = match job.job_kind {
                 JobKind::Dependencies => {
                     handle_dependency_job(&job, &mut logs, job_dir, db, worker_name, worker_dir).await
@@ -957,19 +967,26 @@ async fn handle_queued_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>(
                     }
                     args @ _ => Ok(args.unwrap_or_else(|| Value::Null)),
                 },
-                _ => {
-                    handle_code_execution_job(
-                        &job,
-                        db,
-                        client,
-                        job_dir,
-                        worker_dir,
-                        &mut logs,
-                        base_internal_url,
-                        worker_name
-                    )
-                    .await
-                }
+                _ => match get_cached_result(db, &job).await? {
+                    Some((cached_job, r)) => {
+                        logs.push_str(&format!("result served from the cache of job {cached_job}\n"));
+                        cached = true;
+                        Ok(r)
+                    }
+                    None => {
+                        handle_code_execution_job(
+                            &job,
+                            db,
+                            client,
+                            job_dir,
+                            worker_dir,
+                            &mut logs,
+                            base_internal_url,
+                            worker_name
+                        )
+                        .await
+                    }
+                },
             };
 
             //it's a test job, no need to update the db
@@ -981,7 +998,7 @@ async fn handle_queued_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>(
                 Ok(r) => {
                     // println!("bef completed job{:?}",  SystemTime::now());
                     if job.is_flow_step {
-                        add_completed_job(db, &job, true, false, r.clone(), logs, rsmq.clone()).await?;
+                        add_completed_job(db, &job, true, false, cached, r.clone(), logs, rsmq.clone()).await?;
                         if let Some(parent_job) = job.parent_job {
                             update_flow_status_after_job_completion(
                                 db,
@@ -1003,7 +1020,7 @@ async fn handle_queued_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>(
                         }
                     } else {
                         // in the happy path and if job not a flow step, we can delegate updating the completed job in the background
-                        job_completed_tx.send(JobCompleted{job,result:r,logs:logs,}).await.expect("send job completed");
+                        job_completed_tx.send(JobCompleted{job,result:r,logs:logs,cached}).await.expect("send job completed");
                         
                     }
                 }
@@ -1030,6 +1047,12 @@ async fn handle_queued_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>(
                                 extract_error_value(log_lines, i)
                             }
                         }
+                        err @ Error::ExecutionTimeout(_) => {
+                            json!({"message": err.to_string(), "name": "TimeoutErr"})
+                        }
+                        err @ Error::MemoryLimitExceeded(_) => {
+                            json!({"message": err.to_string(), "name": "MemoryLimitErr"})
+                        }
                         err @ _ => {
                             json!({"message": format!("error during execution of the script:\n{}", err), "name": "ExecutionErr"})
                         }
@@ -1076,7 +1099,7 @@ pub async fn write_file(dir: &str, path: &str, content: &str) -> error::Result<F
 }
 
 #[async_recursion]
-async fn transform_json_value(
+pub async fn transform_json_value(
     name: &str,
     client: &AuthedClient,
     workspace: &str,
@@ -1270,6 +1293,7 @@ mount {{
             )
             .await
         }
+        Some(ScriptLang::Postgresql) => do_postgresql(job, logs, db, client, &inner_content).await,
     };
     tracing::info!(
         worker_name = %worker_name,
@@ -1299,6 +1323,11 @@ async fn handle_bash_job(
     worker_name: &str,
 ) -> Result<serde_json::Value, Error> {
     logs.push_str("\n\n--- BASH CODE EXECUTION ---\n");
+    let requirements = windmill_parser_bash::parse_bash_requirements(content)?;
+    if !requirements.is_empty() {
+        let found = check_bash_requirements(&requirements, worker_name).await?;
+        logs.push_str(&format!("requirements: {}\n", found.join(", ")));
+    }
     set_logs(logs, &job.id, db).await;
     write_file(job_dir, "main.sh", &format!("set -e\n{content}\nsleep 0.02")).await?;
     let token = client.get_token().await;
@@ -1572,6 +1601,81 @@ pub async fn create_args_and_out_file(
 
 
 
+lazy_static::lazy_static! {
+    static ref RE_BINARY_VERSION: regex::Regex = regex::Regex::new(r"\d+(?:\.\d+)*").unwrap();
+}
+
+/* Checks the `# requires:` of a bash script against the binaries of this worker and returns
+ * them as `binary=version`, or an error listing all the unsatisfied ones */
+async fn check_bash_requirements(
+    requirements: &[BashRequirement],
+    worker_name: &str,
+) -> error::Result<Vec<String>> {
+    let mut found = vec![];
+    let mut unsatisfied = vec![];
+    for requirement in requirements {
+        let path = match find_in_path(&requirement.binary).await {
+            Some(path) => path,
+            None => {
+                unsatisfied.push(format!("{requirement} (not found)"));
+                continue;
+            }
+        };
+        match (&requirement.version, binary_version(&path).await) {
+            (None, None) => found.push(requirement.binary.clone()),
+            (_, Some(version)) if requirement.is_satisfied_by(&version) => {
+                found.push(format!("{}={version}", requirement.binary))
+            }
+            (_, Some(version)) => unsatisfied.push(format!("{requirement} (found {version})")),
+            (Some(_), None) => unsatisfied.push(format!("{requirement} (unknown version)")),
+        }
+    }
+    if unsatisfied.is_empty() {
+        Ok(found)
+    } else {
+        Err(Error::ExecutionErr(format!(
+            "worker {worker_name} does not satisfy the bash requirements: {}",
+            unsatisfied.join(", ")
+        )))
+    }
+}
+
+async fn find_in_path(binary: &str) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+    for dir in PATH_ENV.split(':').filter(|dir| !dir.is_empty()) {
+        let path = format!("{dir}/{binary}");
+        if let Ok(m) = metadata(&path).await {
+            if m.is_file() && m.permissions().mode() & 0o111 != 0 {
+                return Some(path);
+            }
+        }
+    }
+    None
+}
+
+/* the first version number printed by `binary --version` */
+async fn binary_version(path: &str) -> Option<String> {
+    let output = tokio::time::timeout(
+        Duration::from_secs(5),
+        Command::new(path)
+            .arg("--version")
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .ok()?
+    .ok()?;
+    let output = format!(
+        "{}\n{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    RE_BINARY_VERSION
+        .find(&output)
+        .map(|version| version.as_str().to_string())
+}
+
 #[tracing::instrument(level = "trace", skip_all)]
 async fn handle_dependency_job(
     job: &QueuedJob,
@@ -1728,6 +1832,17 @@ async fn handle_flow_dependency_job(
     )
     .execute(db)
     .await?;
+    /* the locks belong to the version the dependency job was pushed for */
+    if let Some(version) = job.flow_version {
+        sqlx::query!(
+            "UPDATE flow_version SET value = $1 WHERE id = $2 AND workspace_id = $3",
+            new_flow_value,
+            version,
+            job.workspace_id
+        )
+        .execute(db)
+        .await?;
+    }
     Ok(())
 }
 
@@ -1784,7 +1899,18 @@ async fn capture_dependency_job(
             Ok(String::new())
             // generate_deno_lock(job_id, job_raw_code, logs, job_dir, db, timeout).await
         }
-        ScriptLang::Bash => Ok("".to_owned()),
+        ScriptLang::Bash => {
+            let requirements = windmill_parser_bash::parse_bash_requirements(job_raw_code)?;
+            if requirements.is_empty() {
+                return Ok("".to_owned());
+            }
+            let lock = check_bash_requirements(&requirements, worker_name)
+                .await?
+                .join("\n");
+            logs.push_str(&format!("\nrequirements found on worker {worker_name}:\n{lock}"));
+            Ok(lock)
+        }
+        ScriptLang::Postgresql => Ok("".to_owned()),
     }
 }
 
@@ -1823,6 +1949,41 @@ pub async fn get_reserved_variables(
         .collect())
 }
 
+/* how long a job may run before being killed */
+pub async fn job_timeout(job_id: &Uuid, _w_id: &str, db: &Pool<Postgres>) -> Duration {
+    #[cfg(not(feature = "enterprise"))]
+    let (default_timeout, max_timeout) = (*TIMEOUT_DURATION, Duration::from_secs(*MAX_TIMEOUT));
+
+    #[cfg(feature = "enterprise")]
+    let premium_workspace = *CLOUD_HOSTED && sqlx::query_scalar!("SELECT premium FROM workspace WHERE id = $1", _w_id)
+        .fetch_one(db)
+        .await
+        .map_err(|e| {
+            tracing::error!(%e, "error getting premium workspace for job {job_id}: {e}");
+        }).unwrap_or(false);
+
+    #[cfg(feature = "enterprise")]
+    let (default_timeout, max_timeout) = if premium_workspace {
+        (*TIMEOUT_DURATION*6, Duration::from_secs(*MAX_TIMEOUT*6)) //30mins
+    } else {
+        (*TIMEOUT_DURATION, Duration::from_secs(*MAX_TIMEOUT))
+    };
+
+    /* a timeout set on the job, from its script, flow step or run query, overrides the default
+     * up to the maximum, scripts may have been saved with a timeout since lowered */
+    sqlx::query_scalar!("SELECT timeout FROM queue WHERE id = $1", job_id)
+        .fetch_optional(db)
+        .await
+        .map_err(|e| {
+            tracing::error!(%e, "error getting timeout for job {job_id}: {e}");
+        })
+        .ok()
+        .flatten()
+        .flatten()
+        .map(|timeout| Duration::from_secs(timeout as u64).min(max_timeout))
+        .unwrap_or(default_timeout)
+}
+
 async fn get_mem_peak(pid: Option<u32>, nsjail: bool) -> i32 {
     if pid.is_none() {
         return -1
@@ -1877,6 +2038,21 @@ pub async fn handle_child(
     } else {
         tracing::info!("could not get child pid");
     }
+
+    let timeout_duration = job_timeout(job_id, _w_id, db).await;
+
+    /* nsjail enforces the limits of its own config */
+    let limits = if nsjail {
+        JobLimits::default()
+    } else {
+        job_limits(job_id, db).await
+    };
+    let enforcement = match pid {
+        Some(pid) => apply_limits(job_id, pid, &limits, timeout_duration).await,
+        None => Enforcement::None,
+    };
+    let memory_limit_kb = limits.memory_limit_kb();
+
     let (set_too_many_logs, mut too_many_logs) = watch::channel::<bool>(false);
     let (tx, mut rx) = broadcast::channel::<()>(3);
     let mut rx2 = tx.subscribe();
@@ -1898,7 +2074,7 @@ pub async fn handle_child(
         let mut i = 1;
         loop {
             tokio::select!(
-                _ = rx.recv() => break,
+                _ = rx.recv() => break KillReason::Cancelled,
                 _ = interval.tick() => {
                     // update the last_ping column every 5 seconds
                     i+=1;
@@ -1913,6 +2089,9 @@ pub async fn handle_child(
                     }
                     let mem_peak = get_mem_peak(pid, nsjail).await;
                     tracing::info!("{job_id} still running. mem peak: {}kB", mem_peak);
+                    if matches!(memory_limit_kb, Some(limit) if mem_peak > limit) {
+                        break KillReason::MemoryLimit;
+                    }
                     let mem_peak = if mem_peak > 0 { Some(mem_peak) } else { None };
                     if sqlx::query_scalar!("UPDATE queue SET mem_peak = GREATEST($1, mem_peak), last_ping = now() WHERE id = $2 RETURNING canceled", mem_peak, job_id)
                         .fetch_optional(&db)
@@ -1923,7 +2102,7 @@ pub async fn handle_child(
                             false
                         })
                     {
-                        break;
+                        break KillReason::Cancelled;
                     }
                 },
             );
@@ -1935,35 +2114,19 @@ pub async fn handle_child(
         TooManyLogs,
         Timeout,
         Cancelled,
+        MemoryLimit,
     }
+
     /* a future that completes when the child process exits */
     let wait_on_child = async {
         let db = db.clone();
 
-        #[cfg(not(feature = "enterprise"))]
-        let timeout_duration = *TIMEOUT_DURATION;
-
-        #[cfg(feature = "enterprise")]
-        let premium_workspace = *CLOUD_HOSTED && sqlx::query_scalar!("SELECT premium FROM workspace WHERE id = $1", _w_id)
-            .fetch_one(&db)
-            .await
-            .map_err(|e| {
-                tracing::error!(%e, "error getting premium workspace for job {job_id}: {e}");
-            }).unwrap_or(false);
-        
-        #[cfg(feature = "enterprise")]
-        let timeout_duration = if premium_workspace {
-            *TIMEOUT_DURATION*6 //30mins
-        } else {
-            *TIMEOUT_DURATION
-        };
-
         let kill_reason = tokio::select! {
             biased;
             result = child.wait() => return result.map(Ok),
             Ok(()) = too_many_logs.changed() => KillReason::TooManyLogs,
             _ = sleep(timeout_duration) => KillReason::Timeout,
-            _ = update_job => KillReason::Cancelled,
+            kill_reason = update_job => kill_reason,
         };
         tx.send(()).expect("rx should never be dropped");
         drop(tx);
@@ -1979,7 +2142,7 @@ pub async fn handle_child(
                         WHERE id = $2
                     "#,
                 )
-                .bind(format!("duration > {}", TIMEOUT_DURATION.as_secs()))
+                .bind(format!("duration > {}", timeout_duration.as_secs()))
                 .bind(job_id)
                 .execute(&db)
                 .await
@@ -2097,12 +2260,25 @@ pub async fn handle_child(
 
 
     let (wait_result, _) = tokio::join!(wait_on_child, lines);
+    let oom_killed = enforcement.oom_killed().await;
+    enforcement.cleanup().await;
 
     tracing::info!(%job_id, "child process '{child_name}' for {job_id} took {}ms", start.elapsed().as_millis());
     match wait_result {
         _ if *too_many_logs.borrow() => Err(Error::ExecutionErr(format!(
             "logs or result reached limit. (current max size: {MAX_RESULT_SIZE} characters)"
         ))),
+        _ if oom_killed => Err(Error::MemoryLimitExceeded(
+            limits.memory_limit_mb.unwrap_or(0),
+        )),
+        Ok(Err(KillReason::MemoryLimit)) => Err(Error::MemoryLimitExceeded(
+            limits.memory_limit_mb.unwrap_or(0),
+        )),
+        Ok(Ok(status)) if status.signal() == Some(SIGXCPU) => Err(Error::ExecutionErr(format!(
+            "cpu time limit exceeded ({} cpu millis over {}s)",
+            limits.cpu_limit_millis.unwrap_or(0),
+            timeout_duration.as_secs()
+        ))),
         Ok(Ok(status)) => {
             if status.success() {
                 Ok(())
@@ -2117,6 +2293,7 @@ pub async fn handle_child(
                 )))
             }
         }
+        Ok(Err(KillReason::Timeout)) => Err(Error::ExecutionTimeout(timeout_duration.as_secs())),
         Ok(Err(kill_reason)) => Err(Error::ExecutionErr(format!(
             "job process killed because {kill_reason:#?}"
         ))),
@@ -2188,14 +2365,33 @@ async fn append_logs(job_id: uuid::Uuid, logs: impl AsRef<str>, db: impl Borrow<
         return;
     }
 
-    if let Err(err) = sqlx::query!(
-        "UPDATE queue SET logs = concat(logs, $1::text) WHERE id = $2",
+    let log_offset = match sqlx::query_scalar!(
+        "UPDATE queue SET logs = concat(logs, $1::text) WHERE id = $2 RETURNING char_length(logs) as \"log_offset!\"",
         logs.as_ref(),
         job_id,
     )
-    .execute(db.borrow())
+    .fetch_optional(db.borrow())
     .await
     {
-        tracing::error!(%job_id, %err, "error updating logs for job {job_id}: {err}");
+        Ok(Some(log_offset)) => log_offset,
+        Ok(None) => return,
+        Err(err) => {
+            tracing::error!(%job_id, %err, "error updating logs for job {job_id}: {err}");
+            return;
+        }
+    };
+
+    let pieces = split_logs_for_notify(logs.as_ref());
+    let mut remaining = pieces.iter().map(|p| p.chars().count() as i32).sum::<i32>();
+    for piece in pieces {
+        remaining -= piece.chars().count() as i32;
+        let update = JobUpdateNotification::Logs {
+            job_id,
+            logs: piece.to_string(),
+            log_offset: log_offset - remaining,
+        };
+        if let Err(err) = notify_job_update(db.borrow(), &update).await {
+            tracing::error!(%job_id, %err, "error notifying logs update for job {job_id}: {err}");
+        }
     }
 }
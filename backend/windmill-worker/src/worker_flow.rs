@@ -20,15 +20,18 @@ use tracing::instrument;
 use uuid::Uuid;
 use windmill_common::flow_status::{FlowStatusModuleWParent, Iterator, JobResult};
 use windmill_common::jobs::{
-    script_hash_to_tag, script_path_to_payload, JobPayload, QueuedJob, RawCode,
+    notify_job_update, script_hash_to_tag, script_path_to_payload, JobPayload,
+    JobUpdateNotification, QueuedJob, RawCode,
 };
 use windmill_common::{
     error::{self, to_anyhow, Error},
     flow_status::{
-        Approval, BranchAllStatus, BranchChosen, FlowStatus, FlowStatusModule, RetryStatus,
-        MAX_RETRY_ATTEMPTS, MAX_RETRY_INTERVAL,
+        Approval, BranchAllStatus, BranchChosen, FlowStatus, FlowStatusModule, OnErrorStatus,
+        RetryStatus, WhileLoopStatus, DEFAULT_WHILE_LOOP_MAX_ITERATIONS, MAX_RETRY_ATTEMPTS,
+        MAX_RETRY_INTERVAL,
     },
     flows::{FlowModule, FlowModuleValue, FlowValue, InputTransform, Retry, Suspend},
+    result_store::{resolve_optional_result, resolve_result},
 };
 
 type DB = sqlx::Pool<sqlx::Postgres>;
@@ -124,7 +127,7 @@ pub async fn update_flow_status_after_job_completion_internal<
     depth: u8,
     rsmq: Option<R>,
 ) -> error::Result<Option<RecUpdateFlowStatusAfterJobCompletion>> {
-    let (should_continue_flow, flow_job, stop_early, skip_if_stop_early, nresult) = {
+    let (should_continue_flow, flow_job, stop_early, skip_if_stop_early, nresult, success) = {
         tracing::debug!("UPDATE FLOW STATUS: {flow:?} {success} {result:?} {w_id} {depth}");
 
         let mut tx: QueueTransaction<'_, _> = (rsmq.clone(), db.begin().await?).into();
@@ -162,6 +165,7 @@ pub async fn update_flow_status_after_job_completion_internal<
         let skip_loop_failures = if matches!(
             module_status,
             FlowStatusModule::InProgress { iterator: Some(_), .. }
+                | FlowStatusModule::InProgress { whileloop: Some(_), .. }
         ) {
             compute_skip_loop_failures(flow, old_status.step, tx.transaction_mut())
                 .await?
@@ -202,6 +206,19 @@ pub async fn update_flow_status_after_job_completion_internal<
             (stop_early, r.skip_if_stopped.unwrap_or(false))
         };
 
+        let whileloop_max_iterations = match module_status {
+            FlowStatusModule::InProgress {
+                whileloop: Some(WhileLoopStatus { iteration }), ..
+            } if !stop_early && (success || skip_loop_failures) => {
+                let max_iterations =
+                    compute_whileloop_max_iterations(flow, old_status.step, tx.transaction_mut())
+                        .await?
+                        .unwrap_or(DEFAULT_WHILE_LOOP_MAX_ITERATIONS);
+                (*iteration + 1 >= max_iterations as usize).then_some(max_iterations)
+            }
+            _ => None,
+        };
+
         let skip_branch_failure = match module_status {
             FlowStatusModule::InProgress {
                 branchall: Some(BranchAllStatus { branch, .. }),
@@ -214,7 +231,8 @@ pub async fn update_flow_status_after_job_completion_internal<
             _ => false,
         };
 
-        let skip_failure = skip_branch_failure || skip_loop_failures;
+        let skip_failure =
+            (skip_branch_failure || skip_loop_failures) && whileloop_max_iterations.is_none();
 
         let (inc_step_counter, new_status) = match module_status {
             FlowStatusModule::InProgress {
@@ -282,6 +300,7 @@ pub async fn update_flow_status_after_job_completion_internal<
                             flow_jobs: Some(jobs.clone()),
                             branch_chosen: None,
                             approvers: vec![],
+                            on_error: None,
                         }
                     } else {
                         FlowStatusModule::Failure {
@@ -289,6 +308,7 @@ pub async fn update_flow_status_after_job_completion_internal<
                             job: job_id_for_status.clone(),
                             flow_jobs: Some(jobs.clone()),
                             branch_chosen: None,
+                            on_error: None,
                         }
                     };
                     (true, Some(new_status))
@@ -303,6 +323,13 @@ pub async fn update_flow_status_after_job_completion_internal<
             } if (*index + 1 < itered.len() && (success || skip_loop_failures)) && !stop_early => {
                 (false, None)
             }
+            FlowStatusModule::InProgress { whileloop: Some(_), .. }
+                if (success || skip_loop_failures)
+                    && !stop_early
+                    && whileloop_max_iterations.is_none() =>
+            {
+                (false, None)
+            }
             FlowStatusModule::InProgress {
                 branchall: Some(BranchAllStatus { branch, len, .. }),
                 ..
@@ -312,18 +339,22 @@ pub async fn update_flow_status_after_job_completion_internal<
                     && matches!(
                         module_status,
                         FlowStatusModule::InProgress { iterator: Some(_), .. }
+                            | FlowStatusModule::InProgress { whileloop: Some(_), .. }
                     )
                 {
                     // if we're stopping early inside a loop, we just want to break the loop instead
                     stop_early = false;
                 }
-                let (flow_jobs, branch_chosen) = match module_status {
-                    FlowStatusModule::InProgress { flow_jobs, branch_chosen, .. } => {
-                        (flow_jobs.clone(), branch_chosen.clone())
+                let (flow_jobs, branch_chosen, on_error) = match module_status {
+                    FlowStatusModule::InProgress { flow_jobs, branch_chosen, on_error, .. } => {
+                        (flow_jobs.clone(), branch_chosen.clone(), on_error.clone())
                     }
-                    _ => (None, None),
+                    _ => (None, None, None),
                 };
-                if success || (flow_jobs.is_some() && (skip_loop_failures || skip_branch_failure)) {
+                if whileloop_max_iterations.is_none()
+                    && (success
+                        || (flow_jobs.is_some() && (skip_loop_failures || skip_branch_failure)))
+                {
                     (
                         true,
                         Some(FlowStatusModule::Success {
@@ -332,6 +363,7 @@ pub async fn update_flow_status_after_job_completion_internal<
                             flow_jobs,
                             branch_chosen,
                             approvers: vec![],
+                            on_error,
                         }),
                     )
                 } else {
@@ -342,12 +374,48 @@ pub async fn update_flow_status_after_job_completion_internal<
                             job: job_id_for_status.clone(),
                             flow_jobs,
                             branch_chosen,
+                            on_error,
                         }),
                     )
                 }
             }
         };
 
+        /* a failing module with on_error modules runs them instead of failing the flow,
+         * once its retries are exhausted */
+        let catch_error = !is_failure_step
+            && !unrecoverable
+            && matches!(
+                &new_status,
+                Some(FlowStatusModule::Failure { on_error: None, .. })
+            )
+            && has_on_error_to_run(
+                flow,
+                old_status.step,
+                &old_status.retry,
+                tx.transaction_mut(),
+            )
+            .await?;
+
+        let (inc_step_counter, new_status) = if catch_error {
+            (
+                false,
+                Some(FlowStatusModule::InProgress {
+                    id: module_status.id(),
+                    job: job_id_for_status.clone(),
+                    iterator: None,
+                    whileloop: None,
+                    flow_jobs: None,
+                    branch_chosen: None,
+                    branchall: None,
+                    on_error: Some(OnErrorStatus { failed_job: job_id_for_status.clone() }),
+                    parallel: false,
+                }),
+            )
+        } else {
+            (inc_step_counter, new_status)
+        };
+
         let step_counter = if inc_step_counter {
             sqlx::query!(
                 "
@@ -452,11 +520,29 @@ pub async fn update_flow_status_after_job_completion_internal<
                     })
                     .collect::<Result<Vec<_>, _>>()?;
 
-                json!(results)
+                let mut resolved = Vec::with_capacity(results.len());
+                for result in results {
+                    resolved.push(resolve_optional_result(result.clone()).await?);
+                }
+                json!(resolved)
             }
             _ => result,
         };
 
+        /* a while loop that never met its stop condition fails the module */
+        let (success, nresult) = if let Some(max_iterations) = whileloop_max_iterations {
+            (
+                false,
+                json!({ "error": {
+                    "name": "MaxIterationsReached",
+                    "message": format!("while loop did not stop after {max_iterations} iterations"),
+                    "results": nresult,
+                }}),
+            )
+        } else {
+            (success, nresult)
+        };
+
         if matches!(&new_status, Some(FlowStatusModule::Success { .. })) {
             sqlx::query(
                 "
@@ -502,6 +588,7 @@ pub async fn update_flow_status_after_job_completion_internal<
         let should_continue_flow = match success {
             _ if stop_early => false,
             _ if flow_job.canceled => false,
+            _ if catch_error => true,
             true => !is_last_step,
             false if unrecoverable => false,
             false if skip_failure => !is_last_step,
@@ -531,11 +618,18 @@ pub async fn update_flow_status_after_job_completion_internal<
                 flow_job.schedule_path.as_ref().unwrap(),
                 flow_job.script_path.as_ref().unwrap(),
                 &w_id,
+                flow_job.scheduled_for,
+                flow_job.args.as_ref(),
                 success,
-                if success { None } else { Some(nresult.clone()) },
+                nresult.clone(),
             )
             .await?;
         }
+        notify_job_update(
+            tx.transaction_mut(),
+            &JobUpdateNotification::FlowStatus { job_id: flow },
+        )
+        .await?;
         tx.commit().await?;
         (
             should_continue_flow,
@@ -543,6 +637,7 @@ pub async fn update_flow_status_after_job_completion_internal<
             stop_early,
             skip_if_stop_early,
             nresult,
+            success,
         )
     };
 
@@ -572,6 +667,7 @@ pub async fn update_flow_status_after_job_completion_internal<
                 &flow_job,
                 success,
                 stop_early && skip_if_stop_early,
+                false,
                 nresult.clone(),
                 logs,
                 rsmq.clone(),
@@ -652,6 +748,53 @@ async fn compute_skip_loop_failures<'c>(
     .map_err(|e| Error::InternalErr(format!("error during retrieval of skip_loop_failures: {e}")))
 }
 
+async fn compute_whileloop_max_iterations<'c>(
+    flow: Uuid,
+    step: i32,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+) -> Result<Option<u32>, Error> {
+    sqlx::query_scalar!(
+        "
+    SELECT (raw_flow->'modules'->$1::int->'value'->>'max_iterations')::int
+      FROM queue
+     WHERE id = $2
+        ",
+        step,
+        flow
+    )
+    .fetch_one(tx)
+    .await
+    .map(|v| v.map(|v| v.max(0) as u32))
+    .map_err(|e| Error::InternalErr(format!("error during retrieval of max_iterations: {e}")))
+}
+
+async fn has_on_error_to_run<'c>(
+    flow: Uuid,
+    step: i32,
+    retry_status: &RetryStatus,
+    tx: &mut sqlx::Transaction<'c, sqlx::Postgres>,
+) -> Result<bool, Error> {
+    let module = sqlx::query_scalar!(
+        "
+    SELECT raw_flow->'modules'->$1::int
+      FROM queue
+     WHERE id = $2
+        ",
+        step,
+        flow
+    )
+    .fetch_one(tx)
+    .await
+    .map_err(|e| Error::InternalErr(format!("error during retrieval of on_error: {e}")))?
+    .and_then(|m| serde_json::from_value::<FlowModule>(m).ok());
+
+    Ok(module
+        .map(|m| {
+            m.on_error.is_some() && next_retry(&m.retry.unwrap_or_default(), retry_status).is_none()
+        })
+        .unwrap_or(false))
+}
+
 async fn compute_skip_branchall_failure<'c>(
     flow: Uuid,
     step: i32,
@@ -760,6 +903,7 @@ pub async fn update_flow_status_in_progress(
         .execute(db)
         .await?;
     }
+    notify_job_update(db, &JobUpdateNotification::FlowStatus { job_id: flow }).await?;
     Ok(())
 }
 
@@ -1065,11 +1209,13 @@ async fn push_next_flow_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>
                 /* If we are woken up after suspending, last_result will be the flow args, but we
                  * should use the result from the last job */
                 if let FlowStatusModule::WaitingForEvents { .. } = &status_module {
-                    last_result =
+                    last_result = resolve_result(
                         sqlx::query_scalar!("SELECT result FROM completed_job WHERE id = $1", last)
                             .fetch_one(&mut tx)
                             .await?
-                            .context("previous job result")?;
+                            .context("previous job result")?,
+                    )
+                    .await?;
                 }
 
                 /* continue on and run this job! */
@@ -1108,7 +1254,8 @@ async fn push_next_flow_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>
                 let logs = "Timed out waiting to be resumed".to_string();
                 let result = json!({ "error": {"message": logs, "name": "SuspendedTimeout"}});
                 let _uuid =
-                    add_completed_job(db, &flow_job, success, skipped, result, logs, rsmq).await?;
+                    add_completed_job(db, &flow_job, success, skipped, false, result, logs, rsmq)
+                        .await?;
 
                 return Ok(());
             }
@@ -1221,7 +1368,26 @@ async fn push_next_flow_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>
 
     let mut transform_context: Option<IdContext> = None;
 
+    let on_error = match &status_module {
+        FlowStatusModule::InProgress { on_error: Some(on_error), .. } => Some(on_error.clone()),
+        _ => None,
+    };
+    if on_error.is_some() {
+        /* the sleep before this module already elapsed before the job that failed */
+        scheduled_for_o = None;
+    }
+
     let args: windmill_common::error::Result<_> = match &module.value {
+        /* on_error modules get the flow input along with the error of the failed module */
+        _ if on_error.is_some() => {
+            let mut args = flow_job
+                .args
+                .as_ref()
+                .and_then(|args| args.as_object().cloned())
+                .unwrap_or_default();
+            args.insert("error".to_string(), last_result.clone());
+            Ok(args)
+        }
         FlowModuleValue::Script { input_transforms, .. }
         | FlowModuleValue::RawScript { input_transforms, .. }
         | FlowModuleValue::Flow { input_transforms, .. } => {
@@ -1291,7 +1457,7 @@ async fn push_next_flow_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>
                                 "#,
                     )
                     .bind(status.step)
-                    .bind(json!(FlowStatusModule::Success { id: status_module.id(), job: Uuid::nil(), flow_jobs: None, branch_chosen: None, approvers: vec![] }))
+                    .bind(json!(FlowStatusModule::Success { id: status_module.id(), job: Uuid::nil(), flow_jobs: None, branch_chosen: None, approvers: vec![], on_error: None }))
                     .bind(flow_job.id)
                     .execute(db)
                     .await?;
@@ -1333,6 +1499,8 @@ async fn push_next_flow_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>
                             modules: (*modules).clone(),
                             failure_module: fm.clone(),
                             same_worker: flow.same_worker,
+                            concurrency_limit: None,
+                            early_return: None,
                         },
                         path: Some(format!("{}/loop-{}", flow_job.script_path(), i)),
                     },
@@ -1346,7 +1514,8 @@ async fn push_next_flow_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>
                 iterator: None,
                 ..
             } => args.as_ref().map(|args| args.clone()),
-            NextStatus::NextLoopIteration(NextIteration { new_args, .. }) => {
+            NextStatus::NextLoopIteration(NextIteration { new_args, .. })
+            | NextStatus::NextWhileIteration(NextWhileIteration { new_args, .. }) => {
                 args.as_ref().map(|args| {
                     let mut args = args.clone();
                     args.extend(new_args.clone());
@@ -1395,6 +1564,9 @@ async fn push_next_flow_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>
             err,
             flow_job.visible_to_owner,
             payload_tag.tag,
+            module.priority.or(flow_job.priority),
+            module.timeout,
+            module.cache_ttl,
         )
         .await?;
         tx = inner_tx;
@@ -1418,6 +1590,7 @@ async fn push_next_flow_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>
             FlowStatusModule::InProgress {
                 job: uuid,
                 iterator: Some(windmill_common::flow_status::Iterator { index, itered }),
+                whileloop: None,
                 flow_jobs: Some(flow_jobs),
                 branch_chosen: None,
                 branchall: None,
@@ -1428,12 +1601,39 @@ async fn push_next_flow_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>
         NextStatus::AllFlowJobs { iterator, branchall } => FlowStatusModule::InProgress {
             job: flow_job.id,
             iterator,
+            whileloop: None,
             flow_jobs: Some(uuids),
             branch_chosen: None,
             branchall,
             id: status_module.id(),
             parallel: true,
         },
+        NextStatus::NextWhileIteration(NextWhileIteration { iteration, mut flow_jobs, .. }) => {
+            let uuid = one_uuid?;
+            flow_jobs.push(uuid);
+
+            FlowStatusModule::InProgress {
+                job: uuid,
+                iterator: None,
+                whileloop: Some(WhileLoopStatus { iteration }),
+                flow_jobs: Some(flow_jobs),
+                branch_chosen: None,
+                branchall: None,
+                id: status_module.id(),
+                parallel: false,
+            }
+        }
+        NextStatus::OnError(on_error) => FlowStatusModule::InProgress {
+            job: one_uuid?,
+            iterator: None,
+            whileloop: None,
+            flow_jobs: None,
+            branch_chosen: None,
+            branchall: None,
+            on_error: Some(on_error),
+            id: status_module.id(),
+            parallel: false,
+        },
         NextStatus::NextBranchStep(NextBranch { mut flow_jobs, status, .. }) => {
             let uuid = one_uuid?;
             flow_jobs.push(uuid);
@@ -1441,6 +1641,7 @@ async fn push_next_flow_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>
             FlowStatusModule::InProgress {
                 job: uuid,
                 iterator: None,
+                whileloop: None,
                 flow_jobs: Some(flow_jobs),
                 branch_chosen: None,
                 branchall: Some(status),
@@ -1452,6 +1653,7 @@ async fn push_next_flow_job<R: rsmq_async::RsmqConnection + Send + Sync + Clone>
         NextStatus::BranchChosen(branch) => FlowStatusModule::InProgress {
             job: one_uuid?,
             iterator: None,
+            whileloop: None,
             flow_jobs: None,
             branch_chosen: Some(branch),
             branchall: None,
@@ -1589,6 +1791,14 @@ struct NextIteration {
     new_args: Map<String, serde_json::Value>,
 }
 
+/// State of a while loop used to initialize the next iteration's FlowStatusModule
+#[derive(Debug)]
+struct NextWhileIteration {
+    iteration: usize,
+    flow_jobs: Vec<Uuid>,
+    new_args: Map<String, serde_json::Value>,
+}
+
 enum LoopStatus {
     ParallelIteration { itered: Vec<Value> },
     NextIteration(NextIteration),
@@ -1607,6 +1817,8 @@ enum NextStatus {
     BranchChosen(BranchChosen),
     NextBranchStep(NextBranch),
     NextLoopIteration(NextIteration),
+    NextWhileIteration(NextWhileIteration),
+    OnError(OnErrorStatus),
     AllFlowJobs {
         branchall: Option<BranchAllStatus>,
         iterator: Option<windmill_common::flow_status::Iterator>,
@@ -1641,6 +1853,32 @@ async fn compute_next_flow_transform<'c>(
     previous_id: String,
     client: &AuthedClient,
 ) -> error::Result<(sqlx::Transaction<'c, sqlx::Postgres>, NextFlowTransform)> {
+    if let FlowStatusModule::InProgress { on_error: Some(on_error), .. } = status_module {
+        return Ok((
+            tx,
+            NextFlowTransform::Continue(
+                ContinuePayload::SingleJob(JobPayloadWithTag {
+                    payload: JobPayload::RawFlow {
+                        value: FlowValue {
+                            modules: module.on_error.clone().unwrap_or_default(),
+                            failure_module: None,
+                            same_worker: flow.same_worker,
+                            concurrency_limit: None,
+                            early_return: None,
+                        },
+                        path: Some(format!(
+                            "{}/on_error-{}",
+                            flow_job.script_path(),
+                            status.step
+                        )),
+                    },
+                    tag: None,
+                }),
+                NextStatus::OnError(on_error.clone()),
+            ),
+        ));
+    }
+
     match &module.value {
         FlowModuleValue::Identity => Ok((
             tx,
@@ -1800,6 +2038,8 @@ async fn compute_next_flow_transform<'c>(
                                         modules: (*modules).clone(),
                                         failure_module: fm,
                                         same_worker: flow.same_worker,
+                                        concurrency_limit: None,
+                                        early_return: None,
                                     },
                                     path: Some(format!(
                                         "{}/loop-{}",
@@ -1831,6 +2071,62 @@ async fn compute_next_flow_transform<'c>(
                 )),
             }
         }
+        /* whileloop modules are expected set `iter: { value: Value, index: usize }` as job arguments,
+         * the value being the result of the previous iteration */
+        FlowModuleValue::WhileloopFlow { modules, .. } => {
+            let (iteration, flow_jobs) = match status_module {
+                FlowStatusModule::WaitingForPriorSteps { .. }
+                | FlowStatusModule::WaitingForEvents { .. }
+                | FlowStatusModule::WaitingForExecutor { .. } => (0, vec![]),
+                FlowStatusModule::InProgress {
+                    whileloop: Some(WhileLoopStatus { iteration }),
+                    flow_jobs: Some(flow_jobs),
+                    ..
+                } => (iteration + 1, flow_jobs.clone()),
+                _ => Err(Error::BadRequest(format!(
+                    "Unrecognized module status for WhileloopFlow {status_module:?}"
+                )))?,
+            };
+
+            let mut new_args = Map::new();
+            new_args.insert(
+                "iter".to_string(),
+                json!({ "index": iteration, "value": last_result }),
+            );
+
+            let mut fm = flow.failure_module.clone();
+            if let Some(mut failure_module) = flow.failure_module.clone() {
+                failure_module.id_append(&format!("{}/{}", status.step, iteration));
+                fm = Some(failure_module);
+            }
+            Ok((
+                tx,
+                NextFlowTransform::Continue(
+                    ContinuePayload::SingleJob(JobPayloadWithTag {
+                        payload: JobPayload::RawFlow {
+                            value: FlowValue {
+                                modules: (*modules).clone(),
+                                failure_module: fm,
+                                same_worker: flow.same_worker,
+                                concurrency_limit: None,
+                                early_return: None,
+                            },
+                            path: Some(format!(
+                                "{}/whileloop-{}",
+                                flow_job.script_path(),
+                                iteration
+                            )),
+                        },
+                        tag: None,
+                    }),
+                    NextStatus::NextWhileIteration(NextWhileIteration {
+                        iteration,
+                        flow_jobs,
+                        new_args,
+                    }),
+                ),
+            ))
+        }
         FlowModuleValue::BranchOne { branches, default, .. } => {
             let branch = match status_module {
                 FlowStatusModule::WaitingForPriorSteps { .. }
@@ -1886,6 +2182,8 @@ async fn compute_next_flow_transform<'c>(
                                 modules,
                                 failure_module: fm,
                                 same_worker: flow.same_worker,
+                                concurrency_limit: None,
+                                early_return: None,
                             },
                             path: Some(format!(
                                 "{}/branchone-{}",
@@ -1929,6 +2227,8 @@ async fn compute_next_flow_transform<'c>(
                                                         modules: b.modules.clone(),
                                                         failure_module: fm.clone(),
                                                         same_worker: flow.same_worker,
+                                                        concurrency_limit: None,
+                                                        early_return: None,
                                                     },
                                                     path: Some(format!(
                                                         "{}/branchall-{}",
@@ -2003,6 +2303,8 @@ async fn compute_next_flow_transform<'c>(
                                 modules,
                                 failure_module: fm.clone(),
                                 same_worker: flow.same_worker,
+                                concurrency_limit: None,
+                                early_return: None,
                             },
                             path: Some(format!(
                                 "{}/branchall-{}",